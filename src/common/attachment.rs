@@ -0,0 +1,31 @@
+use std::ops::{Deref, DerefMut};
+
+// Something attached to the emulator by the frontend, like a trace writer or a debug hook.
+// It isn't part of the emulated machine, so clones (i.e. save states) are made without it.
+pub struct Attachment<T>(Option<T>);
+
+impl<T> Attachment<T> {
+    pub fn new(value: Option<T>) -> Self {
+        Self(value)
+    }
+}
+
+impl<T> Clone for Attachment<T> {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+impl<T> Deref for Attachment<T> {
+    type Target = Option<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Attachment<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
@@ -0,0 +1,114 @@
+#[cfg(test)]
+use std::cell::Cell;
+#[cfg(test)]
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+// All time based sources go through a Clock, so the emulation can be made
+// deterministic by injecting a FixedClock.
+pub trait Clock {
+    // Time elapsed since an arbitrary, but fixed, starting point
+    fn now(&self) -> Duration;
+}
+
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+// Only moves when explicitly advanced. Clones share the same time, so one can be kept
+// for advancing the clock given to the emulator.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FixedClock {
+    now: Rc<Cell<Duration>>,
+}
+
+#[cfg(test)]
+impl FixedClock {
+    pub fn new(now: Duration) -> Self {
+        Self {
+            now: Rc::new(Cell::new(now)),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        self.now.set(self.now.get() + duration);
+    }
+}
+
+#[cfg(test)]
+impl Clock for FixedClock {
+    fn now(&self) -> Duration {
+        self.now.get()
+    }
+}
+
+// Paces presented frames against a Clock
+pub struct FramePacer {
+    next_frame_at: Duration,
+}
+
+impl FramePacer {
+    pub fn new(now: Duration) -> Self {
+        Self { next_frame_at: now }
+    }
+
+    // Returns how long to wait before presenting the frame
+    pub fn on_frame(&mut self, now: Duration, frame_interval: Duration) -> Duration {
+        self.next_frame_at += frame_interval;
+        if self.next_frame_at > now {
+            self.next_frame_at - now
+        } else {
+            // Don't try to catch up if we're lagging behind
+            self.next_frame_at = now;
+            Duration::ZERO
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_clock() {
+        let clock = FixedClock::new(Duration::from_secs(10));
+        assert_eq!(clock.now(), Duration::from_secs(10));
+        assert_eq!(clock.now(), Duration::from_secs(10));
+
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(clock.now(), Duration::from_millis(10_500));
+    }
+
+    #[test]
+    fn test_frame_pacer() {
+        let clock = FixedClock::new(Duration::from_secs(1));
+        let frame_interval = Duration::from_millis(10);
+        let mut pacer = FramePacer::new(clock.now());
+
+        clock.advance(Duration::from_millis(4));
+        let wait = pacer.on_frame(clock.now(), frame_interval);
+        assert_eq!(wait, Duration::from_millis(6));
+        clock.advance(wait);
+
+        // A slow frame isn't caught up on by the following ones
+        clock.advance(Duration::from_millis(25));
+        assert_eq!(pacer.on_frame(clock.now(), frame_interval), Duration::ZERO);
+        clock.advance(Duration::from_millis(2));
+        assert_eq!(pacer.on_frame(clock.now(), frame_interval), Duration::from_millis(8));
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Hash)]
 pub struct RgbColor {
     pub r: u8,
     pub g: u8,
@@ -17,8 +17,30 @@ impl RgbColor {
     pub fn white() -> Self {
         RgbColor::new(0xFF, 0xFF, 0xFF)
     }
+
+    // Parses RRGGBB, with or without a leading #
+    pub fn from_hex(hex: &str) -> Result<Self, String> {
+        let digits = hex.strip_prefix('#').unwrap_or(hex);
+        if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid color {}, expected RRGGBB", hex));
+        }
+        let channel = |offset: usize| u8::from_str_radix(&digits[offset..offset + 2], 16).unwrap();
+        Ok(RgbColor::new(channel(0), channel(2), channel(4)))
+    }
+
+    #[cfg(any(test, feature = "sdl-frontend"))]
+    pub fn to_rgba(self, alpha: u8) -> [u8; 4] {
+        [self.r, self.g, self.b, alpha]
+    }
+
+    // Keeps `factor` of this color, and takes the rest from the other one
+    pub fn blend(self, other: RgbColor, factor: f32) -> Self {
+        let mix = |a: u8, b: u8| (a as f32 * factor + b as f32 * (1.0 - factor)).round() as u8;
+        RgbColor::new(mix(self.r, other.r), mix(self.g, other.g), mix(self.b, other.b))
+    }
 }
 
+#[derive(Clone)]
 pub struct FrameBuffer {
     data: Vec<RgbColor>,
     pub width: usize,
@@ -35,6 +57,7 @@ impl FrameBuffer {
         }
     }
 
+    #[cfg(test)]
     pub fn get_pixel(&self, x: usize, y: usize) -> RgbColor {
         let index = y as usize * self.width + x as usize;
         self.data[index]
@@ -44,4 +67,129 @@ impl FrameBuffer {
         let index = y as usize * self.width + x as usize;
         self.data[index] = color;
     }
+
+    pub fn copy_from(&mut self, other: &FrameBuffer) {
+        self.data.copy_from_slice(&other.data);
+    }
+
+    // Blends each pixel with the same pixel of the other frame, see RgbColor::blend
+    pub fn blend_from(&mut self, other: &FrameBuffer, factor: f32) {
+        assert_eq!(self.data.len(), other.data.len(), "Frame size mismatch");
+        for (pixel, other_pixel) in self.data.iter_mut().zip(other.data.iter()) {
+            *pixel = pixel.blend(*other_pixel, factor);
+        }
+    }
+
+    pub fn fill(&mut self, color: RgbColor) {
+        self.data.fill(color);
+    }
+
+    // All pixels, row by row
+    #[cfg(any(test, feature = "sdl-frontend", feature = "minifb-frontend"))]
+    pub fn pixels(&self) -> &[RgbColor] {
+        &self.data
+    }
+
+    pub fn row(&self, y: usize) -> &[RgbColor] {
+        let start = y * self.width;
+        &self.data[start..start + self.width]
+    }
+
+    pub fn row_mut(&mut self, y: usize) -> &mut [RgbColor] {
+        let start = y * self.width;
+        &mut self.data[start..start + self.width]
+    }
+
+    // The index of the closest palette color for each pixel, row by row
+    pub fn to_indexed(&self, palette: &[RgbColor]) -> Vec<u8> {
+        let distance = |a: RgbColor, b: RgbColor| {
+            let diff = |x: u8, y: u8| (x as i32 - y as i32).pow(2);
+            diff(a.r, b.r) + diff(a.g, b.g) + diff(a.b, b.b)
+        };
+        self.data
+            .iter()
+            .map(|&color| {
+                (0..palette.len())
+                    .min_by_key(|&index| distance(color, palette[index]))
+                    .expect("Palette is empty") as u8
+            })
+            .collect()
+    }
+
+    // Fills a tightly packed RGBA8 buffer, which needs to hold 4 bytes per pixel
+    #[cfg(any(test, feature = "sdl-frontend"))]
+    pub fn copy_to_rgba(&self, out: &mut [u8]) {
+        assert_eq!(out.len(), self.data.len() * 4, "RGBA buffer size mismatch");
+        for (color, out_pixel) in self.data.iter().zip(out.chunks_exact_mut(4)) {
+            out_pixel.copy_from_slice(&color.to_rgba(0xFF));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_to_rgba() {
+        let mut frame = FrameBuffer::new(2, 2);
+        frame.set_pixel(1, 0, RgbColor::new(0x12, 0x34, 0x56));
+        frame.set_pixel(0, 1, RgbColor::new_gray(0));
+
+        let mut out = vec![0x00; 2 * 2 * 4];
+        frame.copy_to_rgba(&mut out);
+        assert_eq!(
+            out,
+            vec![
+                0xFF, 0xFF, 0xFF, 0xFF, 0x12, 0x34, 0x56, 0xFF,
+                0x00, 0x00, 0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+            ]
+        );
+        assert_eq!(RgbColor::new(1, 2, 3).to_rgba(4), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_from_hex() {
+        assert_eq!(RgbColor::from_hex("#12aBff"), Ok(RgbColor::new(0x12, 0xAB, 0xFF)));
+        assert_eq!(RgbColor::from_hex("000000"), Ok(RgbColor::new_gray(0)));
+        assert!(RgbColor::from_hex("#12345").is_err());
+        assert!(RgbColor::from_hex("#12345G").is_err());
+        assert!(RgbColor::from_hex("+12345").is_err());
+    }
+
+    #[test]
+    fn test_to_indexed() {
+        let palette = [RgbColor::new_gray(0xFF), RgbColor::new_gray(0x00)];
+        let mut frame = FrameBuffer::new(3, 1);
+        frame.set_pixel(1, 0, RgbColor::new_gray(0x00));
+        frame.set_pixel(2, 0, RgbColor::new(0x10, 0x20, 0x30));
+        assert_eq!(frame.to_indexed(&palette), vec![0, 1, 1]);
+    }
+
+    #[test]
+    fn test_row_access_matches_pixel_access() {
+        let (width, height) = (160, 144);
+        let color_at = |x: usize, y: usize| RgbColor::new((x * 7) as u8, (y * 13) as u8, (x ^ y) as u8);
+
+        let mut per_pixel = FrameBuffer::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                per_pixel.set_pixel(x as u8, y as u8, color_at(x, y));
+            }
+        }
+
+        let mut per_row = FrameBuffer::new(width, height);
+        for y in 0..height {
+            for (x, pixel) in per_row.row_mut(y).iter_mut().enumerate() {
+                *pixel = color_at(x, y);
+            }
+        }
+
+        assert_eq!(per_row.pixels(), per_pixel.pixels());
+        for y in 0..height {
+            for x in 0..width {
+                assert_eq!(per_row.get_pixel(x, y), color_at(x, y));
+            }
+        }
+    }
 }
@@ -0,0 +1,82 @@
+use super::framebuffer::FrameBuffer;
+
+pub const DEFAULT_GHOSTING_FACTOR: f32 = 0.5;
+
+// The DMG LCD is slow to change, so each frame fades into the next. Some games rely on
+// this, e.g. flickering sprites on every other frame to make them look transparent.
+// Applied to presented frames only, the emulation itself is unaffected.
+pub struct LcdGhosting {
+    enabled: bool,
+    // How much of the previously presented frame is kept, from 0.0 to 1.0
+    factor: f32,
+    previous: Option<FrameBuffer>,
+}
+
+impl LcdGhosting {
+    pub fn new(enabled: bool, factor: f32) -> Self {
+        Self {
+            enabled,
+            factor: factor.clamp(0.0, 1.0),
+            previous: None,
+        }
+    }
+
+    pub fn toggle(&mut self) -> bool {
+        self.enabled = !self.enabled;
+        // Don't fade in from whatever was presented when it was last enabled
+        self.previous = None;
+        self.enabled
+    }
+
+    // The frame to present, blended with the previous one if enabled
+    pub fn apply<'a>(&'a mut self, frame: &'a FrameBuffer) -> &'a FrameBuffer {
+        if !self.enabled {
+            return frame;
+        }
+
+        // The first frame is blended with itself, which leaves it as is. After that the
+        // previous frame is itself a blend, so older frames keep fading out.
+        let previous = self.previous.get_or_insert_with(|| frame.clone());
+        previous.blend_from(frame, self.factor);
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::framebuffer::RgbColor;
+
+    fn filled_frame(shade: u8) -> FrameBuffer {
+        let mut frame = FrameBuffer::new(4, 4);
+        frame.fill(RgbColor::new_gray(shade));
+        frame
+    }
+
+    #[test]
+    fn test_blends_with_previous_frame() {
+        let mut ghosting = LcdGhosting::new(true, 0.5);
+
+        // Nothing to blend with for the first frame
+        let first = ghosting.apply(&filled_frame(0xFF)).get_pixel(0, 0);
+        assert_eq!(first, RgbColor::new_gray(0xFF));
+
+        let second = ghosting.apply(&filled_frame(0x00)).get_pixel(3, 3);
+        assert_eq!(second, RgbColor::new_gray(0x80));
+
+        let third = ghosting.apply(&filled_frame(0x00)).get_pixel(3, 3);
+        assert_eq!(third, RgbColor::new_gray(0x40));
+    }
+
+    #[test]
+    fn test_disabled_passes_frame_through() {
+        let mut ghosting = LcdGhosting::new(false, 0.5);
+        ghosting.apply(&filled_frame(0xFF));
+        assert_eq!(ghosting.apply(&filled_frame(0x00)).get_pixel(0, 0), RgbColor::new_gray(0x00));
+
+        // Starts over when toggled on
+        assert!(ghosting.toggle());
+        assert_eq!(ghosting.apply(&filled_frame(0x00)).get_pixel(0, 0), RgbColor::new_gray(0x00));
+        assert_eq!(ghosting.apply(&filled_frame(0xFF)).get_pixel(0, 0), RgbColor::new_gray(0x80));
+    }
+}
@@ -0,0 +1,320 @@
+use std::collections::HashMap;
+
+use super::framebuffer::{FrameBuffer, RgbColor};
+
+// At ~60 Hz only every other frame is kept, GIF delays are in steps of 10 ms
+const FRAME_STEP: usize = 2;
+const FRAME_DELAY_CENTISECONDS: u16 = 3;
+
+// The four DMG shades fit in 2 bits per pixel
+const MIN_CODE_SIZE: u8 = 2;
+const MAX_CODE_SIZE: u8 = 12;
+
+// Collects presented frames, quantized to the palette, until stopped or full.
+// https://www.w3.org/Graphics/GIF/spec-gif89a.txt
+pub struct GifRecorder {
+    palette: [RgbColor; 4],
+    width: usize,
+    height: usize,
+    frames: Vec<Vec<u8>>,
+    // Bounds the memory use, at one byte per pixel and frame
+    max_frames: usize,
+    presented_frames: usize,
+}
+
+impl GifRecorder {
+    pub fn new(palette: [RgbColor; 4], width: usize, height: usize, max_frames: usize) -> Self {
+        Self {
+            palette,
+            width,
+            height,
+            frames: vec![],
+            max_frames,
+            presented_frames: 0,
+        }
+    }
+
+    // Returns false once the recording is full, and the frame was dropped
+    pub fn push_frame(&mut self, frame: &FrameBuffer) -> bool {
+        if self.frames.len() >= self.max_frames {
+            return false;
+        }
+        assert_eq!((frame.width, frame.height), (self.width, self.height), "Frame size mismatch");
+
+        if self.presented_frames.is_multiple_of(FRAME_STEP) {
+            self.frames.push(frame.to_indexed(&self.palette));
+        }
+        self.presented_frames += 1;
+        true
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![];
+        out.extend_from_slice(b"GIF89a");
+
+        // Logical screen descriptor, with a global color table of 4 colors
+        out.extend_from_slice(&(self.width as u16).to_le_bytes());
+        out.extend_from_slice(&(self.height as u16).to_le_bytes());
+        out.extend_from_slice(&[0b1001_0001, 0x00, 0x00]);
+        for color in self.palette {
+            out.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+
+        // Loop forever
+        out.extend_from_slice(&[0x21, 0xFF, 0x0B]);
+        out.extend_from_slice(b"NETSCAPE2.0");
+        out.extend_from_slice(&[0x03, 0x01, 0x00, 0x00, 0x00]);
+
+        for frame in &self.frames {
+            // Graphic control extension, only for the delay
+            out.extend_from_slice(&[0x21, 0xF9, 0x04, 0x00]);
+            out.extend_from_slice(&FRAME_DELAY_CENTISECONDS.to_le_bytes());
+            out.extend_from_slice(&[0x00, 0x00]);
+
+            // Image descriptor, covering the whole screen without a local color table
+            out.push(0x2C);
+            out.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+            out.extend_from_slice(&(self.width as u16).to_le_bytes());
+            out.extend_from_slice(&(self.height as u16).to_le_bytes());
+            out.push(0x00);
+
+            out.push(MIN_CODE_SIZE);
+            for block in lzw_encode(frame).chunks(255) {
+                out.push(block.len() as u8);
+                out.extend_from_slice(block);
+            }
+            out.push(0x00);
+        }
+
+        out.push(0x3B);
+        out
+    }
+}
+
+// Packs codes of varying size, least significant bit first
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u32,
+    buffered_bits: u8,
+}
+
+impl BitWriter {
+    fn new() -> Self {
+        Self {
+            bytes: vec![],
+            buffer: 0,
+            buffered_bits: 0,
+        }
+    }
+
+    fn write(&mut self, code: u16, size: u8) {
+        self.buffer |= (code as u32) << self.buffered_bits;
+        self.buffered_bits += size;
+        while self.buffered_bits >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.buffered_bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.buffered_bits > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+fn lzw_encode(indices: &[u8]) -> Vec<u8> {
+    let clear_code: u16 = 1 << MIN_CODE_SIZE;
+    let end_code = clear_code + 1;
+
+    let mut writer = BitWriter::new();
+    // (prefix code, next index) -> code
+    let mut table: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end_code + 1;
+    let mut code_size = MIN_CODE_SIZE + 1;
+
+    writer.write(clear_code, code_size);
+
+    let mut maybe_prefix: Option<u16> = None;
+    for &index in indices {
+        let prefix = match maybe_prefix {
+            Some(prefix) => prefix,
+            None => {
+                maybe_prefix = Some(index as u16);
+                continue;
+            }
+        };
+
+        if let Some(&code) = table.get(&(prefix, index)) {
+            maybe_prefix = Some(code);
+            continue;
+        }
+
+        writer.write(prefix, code_size);
+        // The decoder adds its entries one code behind, so it grows the code size
+        // when reading the code after the one that filled up the current size.
+        if next_code == 1 << code_size && code_size < MAX_CODE_SIZE {
+            code_size += 1;
+        }
+        if next_code < 1 << MAX_CODE_SIZE {
+            table.insert((prefix, index), next_code);
+            next_code += 1;
+        } else {
+            writer.write(clear_code, code_size);
+            table.clear();
+            next_code = end_code + 1;
+            code_size = MIN_CODE_SIZE + 1;
+        }
+        maybe_prefix = Some(index as u16);
+    }
+
+    if let Some(prefix) = maybe_prefix {
+        writer.write(prefix, code_size);
+        if next_code == 1 << code_size && code_size < MAX_CODE_SIZE {
+            code_size += 1;
+        }
+    }
+    writer.write(end_code, code_size);
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Decodes the LZW data of a single frame, the reverse of lzw_encode
+    fn lzw_decode(data: &[u8]) -> Vec<u8> {
+        let clear_code: u16 = 1 << MIN_CODE_SIZE;
+        let end_code = clear_code + 1;
+
+        let mut bit_position = 0;
+        let mut read_code = |size: u8| {
+            let mut code = 0;
+            for bit in 0..size as usize {
+                let byte = data[(bit_position + bit) / 8];
+                code |= (((byte >> ((bit_position + bit) % 8)) & 1) as u16) << bit;
+            }
+            bit_position += size as usize;
+            code
+        };
+
+        let mut out = vec![];
+        let mut table: Vec<Vec<u8>> = vec![];
+        let mut code_size = MIN_CODE_SIZE + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        loop {
+            let code = read_code(code_size);
+            if code == clear_code {
+                table = (0..clear_code).map(|index| vec![index as u8]).collect();
+                table.extend([vec![], vec![]]);
+                code_size = MIN_CODE_SIZE + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code {
+                return out;
+            }
+
+            let entry = match (table.get(code as usize), previous.as_ref()) {
+                (Some(entry), _) => entry.clone(),
+                // The code being defined by this very read
+                (None, Some(previous)) => [previous.clone(), vec![previous[0]]].concat(),
+                (None, None) => panic!("Invalid code {}", code),
+            };
+            if let Some(previous) = previous {
+                if table.len() < 1 << MAX_CODE_SIZE {
+                    table.push([previous, vec![entry[0]]].concat());
+                    if table.len() == 1 << code_size && code_size < MAX_CODE_SIZE {
+                        code_size += 1;
+                    }
+                }
+            }
+            out.extend_from_slice(&entry);
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn test_lzw_round_trip() {
+        // Long enough to fill up the table a couple of times
+        let mut state: u32 = 1;
+        let noise: Vec<u8> = (0..20000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8 & 0b11
+            })
+            .collect();
+        let runs: Vec<u8> = (0..23040).map(|i| (i / 7 % 4) as u8).collect();
+
+        for indices in [vec![], vec![3], vec![1; 1000], noise, runs] {
+            assert_eq!(lzw_decode(&lzw_encode(&indices)), indices);
+        }
+    }
+
+    #[test]
+    fn test_encode_frames() {
+        let palette = [
+            RgbColor::new_gray(0xFF),
+            RgbColor::new_gray(0xAA),
+            RgbColor::new_gray(0x55),
+            RgbColor::new_gray(0x00),
+        ];
+        let mut recorder = GifRecorder::new(palette, 4, 2, 2);
+        let mut frame = FrameBuffer::new(4, 2);
+        for i in 0..(FRAME_STEP + 1) {
+            frame.set_pixel(i as u8, 1, palette[3]);
+            assert!(recorder.push_frame(&frame));
+        }
+        // Full, so further frames are dropped
+        assert!(!recorder.push_frame(&frame));
+        assert_eq!(recorder.frame_count(), 2);
+
+        let gif = recorder.encode();
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[4, 0, 2, 0]);
+        assert_eq!(&gif[13..16], &[0xFF, 0xFF, 0xFF]);
+
+        // Walk the blocks after the global color table
+        let mut position = 13 + 4 * 3;
+        let skip_sub_blocks = |position: &mut usize| {
+            while gif[*position] != 0 {
+                *position += gif[*position] as usize + 1;
+            }
+            *position += 1;
+        };
+        let mut frames = vec![];
+        loop {
+            match gif[position] {
+                0x21 => {
+                    position += 2;
+                    skip_sub_blocks(&mut position);
+                }
+                0x2C => {
+                    // Descriptor, then the minimum code size
+                    position += 10;
+                    assert_eq!(gif[position], MIN_CODE_SIZE);
+                    position += 1;
+                    let start = position;
+                    skip_sub_blocks(&mut position);
+                    let data: Vec<u8> = gif[start..position - 1]
+                        .chunks(256)
+                        .flat_map(|block| block[1..].to_vec())
+                        .collect();
+                    frames.push(lzw_decode(&data));
+                }
+                0x3B => break,
+                block => panic!("Unexpected block {:#04X}", block),
+            }
+        }
+        assert_eq!(position, gif.len() - 1);
+
+        // Every other frame is kept
+        assert_eq!(frames, vec![vec![0, 0, 0, 0, 3, 0, 0, 0], vec![0, 0, 0, 0, 3, 3, 3, 0]]);
+    }
+}
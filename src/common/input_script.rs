@@ -0,0 +1,164 @@
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use super::joypad_events::{JoypadButton, JoypadEvent};
+
+// Scripted joypad input, for tool-assisted runs and reproducible bug reports. Each line is
+// `<frame> <button> <down|up>`, where the frame counts presented frames from 0. Blank lines
+// and anything after a `#` are ignored.
+pub struct InputScript {
+    // Sorted by frame, keeping the order of the script within a frame
+    events: VecDeque<(u64, JoypadEvent)>,
+}
+
+// The inverse of parse_button
+fn button_name(button: JoypadButton) -> &'static str {
+    match button {
+        JoypadButton::Up => "up",
+        JoypadButton::Down => "down",
+        JoypadButton::Left => "left",
+        JoypadButton::Right => "right",
+        JoypadButton::A => "a",
+        JoypadButton::B => "b",
+        JoypadButton::Select => "select",
+        JoypadButton::Start => "start",
+    }
+}
+
+fn parse_button(word: &str) -> Option<JoypadButton> {
+    Some(match word.to_ascii_lowercase().as_str() {
+        "up" => JoypadButton::Up,
+        "down" => JoypadButton::Down,
+        "left" => JoypadButton::Left,
+        "right" => JoypadButton::Right,
+        "a" => JoypadButton::A,
+        "b" => JoypadButton::B,
+        "select" => JoypadButton::Select,
+        "start" => JoypadButton::Start,
+        _ => return None,
+    })
+}
+
+fn parse_line(line: &str) -> Result<Option<(u64, JoypadEvent)>, String> {
+    let words: Vec<&str> = line.split('#').next().unwrap_or("").split_whitespace().collect();
+    let (frame, button, state) = match words.as_slice() {
+        [] => return Ok(None),
+        [frame, button, state] => (frame, button, state),
+        _ => return Err("Expected <frame> <button> <down|up>".to_owned()),
+    };
+
+    let frame = frame.parse().map_err(|_| format!("Invalid frame: {}", frame))?;
+    let button = parse_button(button).ok_or_else(|| format!("Invalid button: {}", button))?;
+    let event = match state.to_ascii_lowercase().as_str() {
+        "down" => JoypadEvent::new_down(button),
+        "up" => JoypadEvent::new_up(button),
+        _ => return Err(format!("Invalid state: {}, expected down or up", state)),
+    };
+    Ok(Some((frame, event)))
+}
+
+impl InputScript {
+    pub fn parse(script: &str) -> Result<Self, String> {
+        let mut events = vec![];
+        for (index, line) in script.lines().enumerate() {
+            if let Some(event) = parse_line(line).map_err(|e| format!("Line {}: {}", index + 1, e))? {
+                events.push(event);
+            }
+        }
+        // Stable, so events on the same frame stay in order
+        events.sort_by_key(|(frame, _)| *frame);
+        Ok(Self { events: events.into() })
+    }
+
+    // The events to feed in before running the given frame, including any skipped ones
+    pub fn take_events(&mut self, frame: u64) -> Vec<JoypadEvent> {
+        let mut events = vec![];
+        while self.events.front().is_some_and(|(event_frame, _)| *event_frame <= frame) {
+            if let Some((_, event)) = self.events.pop_front() {
+                events.push(event);
+            }
+        }
+        events
+    }
+}
+
+// Writes joypad events as an input script, to be replayed with InputScript
+pub struct InputRecorder<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> InputRecorder<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn record(&mut self, frame: u64, event: &JoypadEvent) -> io::Result<()> {
+        let state = if event.is_down { "down" } else { "up" };
+        writeln!(self.writer, "{} {} {}", frame, button_name(event.button), state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let script = "\
+# Start the game, then jump
+10 start down
+12 Start up
+
+30 a down # Held for a frame
+31 a up
+30 right down
+";
+        let mut input_script = InputScript::parse(script).unwrap();
+        assert!(input_script.take_events(9).is_empty());
+        assert_eq!(input_script.take_events(10), vec![JoypadEvent::new_down(JoypadButton::Start)]);
+        // Skipped frames are caught up on
+        assert_eq!(
+            input_script.take_events(30),
+            vec![
+                JoypadEvent::new_up(JoypadButton::Start),
+                JoypadEvent::new_down(JoypadButton::A),
+                JoypadEvent::new_down(JoypadButton::Right),
+            ]
+        );
+        assert_eq!(input_script.take_events(31), vec![JoypadEvent::new_up(JoypadButton::A)]);
+        assert!(input_script.take_events(100).is_empty());
+    }
+
+    #[test]
+    fn test_recording_round_trip() {
+        let events = [
+            (0, JoypadEvent::new_down(JoypadButton::Start)),
+            (2, JoypadEvent::new_up(JoypadButton::Start)),
+            (2, JoypadEvent::new_down(JoypadButton::Left)),
+            (2, JoypadEvent::new_down(JoypadButton::B)),
+            (7, JoypadEvent::new_up(JoypadButton::Left)),
+            (9, JoypadEvent::new_up(JoypadButton::B)),
+        ];
+        let mut recorder = InputRecorder::new(vec![]);
+        for (frame, event) in &events {
+            recorder.record(*frame, event).unwrap();
+        }
+        let script = String::from_utf8(recorder.writer).unwrap();
+        assert!(script.starts_with("0 start down\n2 start up\n"), "{}", script);
+
+        let mut input_script = InputScript::parse(&script).unwrap();
+        let replayed: Vec<(u64, JoypadEvent)> = (0..10)
+            .flat_map(|frame| input_script.take_events(frame).into_iter().map(move |event| (frame, event)))
+            .collect();
+        assert_eq!(replayed, events);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let error = |script: &str| InputScript::parse(script).err().unwrap();
+        assert_eq!(error("1 a down\nx a down"), "Line 2: Invalid frame: x");
+        assert_eq!(error("1 turbo down"), "Line 1: Invalid button: turbo");
+        assert_eq!(error("1 a pressed"), "Line 1: Invalid state: pressed, expected down or up");
+        assert_eq!(error("1 a"), "Line 1: Expected <frame> <button> <down|up>");
+    }
+}
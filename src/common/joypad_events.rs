@@ -1,4 +1,4 @@
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum JoypadButton {
     Up,
     Down,
@@ -10,7 +10,7 @@ pub enum JoypadButton {
     Start,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub struct JoypadEvent {
     pub is_down: bool,
     pub button: JoypadButton,
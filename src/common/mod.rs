@@ -1,2 +1,10 @@
+pub mod attachment;
+pub mod clock;
 pub mod framebuffer;
+pub mod ghosting;
+pub mod gif;
+pub mod input_script;
 pub mod joypad_events;
+// Only drawn by the SDL overlay
+#[cfg(any(test, feature = "sdl-frontend"))]
+pub mod text;
@@ -0,0 +1,320 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+use std::ops::RangeInclusive;
+
+use crate::gameboy::gameboy::Gameboy;
+use crate::gameboy::instruction_decoder::disassemble_range;
+
+const DEFAULT_DISASM_COUNT: usize = 5;
+const MEM_BYTES_PER_LINE: usize = 16;
+
+const HELP: &str = "\
+Addresses are in hex, counts and lengths in decimal.
+  step [count]           Execute one or more instructions
+  continue               Run until a breakpoint is hit
+  break [addr]           Add a breakpoint, or list them
+  delete <addr>          Remove a breakpoint
+  regs                   Show the CPU registers
+  mem <addr> <len>       Show memory, without any side effects
+  disasm [addr] [count]  Disassemble, from PC by default
+  quit
+";
+
+enum Command {
+    Step(usize),
+    Continue,
+    Break(Option<u16>),
+    Delete(u16),
+    Regs,
+    Mem(u16, usize),
+    Disasm(Option<u16>, usize),
+    Help,
+    Quit,
+}
+
+enum Flow {
+    Prompt,
+    Resume,
+    Quit,
+}
+
+fn parse_address(word: &str) -> Result<u16, String> {
+    let digits = word
+        .strip_prefix("0x")
+        .or_else(|| word.strip_prefix('$'))
+        .unwrap_or(word);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", word))
+}
+
+// An inclusive range like C000-CFFF
+pub fn parse_address_range(word: &str) -> Result<RangeInclusive<u16>, String> {
+    let (start, end) = word
+        .split_once('-')
+        .ok_or_else(|| format!("Invalid range: {}, expected START-END", word))?;
+    Ok(parse_address(start)?..=parse_address(end)?)
+}
+
+fn parse_count(word: &str) -> Result<usize, String> {
+    word.parse().map_err(|_| format!("Invalid count: {}", word))
+}
+
+// Returns None for an empty line
+fn parse_command(line: &str) -> Result<Option<Command>, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match words.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(None),
+    };
+
+    let command = match (name, args) {
+        ("step" | "s", []) => Command::Step(1),
+        ("step" | "s", [count]) => Command::Step(parse_count(count)?),
+        ("continue" | "c", []) => Command::Continue,
+        ("break" | "b", []) => Command::Break(None),
+        ("break" | "b", [address]) => Command::Break(Some(parse_address(address)?)),
+        ("delete" | "d", [address]) => Command::Delete(parse_address(address)?),
+        ("regs" | "r", []) => Command::Regs,
+        ("mem" | "m", [address, len]) => Command::Mem(parse_address(address)?, parse_count(len)?),
+        ("disasm" | "x", []) => Command::Disasm(None, DEFAULT_DISASM_COUNT),
+        ("disasm" | "x", [address]) => Command::Disasm(Some(parse_address(address)?), DEFAULT_DISASM_COUNT),
+        ("disasm" | "x", [address, count]) => {
+            Command::Disasm(Some(parse_address(address)?), parse_count(count)?)
+        }
+        ("help" | "h", []) => Command::Help,
+        ("quit" | "q", []) => Command::Quit,
+        _ => return Err(format!("Unknown command: {}, see help", line.trim())),
+    };
+    Ok(Some(command))
+}
+
+// Returns the formatted instruction at the address, and its length
+fn disassemble(gameboy: &Gameboy, address: u16) -> (String, u16) {
+    // Enough for the longest instruction, unless the address space ends before that
+    let bytes = gameboy.dump_region(address, address.saturating_add(2));
+    // An unknown opcode is skipped over, leaving the next instruction first
+    let (description, length) = match disassemble_range(&bytes, 0, 0).first() {
+        Some((0, instruction, length)) => (format!("({:?})", instruction), *length),
+        _ => ("unknown opcode".to_owned(), 1),
+    };
+
+    let bytes: Vec<String> = bytes[..length].iter().map(|byte| format!("{:02X}", byte)).collect();
+    (format!("{:#06X}: {:<8} {}", address, bytes.join(" "), description), length as u16)
+}
+
+// A REPL for stepping through the emulation. Execution starts out paused, and pauses
+// again whenever a breakpoint is hit.
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    paused: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self {
+            breakpoints: BTreeSet::new(),
+            paused: true,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    // Runs commands until execution is resumed. Returns false on quit, or when the input ends.
+    pub fn prompt(
+        &mut self,
+        gameboy: &mut Gameboy,
+        input: &mut dyn BufRead,
+        output: &mut dyn Write,
+    ) -> io::Result<bool> {
+        writeln!(output, "{}", disassemble(gameboy, gameboy.debug_pc()).0)?;
+        loop {
+            write!(output, "> ")?;
+            output.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(false);
+            }
+
+            let flow = match parse_command(&line) {
+                Ok(Some(command)) => self.execute(gameboy, command, output)?,
+                Ok(None) => Flow::Prompt,
+                Err(message) => {
+                    writeln!(output, "{}", message)?;
+                    Flow::Prompt
+                }
+            };
+            match flow {
+                Flow::Prompt => (),
+                Flow::Resume => {
+                    self.paused = false;
+                    return Ok(true);
+                }
+                Flow::Quit => return Ok(false),
+            }
+        }
+    }
+
+    // Like Gameboy::run_until_vblank, but stops early when a breakpoint is hit
+    pub fn run_until_vblank(&mut self, gameboy: &mut Gameboy, output: &mut dyn Write) -> io::Result<()> {
+        loop {
+            let previous_pc = gameboy.debug_pc();
+            let frame_ready = gameboy.tick().is_some();
+
+            // PC doesn't move while halted, which shouldn't hit the same breakpoint over and over
+            let pc = gameboy.debug_pc();
+            if pc != previous_pc && self.breakpoints.contains(&pc) {
+                self.paused = true;
+                writeln!(output, "Breakpoint at {:#06X}", pc)?;
+                return Ok(());
+            }
+            if frame_ready {
+                return Ok(());
+            }
+        }
+    }
+
+    fn execute(&mut self, gameboy: &mut Gameboy, command: Command, output: &mut dyn Write) -> io::Result<Flow> {
+        match command {
+            Command::Step(count) => {
+                for _ in 0..count {
+                    gameboy.tick();
+                }
+                writeln!(output, "{}", disassemble(gameboy, gameboy.debug_pc()).0)?;
+            }
+            Command::Continue => return Ok(Flow::Resume),
+            Command::Break(Some(address)) => {
+                self.breakpoints.insert(address);
+                writeln!(output, "Breakpoint added at {:#06X}", address)?;
+            }
+            Command::Break(None) => {
+                for address in &self.breakpoints {
+                    writeln!(output, "{:#06X}", address)?;
+                }
+            }
+            Command::Delete(address) => {
+                if self.breakpoints.remove(&address) {
+                    writeln!(output, "Breakpoint removed at {:#06X}", address)?;
+                } else {
+                    writeln!(output, "No breakpoint at {:#06X}", address)?;
+                }
+            }
+            Command::Regs => {
+                let registers = gameboy.registers();
+                writeln!(
+                    output,
+                    "PC={:#06X} SP={:#06X} A={:#04X} F={:#04X} B={:#04X} C={:#04X} D={:#04X} E={:#04X} H={:#04X} L={:#04X} IME={}",
+                    registers.pc,
+                    registers.sp,
+                    registers.a,
+                    registers.f,
+                    registers.b,
+                    registers.c,
+                    registers.d,
+                    registers.e,
+                    registers.h,
+                    registers.l,
+                    registers.ime,
+                )?;
+            }
+            Command::Mem(address, len) => {
+                if len == 0 {
+                    return Ok(Flow::Prompt);
+                }
+                // Stops at the end of the address space
+                let end = address.saturating_add((len - 1).min(u16::MAX as usize) as u16);
+                let bytes = gameboy.dump_region(address, end);
+                for (line, chunk) in bytes.chunks(MEM_BYTES_PER_LINE).enumerate() {
+                    let hex: Vec<String> = chunk.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    let line_address = address as usize + line * MEM_BYTES_PER_LINE;
+                    writeln!(output, "{:#06X}: {}", line_address, hex.join(" "))?;
+                }
+            }
+            Command::Disasm(maybe_address, count) => {
+                let mut address = maybe_address.unwrap_or(gameboy.debug_pc());
+                for _ in 0..count {
+                    let (line, length) = disassemble(gameboy, address);
+                    writeln!(output, "{}", line)?;
+                    address = address.wrapping_add(length);
+                }
+            }
+            Command::Help => write!(output, "{}", HELP)?,
+            Command::Quit => return Ok(Flow::Quit),
+        }
+        Ok(Flow::Prompt)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::gameboy::cpu::TraceMode;
+
+    // NOPs from the entry point, then a loop at 0x0104
+    fn test_gameboy() -> Gameboy {
+        let mut rom_data = vec![0x00; 0x8000];
+        // JR -2
+        rom_data[0x0104] = 0x18;
+        rom_data[0x0105] = 0xFE;
+        Gameboy::from_bytes(&rom_data, TraceMode::Off, true).unwrap()
+    }
+
+    fn run_script(debugger: &mut Debugger, gameboy: &mut Gameboy, script: &str) -> (bool, String) {
+        let mut output = vec![];
+        let resumed = debugger
+            .prompt(gameboy, &mut Cursor::new(script.as_bytes()), &mut output)
+            .unwrap();
+        (resumed, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn test_step_and_inspect() {
+        let mut gameboy = test_gameboy();
+        let mut debugger = Debugger::new();
+
+        let script = "regs\nstep 2\nregs\nmem 0x0104 2\ndisasm 0x0104 1\nbogus\nquit\nregs\n";
+        let (resumed, output) = run_script(&mut debugger, &mut gameboy, script);
+        assert!(!resumed);
+
+        // The state after the boot ROM
+        assert!(output.contains("PC=0x0100 SP=0xFFFE A=0x01 F=0xB0"), "{}", output);
+        assert!(output.contains("PC=0x0102"), "{}", output);
+        assert!(output.contains("0x0104: 18 FE\n"), "{}", output);
+        assert!(output.contains("0x0104: 18 FE    (JumpRelative(None))"), "{}", output);
+        assert!(output.contains("Unknown command: bogus"), "{}", output);
+        // Nothing runs after quit
+        assert_eq!(output.matches("PC=").count(), 2);
+    }
+
+    #[test]
+    fn test_continue_to_breakpoint() {
+        let mut gameboy = test_gameboy();
+        let mut debugger = Debugger::new();
+        assert!(debugger.is_paused());
+
+        let (resumed, _) = run_script(&mut debugger, &mut gameboy, "break 103\ncontinue\n");
+        assert!(resumed);
+        assert!(!debugger.is_paused());
+
+        let mut output = vec![];
+        debugger.run_until_vblank(&mut gameboy, &mut output).unwrap();
+        assert!(debugger.is_paused());
+        assert_eq!(gameboy.debug_pc(), 0x0103);
+        assert_eq!(String::from_utf8(output).unwrap(), "Breakpoint at 0x0103\n");
+
+        // The input ending quits
+        let (resumed, _) = run_script(&mut debugger, &mut gameboy, "delete 0x0103\n");
+        assert!(!resumed);
+    }
+
+    #[test]
+    fn test_parse_address_range() {
+        assert_eq!(parse_address_range("C000-CFFF"), Ok(0xC000..=0xCFFF));
+        assert_eq!(parse_address_range("0xFF40-$FF4B"), Ok(0xFF40..=0xFF4B));
+        assert!(parse_address_range("C000").is_err());
+        assert!(parse_address_range("C000-XYZ").is_err());
+    }
+}
@@ -12,6 +12,7 @@ impl Address {
         }
     }
 
+    // Can't overflow, 0xFF00 + 0xFF is at most 0xFFFF
     pub fn from_lower(lower_addr: u8) -> Address {
         Address::new(0xFF00 + lower_addr as u16)
     }
@@ -20,8 +21,9 @@ impl Address {
         self.plus(1)
     }
 
+    // Addresses wrap around at 0xFFFF, like on hardware
     pub fn plus(&self, offset: u16) -> Self {
-        Self { addr: self.addr + offset }
+        Self { addr: self.addr.wrapping_add(offset) }
     }
 
     pub fn value(&self) -> u16 {
@@ -38,3 +40,15 @@ impl fmt::Debug for Address {
         f.debug_struct("Address").field("addr", &format_args!("{:#06X}", &self.addr)).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_address_wraps() {
+        assert_eq!(Address::new(0xFFFF).next().value(), 0x0000);
+        assert_eq!(Address::new(0xFFFE).plus(3).value(), 0x0001);
+        assert_eq!(Address::from_lower(0xFF).value(), 0xFFFF);
+    }
+}
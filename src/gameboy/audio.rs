@@ -0,0 +1,329 @@
+use super::address::Address;
+use super::utils::get_bit;
+
+// https://gbdev.io/pandocs/Audio_Registers.html
+const NR10: usize = 0x00;
+const NR11: usize = 0x01;
+const NR12: usize = 0x02;
+const NR13: usize = 0x03;
+const NR14: usize = 0x04;
+const NR21: usize = 0x06;
+const NR22: usize = 0x07;
+const NR24: usize = 0x09;
+const NR30: usize = 0x0A;
+const NR31: usize = 0x0B;
+const NR34: usize = 0x0E;
+const NR41: usize = 0x10;
+const NR42: usize = 0x11;
+const NR44: usize = 0x13;
+const NR52: usize = 0x16;
+
+#[derive(Clone)]
+struct Envelope {
+    volume: u8,
+    increase: bool,
+    period: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Self {
+        Self {
+            volume: 0,
+            increase: false,
+            period: 0,
+            timer: 0,
+        }
+    }
+
+    fn trigger(&mut self, nrx2: u8) {
+        self.volume = (nrx2 & 0xF0) >> 4;
+        self.increase = get_bit(nrx2, 3);
+        self.period = nrx2 & 0b111;
+        self.timer = self.period;
+    }
+
+    fn clock(&mut self) {
+        // A period of 0 disables the envelope
+        if self.period == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+
+        if self.increase && self.volume < 0xF {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Channel {
+    enabled: bool,
+    length_enabled: bool,
+    length_counter: u16,
+    max_length: u16,
+    envelope: Envelope,
+}
+
+impl Channel {
+    fn new(max_length: u16) -> Self {
+        Self {
+            enabled: false,
+            length_enabled: false,
+            length_counter: 0,
+            max_length,
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn load_length(&mut self, length_data: u8) {
+        self.length_counter = self.max_length - length_data as u16;
+    }
+
+    fn trigger(&mut self, nrx4: u8, dac_enabled: bool) {
+        self.length_enabled = get_bit(nrx4, 6);
+        if !get_bit(nrx4, 7) {
+            return;
+        }
+
+        self.enabled = dac_enabled;
+        if self.length_counter == 0 {
+            self.length_counter = self.max_length;
+        }
+    }
+
+    fn clock_length(&mut self) {
+        if !self.length_enabled || self.length_counter == 0 {
+            return;
+        }
+
+        self.length_counter -= 1;
+        if self.length_counter == 0 {
+            self.enabled = false;
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Sweep {
+    enabled: bool,
+    shadow_frequency: u16,
+    timer: u8,
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            shadow_frequency: 0,
+            timer: 0,
+        }
+    }
+}
+
+fn sweep_period(nr10: u8) -> u8 {
+    (nr10 & 0b0111_0000) >> 4
+}
+
+fn sweep_shift(nr10: u8) -> u8 {
+    nr10 & 0b111
+}
+
+#[derive(Clone)]
+pub struct Audio {
+    registers: Vec<u8>,
+    wave_pattern: Vec<u8>,
+
+    square1: Channel,
+    square2: Channel,
+    wave: Channel,
+    noise: Channel,
+    sweep: Sweep,
+
+    // Internal
+    frame_sequencer_step: u8,
+}
+
+impl Audio {
+    pub fn new() -> Self {
+        Self {
+            registers: vec![0x00; 0xFF26 - 0xFF10 + 1],
+            wave_pattern: vec![0x00; 0xFF3F - 0xFF30 + 1],
+            square1: Channel::new(64),
+            square2: Channel::new(64),
+            wave: Channel::new(256),
+            noise: Channel::new(64),
+            sweep: Sweep::new(),
+            frame_sequencer_step: 0,
+        }
+    }
+
+    pub fn read(&self, address: Address) -> u8 {
+        match address.value() {
+            0xFF26 => {
+                let mut value = self.registers[NR52] & 0x80;
+                for (bit, channel) in self.channels().iter().enumerate() {
+                    if channel.enabled {
+                        value |= 1 << bit;
+                    }
+                }
+                value
+            }
+            0xFF10..=0xFF25 => self.registers[address.index_value() - 0xFF10],
+            0xFF30..=0xFF3F => self.wave_pattern[address.index_value() - 0xFF30],
+            _ => panic!("Invalid audio address: {:#06X}", address.value()),
+        }
+    }
+
+    pub fn write(&mut self, address: Address, value: u8) {
+        match address.value() {
+            0xFF10..=0xFF26 => {
+                let register = address.index_value() - 0xFF10;
+                self.registers[register] = value;
+                self.on_register_write(register, value);
+            }
+            0xFF30..=0xFF3F => self.wave_pattern[address.index_value() - 0xFF30] = value,
+            _ => panic!("Invalid audio address: {:#06X}", address.value()),
+        }
+    }
+
+    // Clocked at 512 Hz by the falling edge of a DIV bit, see Timer.
+    // https://gbdev.io/pandocs/Audio_details.html#div-apu
+    pub fn step_frame_sequencer(&mut self) {
+        let step = self.frame_sequencer_step;
+
+        if matches!(step, 0 | 2 | 4 | 6) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+
+        if step == 2 || step == 6 {
+            self.clock_sweep();
+        }
+
+        if step == 7 {
+            self.square1.envelope.clock();
+            self.square2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+
+        self.frame_sequencer_step = (step + 1) % 8;
+    }
+
+    fn channels(&self) -> [&Channel; 4] {
+        [&self.square1, &self.square2, &self.wave, &self.noise]
+    }
+
+    fn on_register_write(&mut self, register: usize, value: u8) {
+        match register {
+            NR11 => self.square1.load_length(value & 0b0011_1111),
+            NR21 => self.square2.load_length(value & 0b0011_1111),
+            NR31 => self.wave.load_length(value),
+            NR41 => self.noise.load_length(value & 0b0011_1111),
+
+            // Turning off the DAC also disables the channel
+            NR12 | NR22 | NR42 if value & 0xF8 == 0 => {
+                match register {
+                    NR12 => self.square1.enabled = false,
+                    NR22 => self.square2.enabled = false,
+                    _ => self.noise.enabled = false,
+                };
+            }
+            NR30 if !get_bit(value, 7) => self.wave.enabled = false,
+
+            NR14 => {
+                self.square1.trigger(value, self.registers[NR12] & 0xF8 != 0);
+                if get_bit(value, 7) {
+                    self.square1.envelope.trigger(self.registers[NR12]);
+                    self.trigger_sweep();
+                }
+            }
+            NR24 => {
+                self.square2.trigger(value, self.registers[NR22] & 0xF8 != 0);
+                if get_bit(value, 7) {
+                    self.square2.envelope.trigger(self.registers[NR22]);
+                }
+            }
+            NR34 => self.wave.trigger(value, get_bit(self.registers[NR30], 7)),
+            NR44 => {
+                self.noise.trigger(value, self.registers[NR42] & 0xF8 != 0);
+                if get_bit(value, 7) {
+                    self.noise.envelope.trigger(self.registers[NR42]);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn square1_frequency(&self) -> u16 {
+        ((self.registers[NR14] as u16 & 0b111) << 8) | self.registers[NR13] as u16
+    }
+
+    fn set_square1_frequency(&mut self, frequency: u16) {
+        self.registers[NR13] = (frequency & 0xFF) as u8;
+        self.registers[NR14] = (self.registers[NR14] & !0b111) | ((frequency >> 8) as u8 & 0b111);
+    }
+
+    fn reload_sweep_timer(&mut self) {
+        let period = sweep_period(self.registers[NR10]);
+        // The sweep timer treats a period of 0 as 8
+        self.sweep.timer = if period == 0 { 8 } else { period };
+    }
+
+    fn trigger_sweep(&mut self) {
+        let nr10 = self.registers[NR10];
+        self.sweep.shadow_frequency = self.square1_frequency();
+        self.reload_sweep_timer();
+        self.sweep.enabled = sweep_period(nr10) != 0 || sweep_shift(nr10) != 0;
+
+        if sweep_shift(nr10) != 0 {
+            self.calculate_sweep_frequency();
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep.timer = self.sweep.timer.saturating_sub(1);
+        if self.sweep.timer > 0 {
+            return;
+        }
+        self.reload_sweep_timer();
+
+        let nr10 = self.registers[NR10];
+        if !self.sweep.enabled || sweep_period(nr10) == 0 {
+            return;
+        }
+
+        let new_frequency = self.calculate_sweep_frequency();
+        if new_frequency <= 2047 && sweep_shift(nr10) != 0 {
+            self.sweep.shadow_frequency = new_frequency;
+            self.set_square1_frequency(new_frequency);
+            // The overflow check is done again with the new frequency
+            self.calculate_sweep_frequency();
+        }
+    }
+
+    fn calculate_sweep_frequency(&mut self) -> u16 {
+        let nr10 = self.registers[NR10];
+        let delta = self.sweep.shadow_frequency >> sweep_shift(nr10);
+        let new_frequency = if get_bit(nr10, 3) {
+            self.sweep.shadow_frequency.wrapping_sub(delta)
+        } else {
+            self.sweep.shadow_frequency + delta
+        };
+
+        if new_frequency > 2047 {
+            self.square1.enabled = false;
+        }
+
+        new_frequency
+    }
+}
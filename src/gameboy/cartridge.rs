@@ -1,79 +1,272 @@
-use super::header::CartridgeType;
+use std::rc::Rc;
+
+use super::header::{has_nintendo_logo, CartridgeType, RamSize};
 use super::address::Address;
-use super::utils::{set_bit_mut, get_bit};
+use super::error::EmulatorError;
+
+// Save states clone the whole machine, including the cartridge behind the trait object
+pub trait CartridgeClone {
+    fn clone_box(&self) -> Box<dyn Cartridge>;
+}
+
+impl<T: Cartridge + Clone + 'static> CartridgeClone for T {
+    fn clone_box(&self) -> Box<dyn Cartridge> {
+        Box::new(self.clone())
+    }
+}
 
-pub trait Cartridge {
+impl Clone for Box<dyn Cartridge> {
+    fn clone(&self) -> Self {
+        self.clone_box()
+    }
+}
+
+pub trait Cartridge: CartridgeClone {
     fn read(&self, address: Address) -> u8;
     fn write(&mut self, address: Address, value: u8);
+
+    // Raw access to the external RAM, for save editors and other tools
+    fn export_ram(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn import_ram(&mut self, _data: &[u8]) -> Result<(), String> {
+        Err("Cartridge has no RAM".to_owned())
+    }
+
+    // Debug logging of ignored writes to ROM, for cartridges without an MBC
+    fn set_log_rom_writes(&mut self, _enabled: bool) {}
 }
 
+// External RAM which is missing, disabled or outside the declared size reads as open bus
+const OPEN_BUS: u8 = 0xFF;
+
+fn export_ram_data(ram_data: &[u8]) -> Option<Vec<u8>> {
+    if ram_data.is_empty() {
+        None
+    } else {
+        Some(ram_data.to_vec())
+    }
+}
+
+fn import_ram_data(ram_data: &mut [u8], data: &[u8]) -> Result<(), String> {
+    if ram_data.is_empty() {
+        return Err("Cartridge has no RAM".to_owned());
+    }
+    if data.len() != ram_data.len() {
+        return Err(format!(
+            "RAM size mismatch. Expected {} bytes, got {}",
+            ram_data.len(),
+            data.len()
+        ));
+    }
+    ram_data.copy_from_slice(data);
+    Ok(())
+}
+
+// Without an MBC, writes to ROM do nothing. Games still do it though, so when logging is
+// enabled only the first one is reported instead of flooding the console.
+#[derive(Clone, Default)]
+struct RomWriteLog {
+    enabled: bool,
+    reported: bool,
+}
+
+impl RomWriteLog {
+    fn on_write(&mut self, cartridge_name: &str, address: Address, value: u8) {
+        if !self.enabled || self.reported {
+            return;
+        }
+        self.reported = true;
+        println!(
+            "Ignoring writes to {} cartridge ROM, starting with {:?} = {:#04X}",
+            cartridge_name, address, value
+        );
+    }
+}
+
+#[derive(Clone)]
 struct RomOnly {
-    rom_data: Vec<u8>,
+    rom_data: Rc<[u8]>,
+    rom_write_log: RomWriteLog,
 }
 
 impl RomOnly {
     fn new(rom_data: Vec<u8>) -> Self {
-        Self { rom_data }
+        Self {
+            rom_data: rom_data.into(),
+            rom_write_log: RomWriteLog::default(),
+        }
     }
 }
 
 impl Cartridge for RomOnly {
     fn read(&self, address: Address) -> u8 {
-        return self.rom_data[address.index_value()];
+        match address.value() {
+            0x0000..=0x7FFF => self.rom_data[address.index_value()],
+            0xA000..=0xBFFF => OPEN_BUS,
+            _ => panic!("Invalid cartridge address: {:#06X}", address.value()),
+        }
     }
 
     fn write(&mut self, address: Address, value: u8) {
-        println!("Attempt to write to RomOnly cartridge: {:?} = {}", address, value);
+        match address.value() {
+            0x0000..=0x7FFF => self.rom_write_log.on_write("RomOnly", address, value),
+            // No RAM to write to
+            0xA000..=0xBFFF => (),
+            _ => panic!("Invalid cartridge address: {:#06X}", address.value()),
+        }
+    }
+
+    fn set_log_rom_writes(&mut self, enabled: bool) {
+        self.rom_write_log.enabled = enabled;
+    }
+}
+
+// ROM only cartridge with RAM, but no MBC to bank either.
+// https://gbdev.io/pandocs/nombc.html
+#[derive(Clone)]
+struct RomRam {
+    rom_data: Rc<[u8]>,
+    ram_data: Vec<u8>,
+    rom_write_log: RomWriteLog,
+}
+
+impl RomRam {
+    fn new(rom_data: Vec<u8>, ram_size: RamSize) -> Self {
+        Self {
+            rom_data: rom_data.into(),
+            ram_data: vec![0x00; ram_size.byte_count()],
+            rom_write_log: RomWriteLog::default(),
+        }
+    }
+}
+
+impl Cartridge for RomRam {
+    fn read(&self, address: Address) -> u8 {
+        match address.value() {
+            0x0000..=0x7FFF => self.rom_data[address.index_value()],
+            0xA000..=0xBFFF => {
+                let addr = address.index_value() - 0xA000;
+                self.ram_data.get(addr).copied().unwrap_or(OPEN_BUS)
+            }
+            _ => panic!("Invalid cartridge address: {:#06X}", address.value()),
+        }
+    }
+
+    fn write(&mut self, address: Address, value: u8) {
+        match address.value() {
+            0x0000..=0x7FFF => self.rom_write_log.on_write("RomRam", address, value),
+            0xA000..=0xBFFF => {
+                let addr = address.index_value() - 0xA000;
+                if let Some(data) = self.ram_data.get_mut(addr) {
+                    *data = value;
+                }
+            }
+            _ => panic!("Invalid cartridge address: {:#06X}", address.value()),
+        }
+    }
+
+    fn export_ram(&self) -> Option<Vec<u8>> {
+        export_ram_data(&self.ram_data)
+    }
+
+    fn import_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        import_ram_data(&mut self.ram_data, data)
+    }
+
+    fn set_log_rom_writes(&mut self, enabled: bool) {
+        self.rom_write_log.enabled = enabled;
     }
 }
 
+#[derive(Clone)]
 enum BankingMode {
     UseRom,
     UseRam,
 }
 
+#[derive(Clone)]
 struct MBC1 {
-    rom_data: Vec<u8>,
+    // Shared between save states, as the ROM never changes
+    rom_data: Rc<[u8]>,
     ram_data: Vec<u8>,
+    // BANK1, the lower 5 bits of the ROM bank number
     rom_bank: u8,
-    ram_bank: u8,
+    // BANK2, either the upper ROM bank bits or the RAM bank depending on mode
+    bank2: u8,
     ram_enabled: bool,
     banking_mode: BankingMode,
+    // MBC1M multicarts only wire up 4 bits of BANK1, so BANK2 is shifted by 4 instead of 5
+    // https://gbdev.io/pandocs/MBC1.html#mbc1m-multi-game-compilation-carts
+    is_multicart: bool,
 }
 
 impl MBC1 {
-    fn new(rom_data: Vec<u8>) -> Self {
+    fn new(rom_data: Vec<u8>, ram_size: RamSize, is_multicart: bool) -> Self {
         Self {
-            rom_data,
-            ram_data: vec![0x00; 0x2000 * 4],
+            rom_data: rom_data.into(),
+            ram_data: vec![0x00; ram_size.byte_count()],
             // Zero is not valid number, should be 1 initially
             rom_bank: 0x01,
-            ram_bank: 0x00,
+            bank2: 0x00,
             ram_enabled: false,
             banking_mode: BankingMode::UseRom,
+            is_multicart,
         }
     }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.is_multicart { 4 } else { 5 }
+    }
+
+    // Bank mapped at 0x0000-0x3FFF, only affected by BANK2 in RAM banking mode
+    fn low_rom_bank(&self) -> usize {
+        match self.banking_mode {
+            BankingMode::UseRom => 0,
+            BankingMode::UseRam => (self.bank2 << self.bank2_shift()) as usize,
+        }
+    }
+
+    // Bank mapped at 0x4000-0x7FFF
+    fn high_rom_bank(&self) -> usize {
+        let bank1_mask = if self.is_multicart { 0x0F } else { 0x1F };
+        ((self.bank2 << self.bank2_shift()) | (self.rom_bank & bank1_mask)) as usize
+    }
+
+    fn ram_bank(&self) -> u8 {
+        match self.banking_mode {
+            BankingMode::UseRom => 0,
+            BankingMode::UseRam => self.bank2,
+        }
+    }
+
+    // Index of 0xA000-0xBFFF into the RAM, unless disabled or outside of the RAM size
+    // declared in the header. Cartridges without RAM have no valid index at all.
+    fn ram_index(&self, address: Address) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
+        let index = 0x2000 * self.ram_bank() as usize + address.index_value() - 0xA000;
+        (index < self.ram_data.len()).then_some(index)
+    }
+
+    fn read_rom(&self, bank: usize, offset: usize) -> u8 {
+        // Bank numbers wrap around to the actual ROM size
+        let addr = (0x4000 * bank + offset) % self.rom_data.len();
+        self.rom_data[addr]
+    }
 }
 
 impl Cartridge for MBC1 {
     fn read(&self, address: Address) -> u8 {
         match address.value() {
-            0x0000..=0x3FFF => self.rom_data[address.index_value()],
-            0x4000..=0x7FFF => {
-                let normalized_addr = address.value() - 0x4000;
-                let bank_offset_addr = 0x4000 * (self.rom_bank as u16);
-                let addr = bank_offset_addr + normalized_addr;
-                self.rom_data[addr as usize]
+            0x0000..=0x3FFF => self.read_rom(self.low_rom_bank(), address.index_value()),
+            0x4000..=0x7FFF => self.read_rom(self.high_rom_bank(), address.index_value() - 0x4000),
+            0xA000..=0xBFFF => match self.ram_index(address) {
+                Some(index) => self.ram_data[index],
+                None => OPEN_BUS,
             },
-            0xA000..=0xBFFF => {
-                if !self.ram_enabled {
-                    return 0xFF;
-                }
-                let normalized_addr = address.index_value() - 0xA000;
-                let bank_offset_addr = 0x4000 * self.ram_bank as usize;
-                let addr = bank_offset_addr + normalized_addr;
-                self.ram_data[addr]
-            }
             _ => todo!("Read from unmapped or unimplemented cartridge address: {:#06X}", address.value()),
         }
     }
@@ -84,52 +277,484 @@ impl Cartridge for MBC1 {
                 self.ram_enabled = value & 0xF == 0xA;
             },
             0x2000..=0x3FFF => {
-                if value > 0b0001_1111 {
-                    panic!("Invalid BANK1 register value '{:04X}'. Should we allow this?", value);
-                }
-
-                let fixed_value = match value {
-                    0x0 | 0x20 | 0x40 | 0x60 => value + 1,
-                    _ => value,
-                };
-
-                self.rom_bank = fixed_value;
+                // Only 5 bits are used, and a bank number of 0 is treated as 1.
+                // This is also the case for MBC1M even though it only uses 4 of the bits.
+                let masked_value = value & 0b0001_1111;
+                self.rom_bank = if masked_value == 0 { 1 } else { masked_value };
             }
-            0x4000..=0x5FFF => {
-                match self.banking_mode {
-                    BankingMode::UseRom => {
-                        set_bit_mut(&mut self.rom_bank, 5, get_bit(value, 0));
-                        set_bit_mut(&mut self.rom_bank, 6, get_bit(value, 1));
-
-                    },
-                    BankingMode::UseRam => self.ram_bank = value & 0b11,
-                }
-            },
+            0x4000..=0x5FFF => self.bank2 = value & 0b11,
             0x6000..=0x7FFF => {
-                self.banking_mode = if value == 0 {
+                self.banking_mode = if value & 1 == 0 {
                     BankingMode::UseRom
                 } else {
                     BankingMode::UseRam
                 };
             },
             0xA000..=0xBFFF => {
-                if !self.ram_enabled {
-                    return;
+                if let Some(index) = self.ram_index(address) {
+                    self.ram_data[index] = value;
                 }
-                let normalized_addr = address.index_value() - 0xA000;
-                let bank_offset_addr = 0x4000 * self.ram_bank as usize;
-                let addr = bank_offset_addr + normalized_addr;
-                self.ram_data[addr] = value;
             }
             _ => todo!("Write to unmapped or unimplemented cartridge address: {:#06X} = {:#04X}", address.value(), value)
         }
     }
+
+    fn export_ram(&self) -> Option<Vec<u8>> {
+        export_ram_data(&self.ram_data)
+    }
+
+    fn import_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        import_ram_data(&mut self.ram_data, data)
+    }
+}
+
+// 512 half-bytes of RAM built into the MBC
+const MBC2_RAM_SIZE: usize = 0x200;
+
+// https://gbdev.io/pandocs/MBC2.html
+#[derive(Clone)]
+struct MBC2 {
+    rom_data: Rc<[u8]>,
+    // Only the lower 4 bits of each byte are used
+    ram_data: Vec<u8>,
+    rom_bank: u8,
+    ram_enabled: bool,
+}
+
+impl MBC2 {
+    // The RAM size in the header should be 0, and is ignored since the RAM is built in
+    fn new(rom_data: Vec<u8>) -> Self {
+        Self {
+            rom_data: rom_data.into(),
+            ram_data: vec![0x00; MBC2_RAM_SIZE],
+            rom_bank: 0x01,
+            ram_enabled: false,
+        }
+    }
+
+    fn read_rom(&self, bank: usize, offset: usize) -> u8 {
+        let addr = (0x4000 * bank + offset) % self.rom_data.len();
+        self.rom_data[addr]
+    }
+
+    // The RAM is repeated throughout 0xA000-0xBFFF
+    fn ram_index(address: Address) -> usize {
+        (address.index_value() - 0xA000) % MBC2_RAM_SIZE
+    }
+}
+
+impl Cartridge for MBC2 {
+    fn read(&self, address: Address) -> u8 {
+        match address.value() {
+            0x0000..=0x3FFF => self.read_rom(0, address.index_value()),
+            0x4000..=0x7FFF => self.read_rom(self.rom_bank as usize, address.index_value() - 0x4000),
+            // The upper 4 bits aren't connected
+            0xA000..=0xBFFF if self.ram_enabled => 0xF0 | self.ram_data[Self::ram_index(address)],
+            0xA000..=0xBFFF => OPEN_BUS,
+            _ => panic!("Invalid cartridge address: {:#06X}", address.value()),
+        }
+    }
+
+    fn write(&mut self, address: Address, value: u8) {
+        match address.value() {
+            // Bit 8 of the address selects between the RAM enable and ROM bank registers
+            0x0000..=0x3FFF if address.value() & 0x0100 == 0 => {
+                self.ram_enabled = value & 0xF == 0xA;
+            }
+            0x0000..=0x3FFF => {
+                let masked_value = value & 0b0000_1111;
+                self.rom_bank = if masked_value == 0 { 1 } else { masked_value };
+            }
+            0x4000..=0x7FFF => (),
+            0xA000..=0xBFFF if self.ram_enabled => {
+                self.ram_data[Self::ram_index(address)] = value & 0x0F;
+            }
+            0xA000..=0xBFFF => (),
+            _ => panic!("Invalid cartridge address: {:#06X}", address.value()),
+        }
+    }
+
+    fn export_ram(&self) -> Option<Vec<u8>> {
+        export_ram_data(&self.ram_data)
+    }
+
+    fn import_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        import_ram_data(&mut self.ram_data, data)?;
+        for value in &mut self.ram_data {
+            *value &= 0x0F;
+        }
+        Ok(())
+    }
+}
+
+// The whole address space as plain memory, without any header or banking. Used by
+// CPU tests, together with the flat memory mode of the MMU.
+#[cfg(test)]
+#[derive(Clone)]
+pub struct FlatCartridge {
+    memory: Vec<u8>,
+}
+
+#[cfg(test)]
+impl FlatCartridge {
+    // Memory shorter than the address space is padded with zeros
+    pub fn new(mut memory: Vec<u8>) -> Self {
+        assert!(memory.len() <= 0x10000, "Memory is larger than the address space");
+        memory.resize(0x10000, 0x00);
+        Self { memory }
+    }
+}
+
+#[cfg(test)]
+impl Cartridge for FlatCartridge {
+    fn read(&self, address: Address) -> u8 {
+        self.memory[address.index_value()]
+    }
+
+    fn write(&mut self, address: Address, value: u8) {
+        self.memory[address.index_value()] = value;
+    }
+}
+
+// MBC1M carts are 1 MB, with each game having its own header in bank 0x10, 0x20 and 0x30.
+// There's no header flag for it, so look for the logo of the second game.
+fn is_mbc1_multicart(rom_data: &[u8]) -> bool {
+    rom_data.len() == 0x10_0000 && has_nintendo_logo(&rom_data[0x10 * 0x4000..])
 }
 
-pub fn create_for_cartridge_type(cartridge_type: CartridgeType, rom_data: Vec<u8>) -> Option<Box<dyn Cartridge>> {
+// Exactly the types create_for_cartridge_type accepts, the rest are reported as unsupported
+pub const SUPPORTED_CARTRIDGE_TYPES: &[CartridgeType] = &[
+    CartridgeType::RomOnly,
+    CartridgeType::MBC1,
+    CartridgeType::MBC1Ram,
+    CartridgeType::MBC1RamBattery,
+    CartridgeType::MBC2,
+    CartridgeType::MBC2Battery,
+    CartridgeType::RomRam,
+    CartridgeType::RomRamBattery,
+];
+
+pub fn create_for_cartridge_type(
+    cartridge_type: CartridgeType,
+    ram_size: RamSize,
+    rom_data: Vec<u8>,
+) -> Result<Box<dyn Cartridge>, EmulatorError> {
+    // Go by the type, which is less likely to be wrong than the RAM size, see Header::ram_size_warnings
+    let ram_size = if cartridge_type.has_external_ram() { ram_size } else { RamSize::NoBanks };
     match cartridge_type {
-        CartridgeType::RomOnly => Some(Box::new(RomOnly::new(rom_data))),
-        CartridgeType::MBC1 => Some(Box::new(MBC1::new(rom_data))),
-        _ => None,
+        CartridgeType::RomOnly => Ok(Box::new(RomOnly::new(rom_data))),
+        CartridgeType::MBC1 | CartridgeType::MBC1Ram | CartridgeType::MBC1RamBattery => {
+            let is_multicart = is_mbc1_multicart(&rom_data);
+            Ok(Box::new(MBC1::new(rom_data, ram_size, is_multicart)))
+        }
+        CartridgeType::MBC2 | CartridgeType::MBC2Battery => Ok(Box::new(MBC2::new(rom_data))),
+        CartridgeType::RomRam | CartridgeType::RomRamBattery => {
+            Ok(Box::new(RomRam::new(rom_data, ram_size)))
+        }
+        _ => Err(EmulatorError::UnsupportedCartridge(cartridge_type)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gameboy::header::{Header, NINTENDO_LOGO};
+
+    #[test]
+    fn test_mbc1_ram_is_sized_from_header() {
+        let ram_size = RamSize::Size { bank_count: 1, bank_size_kb: 2 };
+        let mut cartridge = MBC1::new(vec![0x00; 0x8000], ram_size, false);
+
+        // Enable RAM
+        cartridge.write(Address::new(0x0000), 0x0A);
+
+        cartridge.write(Address::new(0xA000), 0x12);
+        cartridge.write(Address::new(0xA7FF), 0x34);
+        assert_eq!(cartridge.read(Address::new(0xA000)), 0x12);
+        assert_eq!(cartridge.read(Address::new(0xA7FF)), 0x34);
+
+        // Only 2 KB is available, so 0xA800 and up is unmapped
+        cartridge.write(Address::new(0xA800), 0x56);
+        assert_eq!(cartridge.read(Address::new(0xA800)), 0xFF);
+        assert_eq!(cartridge.read(Address::new(0xBFFF)), 0xFF);
+    }
+
+    // Built through the factory from a parsed header, the way Gameboy::new does it
+    #[test]
+    fn test_mbc1_ram_cartridge_from_header() {
+        let mut rom_data = vec![0x00; 0x8000];
+        rom_data[0x0104..0x0134].copy_from_slice(&NINTENDO_LOGO);
+        // MBC1+RAM with 2 KB of RAM
+        rom_data[0x0147] = 0x02;
+        rom_data[0x0149] = 0x01;
+        let header = Header::read_from_rom(&rom_data).unwrap();
+        let mut cartridge = create_for_cartridge_type(header.cartridge_type, header.ram_size, rom_data).unwrap();
+
+        cartridge.write(Address::new(0x0000), 0x0A);
+        cartridge.write(Address::new(0xA7FF), 0x34);
+        cartridge.write(Address::new(0xA800), 0x56);
+        assert_eq!(cartridge.read(Address::new(0xA7FF)), 0x34);
+        assert_eq!(cartridge.read(Address::new(0xA800)), 0xFF);
+        assert_eq!(cartridge.export_ram().map(|ram| ram.len()), Some(0x800));
+    }
+
+    #[test]
+    fn test_unavailable_ram_reads_open_bus() {
+        let ram_address = Address::new(0xA123);
+
+        let ram_size = RamSize::Size { bank_count: 1, bank_size_kb: 8 };
+        let mut cartridge = MBC1::new(vec![0x00; 0x8000], ram_size, false);
+        // Disabled, so the write is dropped and the read doesn't reach the RAM
+        cartridge.write(ram_address, 0x12);
+        assert_eq!(cartridge.read(ram_address), 0xFF);
+        assert!(cartridge.ram_data.iter().all(|&value| value == 0x00));
+
+        cartridge.write(Address::new(0x0000), 0x0A);
+        assert_eq!(cartridge.read(ram_address), 0x00);
+        cartridge.write(ram_address, 0x12);
+        assert_eq!(cartridge.read(ram_address), 0x12);
+
+        // Disabling it again hides the contents
+        cartridge.write(Address::new(0x0000), 0x00);
+        assert_eq!(cartridge.read(ram_address), 0xFF);
+
+        // Without any RAM, enabling it makes no difference
+        let mut cartridge = MBC1::new(vec![0x00; 0x8000], RamSize::NoBanks, false);
+        cartridge.write(Address::new(0x0000), 0x0A);
+        cartridge.write(ram_address, 0x12);
+        assert_eq!(cartridge.read(ram_address), 0xFF);
+
+        let mut cartridge = RomOnly::new(vec![0x00; 0x8000]);
+        cartridge.write(ram_address, 0x12);
+        assert_eq!(cartridge.read(ram_address), 0xFF);
+        assert!(!cartridge.rom_write_log.reported);
+    }
+
+    #[test]
+    fn test_rom_only_ignores_writes() {
+        let mut rom_data = vec![0x00; 0x8000];
+        rom_data[0x2000] = 0x42;
+        let mut cartridge = RomOnly::new(rom_data);
+
+        for address in [0x0000, 0x2000, 0x4000, 0x7FFF] {
+            cartridge.write(Address::new(address), 0x01);
+        }
+        assert_eq!(cartridge.read(Address::new(0x2000)), 0x42);
+        // Nothing is logged unless enabled
+        assert!(!cartridge.rom_write_log.reported);
+
+        cartridge.set_log_rom_writes(true);
+        cartridge.write(Address::new(0x2000), 0x01);
+        assert!(cartridge.rom_write_log.reported);
+    }
+
+    #[test]
+    fn test_supported_cartridge_types() {
+        for cartridge_type in SUPPORTED_CARTRIDGE_TYPES {
+            let result = create_for_cartridge_type(*cartridge_type, RamSize::NoBanks, vec![0x00; 0x8000]);
+            assert!(result.is_ok(), "{:?} should be supported", cartridge_type);
+        }
+
+        for cartridge_type in [CartridgeType::MBC5, CartridgeType::HuC3, CartridgeType::BandaiTama5] {
+            let result = create_for_cartridge_type(cartridge_type, RamSize::NoBanks, vec![0x00; 0x8000]);
+            assert!(matches!(result, Err(EmulatorError::UnsupportedCartridge(t)) if t == cartridge_type));
+        }
+        assert_eq!(
+            EmulatorError::UnsupportedCartridge(CartridgeType::HuC3).to_string(),
+            "Unsupported cartridge: HuC3",
+        );
+    }
+
+    #[test]
+    fn test_mbc2_has_built_in_ram() {
+        // Whatever the header says, the RAM is 512 half-bytes
+        let ram_sizes = [RamSize::NoBanks, RamSize::Size { bank_count: 4, bank_size_kb: 8 }];
+        for ram_size in ram_sizes {
+            let mut cartridge = create_for_cartridge_type(CartridgeType::MBC2, ram_size, vec![0x00; 0x8000]).unwrap();
+            assert_eq!(cartridge.export_ram().map(|ram| ram.len()), Some(0x200));
+
+            // Disabled until enabled, through an address with bit 8 cleared
+            cartridge.write(Address::new(0xA000), 0x05);
+            assert_eq!(cartridge.read(Address::new(0xA000)), 0xFF);
+            cartridge.write(Address::new(0x3000), 0x0A);
+
+            // Only the lower nibble is stored, and the RAM repeats every 512 bytes
+            cartridge.write(Address::new(0xA001), 0xA5);
+            assert_eq!(cartridge.read(Address::new(0xA001)), 0xF5);
+            assert_eq!(cartridge.read(Address::new(0xA201)), 0xF5);
+            assert_eq!(cartridge.read(Address::new(0xBE01)), 0xF5);
+        }
+    }
+
+    #[test]
+    fn test_mbc2_rom_banks() {
+        let mut rom_data = vec![0x00; 0x40000];
+        for bank in 0..16 {
+            rom_data[bank * 0x4000] = bank as u8;
+        }
+        let mut cartridge = create_for_cartridge_type(CartridgeType::MBC2, RamSize::NoBanks, rom_data).unwrap();
+        assert_eq!(cartridge.read(Address::new(0x4000)), 1);
+
+        // Bit 8 of the address set selects the ROM bank register, which is 4 bits
+        cartridge.write(Address::new(0x2100), 0x1F);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 15);
+        cartridge.write(Address::new(0x0100), 0x00);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 1);
+
+        // Not the ROM bank register
+        cartridge.write(Address::new(0x2000), 0x03);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 1);
+        assert_eq!(cartridge.read(Address::new(0x0000)), 0);
+    }
+
+    #[test]
+    fn test_rom_ram() {
+        let ram_size = RamSize::Size { bank_count: 1, bank_size_kb: 8 };
+        let mut cartridge = create_for_cartridge_type(CartridgeType::RomRamBattery, ram_size, vec![0x00; 0x8000]).unwrap();
+
+        // No MBC, so the RAM is always enabled
+        cartridge.write(Address::new(0xA000), 0x12);
+        cartridge.write(Address::new(0xBFFF), 0x34);
+        assert_eq!(cartridge.read(Address::new(0xA000)), 0x12);
+        assert_eq!(cartridge.read(Address::new(0xBFFF)), 0x34);
+
+        // And ROM writes are ignored
+        cartridge.write(Address::new(0x2000), 0x56);
+        assert_eq!(cartridge.read(Address::new(0x2000)), 0x00);
+    }
+
+    #[test]
+    fn test_mbc1_ram_export_import() {
+        let ram_size = RamSize::Size { bank_count: 1, bank_size_kb: 8 };
+        let mut cartridge = MBC1::new(vec![0x00; 0x8000], ram_size, false);
+        cartridge.write(Address::new(0x0000), 0x0A);
+        cartridge.write(Address::new(0xA010), 0x12);
+
+        let mut ram = cartridge.export_ram().unwrap();
+        assert_eq!(ram.len(), 0x2000);
+        assert_eq!(ram[0x0010], 0x12);
+
+        ram[0x0010] = 0x34;
+        ram[0x1FFF] = 0x56;
+        cartridge.import_ram(&ram).unwrap();
+        assert_eq!(cartridge.read(Address::new(0xA010)), 0x34);
+        assert_eq!(cartridge.read(Address::new(0xBFFF)), 0x56);
+
+        // The size has to match
+        assert!(cartridge.import_ram(&ram[..0x1000]).is_err());
+        assert_eq!(cartridge.read(Address::new(0xA010)), 0x34);
+    }
+
+    #[test]
+    fn test_rom_only_has_no_ram() {
+        let mut cartridge = RomOnly::new(vec![0x00; 0x8000]);
+        assert_eq!(cartridge.export_ram(), None);
+        assert!(cartridge.import_ram(&[0x00; 0x2000]).is_err());
+    }
+
+    // 1 MB ROM where the first byte of each bank is the bank number
+    fn numbered_banks_rom() -> Vec<u8> {
+        let mut rom_data = vec![0x00; 0x10_0000];
+        for bank in 0..64 {
+            rom_data[bank * 0x4000] = bank as u8;
+        }
+        rom_data
+    }
+
+    fn select_banks(cartridge: &mut MBC1, bank1: u8, bank2: u8, mode: u8) {
+        cartridge.write(Address::new(0x2000), bank1);
+        cartridge.write(Address::new(0x4000), bank2);
+        cartridge.write(Address::new(0x6000), mode);
+    }
+
+    #[test]
+    fn test_mbc1_rom_banking() {
+        let mut cartridge = MBC1::new(numbered_banks_rom(), RamSize::NoBanks, false);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 1);
+
+        select_banks(&mut cartridge, 0x12, 0b01, 0);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 0x32);
+        assert_eq!(cartridge.read(Address::new(0x0000)), 0x00);
+
+        // BANK2 applies to 0x0000-0x3FFF as well in mode 1
+        select_banks(&mut cartridge, 0x12, 0b01, 1);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 0x32);
+        assert_eq!(cartridge.read(Address::new(0x0000)), 0x20);
+
+        // Bank 0 can't be selected through BANK1
+        select_banks(&mut cartridge, 0x00, 0b00, 0);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 0x01);
+    }
+
+    #[test]
+    fn test_mbc1_multicart_banking() {
+        let mut cartridge = MBC1::new(numbered_banks_rom(), RamSize::NoBanks, true);
+
+        // Only 4 bits of BANK1 are used, and BANK2 is shifted by 4
+        select_banks(&mut cartridge, 0x12, 0b01, 0);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 0x12);
+        assert_eq!(cartridge.read(Address::new(0x0000)), 0x00);
+
+        select_banks(&mut cartridge, 0x1F, 0b11, 1);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 0x3F);
+        assert_eq!(cartridge.read(Address::new(0x0000)), 0x30);
+
+        // BANK1 = 0x10 is not treated as 0, so it doesn't become 1
+        select_banks(&mut cartridge, 0x10, 0b10, 0);
+        assert_eq!(cartridge.read(Address::new(0x4000)), 0x20);
+    }
+
+    #[test]
+    fn test_detect_mbc1_multicart() {
+        let mut rom_data = numbered_banks_rom();
+        assert!(!is_mbc1_multicart(&rom_data));
+
+        rom_data[0x40104..0x40134].copy_from_slice(&NINTENDO_LOGO);
+        assert!(is_mbc1_multicart(&rom_data));
+        assert!(!is_mbc1_multicart(&rom_data[..0x80000]));
+    }
+
+    #[test]
+    fn test_mbc1_ram_banks_are_8kb() {
+        let ram_size = RamSize::Size { bank_count: 4, bank_size_kb: 8 };
+        let mut cartridge = MBC1::new(vec![0x00; 0x8000], ram_size, false);
+        cartridge.write(Address::new(0x0000), 0x0A);
+        // RAM banking mode
+        cartridge.write(Address::new(0x6000), 0x01);
+
+        for bank in 0..4 {
+            cartridge.write(Address::new(0x4000), bank);
+            cartridge.write(Address::new(0xA000), 0x10 + bank);
+            cartridge.write(Address::new(0xBFFF), 0x20 + bank);
+        }
+
+        for bank in 0..4 {
+            cartridge.write(Address::new(0x4000), bank);
+            assert_eq!(cartridge.read(Address::new(0xA000)), 0x10 + bank);
+            assert_eq!(cartridge.read(Address::new(0xBFFF)), 0x20 + bank);
+        }
+
+        let ram = cartridge.export_ram().unwrap();
+        assert_eq!((ram[0x0000], ram[0x2000], ram[0x7FFF]), (0x10, 0x11, 0x23));
+    }
+
+    #[test]
+    fn test_ram_size_follows_cartridge_type() {
+        let ram_size = RamSize::Size { bank_count: 4, bank_size_kb: 8 };
+        let cartridge = create_for_cartridge_type(CartridgeType::MBC1RamBattery, ram_size, vec![0x00; 0x8000]).unwrap();
+        assert_eq!(cartridge.export_ram().map(|ram| ram.len()), Some(0x8000));
+
+        // Without RAM in the type, the header RAM size is ignored
+        let cartridge = create_for_cartridge_type(CartridgeType::MBC1, ram_size, vec![0x00; 0x8000]).unwrap();
+        assert_eq!(cartridge.export_ram(), None);
+    }
+
+    #[test]
+    fn test_mbc1_without_ram() {
+        let mut cartridge = MBC1::new(vec![0x00; 0x8000], RamSize::NoBanks, false);
+
+        cartridge.write(Address::new(0x0000), 0x0A);
+        cartridge.write(Address::new(0xA000), 0x12);
+        assert_eq!(cartridge.read(Address::new(0xA000)), 0xFF);
     }
 }
@@ -1,16 +1,21 @@
+use std::collections::HashSet;
 use std::fmt;
+use std::io::{self, BufWriter, Write};
 
+use crate::common::attachment::Attachment;
 use crate::gameboy::instruction_decoder::decode_cb;
 
 use clap::ValueEnum;
 
 use super::cartridge::Cartridge;
+#[cfg(test)]
+use super::cartridge::FlatCartridge;
 use super::instruction_decoder::{
     decode, FlagCondition, IncDecU8Target, Instruction, LoadDstU16, LoadDstU8, LoadSrcU16,
     LoadSrcU8, LogicalOpTarget, RegisterU16, RegisterU8, U16Target, CommonOperand,
 };
 
-use super::mmu::{MMU, Word, InterruptSource, interrupt_vector};
+use super::mmu::{MMU, MmioAccess, Word, InterruptSource, interrupt_vector};
 use super::address::Address;
 use super::utils::{get_bit, set_bit};
 
@@ -24,6 +29,21 @@ pub enum TraceMode {
     WithBoot,
     WithoutBoot,
     Serial,
+    Interrupts,
+    // Every CPU access to an IO register
+    Mmio,
+}
+
+// What to do when running into an undefined opcode, e.g. after jumping into data
+#[derive(Debug, Copy, Clone, ValueEnum, PartialEq)]
+pub enum UnknownOpcodePolicy {
+    // Like hardware, the CPU stops for good and nothing wakes it up
+    Lock,
+    Panic,
+    // Log it and carry on with the next byte
+    Nop,
+    // Log it and HALT, which still wakes up on interrupts
+    Halt,
 }
 
 struct RegisterPair<'a> {
@@ -50,19 +70,15 @@ impl RegisterPair<'_> {
     }
 
     fn set(&mut self, value: u16) {
-        let high = (value & 0xFF00) >> 8;
-        let low = value & 0x00FF;
-
-        *self.high = high as u8;
-        *self.low = low as u8;
+        let [low, high] = Word::new(value).to_le_bytes();
+        *self.high = high;
+        *self.low = low;
     }
 }
 
 impl ImmutableRegisterPair<'_> {
     fn get(&self) -> u16 {
-        let high = self.high.clone() as u16;
-        let low = self.get_low() as u16;
-        return high << 8 | low;
+        Word::from_le_bytes([self.get_low(), *self.high]).value
     }
 
     fn get_low(&self) -> u8 {
@@ -87,6 +103,7 @@ struct FlagDebug {
     c: bool,
 }
 
+#[derive(Clone)]
 pub struct FlagRegister {
     value: u8,
 }
@@ -150,6 +167,23 @@ pub struct FlagChange {
     c: Option<bool>,
 }
 
+// Register state which tests can set up and compare, and the debugger shows
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Registers {
+    pub pc: u16,
+    pub sp: u16,
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+    pub ime: bool,
+}
+
+#[derive(Clone)]
 pub struct CPU {
     pc: u16,
     sp: u16,
@@ -165,9 +199,19 @@ pub struct CPU {
     flag_register: FlagRegister,
     did_take_conditional_branch: bool,
     halted: bool,
+    // Locked up by an illegal opcode, until reset
+    locked: bool,
+    unknown_opcode_policy: UnknownOpcodePolicy,
+    // PC and opcode of the illegal opcodes already reported, so a loop over one only logs it once
+    reported_illegal_opcodes: HashSet<(u16, u8)>,
 
     // Debug
     trace_mode: TraceMode,
+    trace_writer: Attachment<Box<dyn Write>>,
+    // How many times each opcode has executed, only counted when enabled
+    count_opcodes: bool,
+    opcode_histogram: [u64; 256],
+    cb_opcode_histogram: [u64; 256],
 }
 
 impl fmt::Debug for CPU {
@@ -187,7 +231,7 @@ impl fmt::Debug for CPU {
 }
 
 fn verify_state(
-    cpu: &CPU,
+    cpu: &mut CPU,
     maybe_metadata: Option<&ReferenceMetadata>,
     i: usize,
     pc: u16,
@@ -205,6 +249,10 @@ fn verify_state(
     } else { None };
 
     if let Some(message) = maybe_error_message {
+        // Make sure the trace leading up to the mismatch isn't lost
+        if let Some(writer) = cpu.trace_writer.as_mut() {
+            writer.flush().expect("Failed to flush trace");
+        }
         println!("CPU (tick {}): {:#?}", i, cpu);
         panic!("{}", message);
     }
@@ -215,12 +263,18 @@ enum OpcodeType {
     Cb,
 }
 
+fn new_mmu(cartridge: Box<dyn Cartridge>, trace_mode: TraceMode, is_cgb: bool) -> MMU {
+    let mut mmu = MMU::new(cartridge, trace_mode == TraceMode::Serial, is_cgb);
+    mmu.set_trace_mmio(trace_mode == TraceMode::Mmio);
+    mmu
+}
+
 impl CPU {
-    pub fn new(cartridge: Box<dyn Cartridge>, trace_mode: TraceMode) -> CPU {
+    pub fn new(cartridge: Box<dyn Cartridge>, trace_mode: TraceMode, is_cgb: bool) -> CPU {
         CPU {
             pc: 0x0000,
             sp: 0x0FFFE,
-            mmu: MMU::new(cartridge, trace_mode == TraceMode::Serial),
+            mmu: new_mmu(cartridge, trace_mode, is_cgb),
             a: 0x00,
             b: 0x00,
             c: 0x00,
@@ -232,15 +286,22 @@ impl CPU {
             flag_register: FlagRegister::new(),
             did_take_conditional_branch: false,
             halted: false,
+            locked: false,
+            unknown_opcode_policy: UnknownOpcodePolicy::Lock,
+            reported_illegal_opcodes: HashSet::new(),
             trace_mode,
+            trace_writer: Attachment::new(Some(Box::new(BufWriter::new(io::stdout())))),
+            count_opcodes: false,
+            opcode_histogram: [0; 256],
+            cb_opcode_histogram: [0; 256],
         }
     }
 
-    pub fn new_without_boot_rom(cartridge: Box<dyn Cartridge>, trace_mode: TraceMode) -> CPU {
+    pub fn new_without_boot_rom(cartridge: Box<dyn Cartridge>, trace_mode: TraceMode, is_cgb: bool) -> CPU {
         CPU {
             pc: 0x0100,
             sp: 0x0FFFE,
-            mmu: MMU::new(cartridge, trace_mode == TraceMode::Serial),
+            mmu: new_mmu(cartridge, trace_mode, is_cgb),
             a: 0x01,
             b: 0x00,
             c: 0x13,
@@ -252,12 +313,109 @@ impl CPU {
             flag_register: FlagRegister::new_without_boot_rom(),
             did_take_conditional_branch: false,
             halted: false,
+            locked: false,
+            unknown_opcode_policy: UnknownOpcodePolicy::Lock,
+            reported_illegal_opcodes: HashSet::new(),
             trace_mode,
+            trace_writer: Attachment::new(Some(Box::new(BufWriter::new(io::stdout())))),
+            count_opcodes: false,
+            opcode_histogram: [0; 256],
+            cb_opcode_histogram: [0; 256],
+        }
+    }
+
+    // Every address goes straight to the cartridge, without any IO or boot ROM
+    #[cfg(test)]
+    pub fn new_with_flat_memory(cartridge: Box<dyn Cartridge>) -> CPU {
+        let mut cpu = CPU::new(cartridge, TraceMode::Off, false);
+        cpu.mmu.use_flat_memory();
+        cpu
+    }
+
+    // Flat memory holding the given program at 0x0000, where execution starts
+    #[cfg(test)]
+    pub fn new_for_test(memory: Vec<u8>) -> CPU {
+        CPU::new_with_flat_memory(Box::new(FlatCartridge::new(memory)))
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp,
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            f: self.flag_register.value,
+            h: self.h,
+            l: self.l,
+            ime: self.interrupts_enabled,
         }
     }
 
+    #[cfg(test)]
+    pub fn set_registers(&mut self, registers: &Registers) {
+        self.pc = registers.pc;
+        self.sp = registers.sp;
+        self.a = registers.a;
+        self.b = registers.b;
+        self.c = registers.c;
+        self.d = registers.d;
+        self.e = registers.e;
+        self.flag_register.value = registers.f;
+        self.h = registers.h;
+        self.l = registers.l;
+        self.interrupts_enabled = registers.ime;
+    }
+
+    // Trace output is written to stdout unless another writer is given
+    pub fn set_trace_writer(&mut self, writer: Box<dyn Write>) {
+        *self.trace_writer = Some(writer);
+    }
+
+    pub fn set_unknown_opcode_policy(&mut self, policy: UnknownOpcodePolicy) {
+        self.unknown_opcode_policy = policy;
+    }
+
+    pub fn set_count_opcodes(&mut self, enabled: bool) {
+        self.count_opcodes = enabled;
+    }
+
+    pub fn opcode_histogram(&self) -> &[u64; 256] {
+        &self.opcode_histogram
+    }
+
+    // Indexed by the opcode following the 0xCB prefix
+    pub fn cb_opcode_histogram(&self) -> &[u64; 256] {
+        &self.cb_opcode_histogram
+    }
+
+    // A save state is a clone of the whole machine. Anything attached by the frontend, like
+    // the trace writer, isn't part of it and is kept as is when loading a state.
+    pub fn save_state(&self) -> CPU {
+        self.clone()
+    }
+
+    pub fn load_state(&mut self, state: &CPU) {
+        let mut previous = std::mem::replace(self, state.clone());
+        *self.trace_writer = previous.trace_writer.take();
+        self.mmu.take_attachments_from(&mut previous.mmu);
+    }
+
     pub fn tick(&mut self, maybe_metadata: Option<&ReferenceMetadata>, i: usize) -> u8 {
-        let interrupt_cycles = self.maybe_process_interrupts();
+        if self.locked {
+            // Time still passes for the rest of the system
+            return 1;
+        }
+
+        let was_halted = self.halted;
+        let mut interrupt_cycles = self.maybe_process_interrupts();
+        if was_halted && interrupt_cycles > 0 {
+            // Exiting HALT takes one more M-cycle before the interrupt is dispatched
+            // https://gbdev.io/pandocs/halt.html#halt
+            interrupt_cycles += 1;
+        }
 
         if self.halted {
             // Handling an interrupt
@@ -268,17 +426,28 @@ impl CPU {
         self.did_take_conditional_branch = false;
 
         let pc = self.pc;
+        self.mmu.set_current_pc(pc);
         let (instruction, opcode_type, opcode) = self.next_instruction();
 
+        if self.count_opcodes {
+            match opcode_type {
+                OpcodeType::Normal => self.opcode_histogram[opcode as usize] += 1,
+                OpcodeType::Cb => self.cb_opcode_histogram[opcode as usize] += 1,
+            }
+        }
+
         let should_trace = match self.trace_mode {
             TraceMode::Off => false,
             TraceMode::WithBoot => true,
             TraceMode::WithoutBoot => self.mmu.boot_rom_disabled(),
             TraceMode::Serial => false,
+            TraceMode::Interrupts => false,
+            TraceMode::Mmio => false,
         };
 
-        if should_trace {
-            println!("{:#06X}: {:#04X} ({:?})", pc, opcode, instruction);
+        if let Some(writer) = self.trace_writer.as_mut().filter(|_| should_trace) {
+            writeln!(writer, "{:#06X}: {:#04X} ({:?})", pc, opcode, instruction)
+                .expect("Failed to write trace");
         }
 
         verify_state(self, maybe_metadata, i, pc);
@@ -300,20 +469,11 @@ impl CPU {
                 self.write_u16_target(dst, value);
             }
             Instruction::LoadHlWithOffsetSp => {
-                // TODO: Move
-                let offset = self.read_u8() as i8 as i16;
-                let signed_sp = self.sp as i16;
-                let result = signed_sp.wrapping_add(offset);
-
-                self.write_u16_target(LoadDstU16::Register(RegisterU16::HL), result as u16);
-                let signed_mask = 0xFFFF as u16 as i16;
-
-                self.apply_flag_change(FlagChange {
-                    z: Some(false),
-                    n: Some(false),
-                    h: Some(((signed_sp ^ offset ^ (result & signed_mask)) & 0x10) == 0x10),
-                    c: Some(((signed_sp ^ offset ^ (result & signed_mask)) & 0x100) == 0x100),
-                });
+                let offset = self.read_u8();
+                let (result, flag_change) = add_signed_offset(self.sp, offset);
+
+                self.write_u16_target(LoadDstU16::Register(RegisterU16::HL), result);
+                self.apply_flag_change(flag_change);
             }
             Instruction::Call(condition) => self.call(condition),
             Instruction::JumpRelative(condition) => self.relative_jump(condition),
@@ -365,13 +525,20 @@ impl CPU {
             Instruction::Ccf => self.ccf(),
             Instruction::Daa => self.daa(),
             Instruction::Rst(addr) => self.rst(addr),
+            Instruction::Illegal(opcode) => self.illegal(pc, opcode),
             Instruction::Stop => {
-                // TODO: Should we actually do anything?
+                // TODO: Should we actually do anything besides switching speed?
                 // Note that stop is encoded as 0x10 0x00, i.e. 2 bytes,
                 // but since 0x00 is NOP it's fine,
+                self.mmu.reset_divider();
+                self.mmu.try_switch_speed();
             }
         }
 
+        if self.trace_mode == TraceMode::Mmio {
+            self.write_mmio_trace();
+        }
+
         let elapsed_cycles = match (self.did_take_conditional_branch, opcode_type) {
             (false, OpcodeType::Normal) => cycles::NORMAL_OPCODE_CYCLES[opcode as usize],
             (false, OpcodeType::Cb) => cycles::CB_OPCODE_CYCLES[opcode as usize],
@@ -382,10 +549,18 @@ impl CPU {
         return elapsed_cycles + interrupt_cycles;
     }
 
-    pub fn mmu(&mut self) -> &mut MMU {
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn mmu_mut(&mut self) -> &mut MMU {
         &mut self.mmu
     }
 
+    pub fn mmu(&self) -> &MMU {
+        &self.mmu
+    }
+
     fn maybe_process_interrupts(&mut self) -> u8 {
         let interrupt_per_priority: &[InterruptSource] = &[
             InterruptSource::VBlank,
@@ -416,25 +591,53 @@ impl CPU {
 
     // https://gbdev.io/pandocs/Interrupts.html#interrupt-handling
     fn handle_interrupt(&mut self, interrupt: InterruptSource) -> u8 {
+        let should_trace = self.trace_mode == TraceMode::Interrupts;
+        if let Some(writer) = self.trace_writer.as_mut().filter(|_| should_trace) {
+            let (interrupt_enable, interrupt_flags) = self.mmu.interrupt_registers();
+            writeln!(
+                writer,
+                "Interrupt {:?}: {:#06X} -> {:#06X} (IE: {:#04X}, IF: {:#04X})",
+                interrupt, self.pc, interrupt_vector(interrupt), interrupt_enable, interrupt_flags,
+            ).expect("Failed to write trace");
+        }
+
         // The IF bit corresponding to this interrupt and the IME flag are reset by the CPU.
         self.interrupts_enabled = false;
         self.mmu.set_interrupt_flag(interrupt, false);
 
         // The corresponding interrupt handler is called by the CPU.
-
+        // Each M-cycle is consumed as it happens, so the timers tick during the dispatch
+        // like they do for memory accesses within an instruction.
 
         // Two wait states are executed (2 M-cycles pass while nothing happens;
         // presumably the CPU is executing nops during this time).
+        self.mmu.consume_idle_cycle();
+        self.mmu.consume_idle_cycle();
 
         // The current value of the PC register is pushed onto the stack, consuming 2 more M-cycles.
         self.stack_push(self.pc);
-        // The PC register is set to the address of the handler
+        // The PC register is set to the address of the handler, consuming 1 more M-cycle.
         self.pc = interrupt_vector(interrupt) as u16;
+        self.mmu.consume_idle_cycle();
 
         // Interrupt handling should last 5 M-cycles.
         return 5;
     }
 
+    fn write_mmio_trace(&mut self) {
+        let log = self.mmu.take_mmio_log();
+        if let Some(writer) = self.trace_writer.as_mut() {
+            for (pc, address, value, access) in log {
+                let direction = match access {
+                    MmioAccess::Read => "read",
+                    MmioAccess::Write => "write",
+                };
+                writeln!(writer, "{:#06X}: {} {:#06X} = {:#04X}", pc, direction, address, value)
+                    .expect("Failed to write trace");
+            }
+        }
+    }
+
     fn should_fire_interrupt(&self, interrupt: InterruptSource) -> bool {
         self.mmu.is_interrupt_enabled(interrupt) && self.mmu.has_interrupt_flag(interrupt)
     }
@@ -453,6 +656,32 @@ impl CPU {
         return (decoded, OpcodeType::Normal, opcode);
     }
 
+    // https://gbdev.io/pandocs/CPU_Comparison.html#gb-vs-cgb-vs-sgb-undefined-opcodes
+    fn illegal(&mut self, pc: u16, opcode: u8) {
+        let message = format!("Illegal opcode: {:#06X}: {:#04X}", pc, opcode);
+        let first_time = self.reported_illegal_opcodes.insert((pc, opcode));
+        match self.unknown_opcode_policy {
+            UnknownOpcodePolicy::Lock => {
+                if first_time {
+                    println!("{}, the CPU is locked up", message);
+                }
+                self.locked = true;
+            }
+            UnknownOpcodePolicy::Panic => panic!("{}", message),
+            UnknownOpcodePolicy::Nop => {
+                if first_time {
+                    println!("{}, running it as NOP", message);
+                }
+            }
+            UnknownOpcodePolicy::Halt => {
+                if first_time {
+                    println!("{}, halting", message);
+                }
+                self.halted = true;
+            }
+        }
+    }
+
     fn next_pc(&mut self) -> u16 {
         let tmp = self.pc;
         self.pc += 1;
@@ -465,9 +694,9 @@ impl CPU {
     }
 
     fn read_u16(&mut self) -> u16 {
-        let a = self.read_u8() as u16;
-        let b = self.read_u8() as u16;
-        return b << 8 | a;
+        let low = self.read_u8();
+        let high = self.read_u8();
+        Word::from_le_bytes([low, high]).value
     }
 
     fn resolve_u8_reg(&mut self, reg: RegisterU8) -> &mut u8 {
@@ -595,6 +824,8 @@ impl CPU {
     }
 
     fn rst(&mut self, addr: u16) {
+        // Internal M-cycle for decrementing SP, before the two writes
+        self.mmu.consume_idle_cycle();
         self.stack_push(self.pc);
         self.pc = addr;
     }
@@ -602,14 +833,20 @@ impl CPU {
     fn call(&mut self, condition: Option<FlagCondition>) {
         let target_address = self.read_u16();
         if self.is_flag_condition_true(condition) {
+            // Internal M-cycle for decrementing SP, before the two writes
+            self.mmu.consume_idle_cycle();
             self.stack_push(self.pc);
             self.pc = target_address;
         }
     }
 
+    // The high byte is written first, as SP is decremented before each write
     fn stack_push(&mut self, value: u16) {
-        self.sp -= 2;
-        self.mmu.write_word(Address::new(self.sp), Word::new(value));
+        let [low, high] = Word::new(value).to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        self.mmu.write(Address::new(self.sp), high);
+        self.sp = self.sp.wrapping_sub(1);
+        self.mmu.write(Address::new(self.sp), low);
     }
 
     fn stack_pop(&mut self) -> u16 {
@@ -636,14 +873,22 @@ impl CPU {
     }
 
     fn ret(&mut self, condition: Option<FlagCondition>) {
+        if condition.is_some() {
+            // Internal M-cycle for checking the condition
+            self.mmu.consume_idle_cycle();
+        }
         if self.is_flag_condition_true(condition) {
             let new_pc = self.stack_pop();
+            // Internal M-cycle for setting PC
+            self.mmu.consume_idle_cycle();
             self.pc = new_pc;
         }
     }
 
     fn push(&mut self, reg: RegisterU16) {
         let value = self.resolve_u16_reg(&reg).get();
+        // Internal M-cycle for decrementing SP, before the two writes
+        self.mmu.consume_idle_cycle();
         self.stack_push(value);
     }
 
@@ -849,20 +1094,11 @@ impl CPU {
     }
 
     fn add_stackpointer_immediate(&mut self) {
-        let offset = self.read_u8() as i8 as i16;
-        let signed_sp = self.sp as i16;
-        let result = signed_sp.wrapping_add(offset);
-
-        self.sp = result as u16;
+        let offset = self.read_u8();
+        let (result, flag_change) = add_signed_offset(self.sp, offset);
 
-        let signed_mask = 0xFFFF as u16 as i16;
-
-        self.apply_flag_change(FlagChange {
-            z: Some(false),
-            n: Some(false),
-            h: Some(((signed_sp ^ offset ^ (result & signed_mask)) & 0x10) == 0x10),
-            c: Some(((signed_sp ^ offset ^ (result & signed_mask)) & 0x100) == 0x100),
-        });
+        self.sp = result;
+        self.apply_flag_change(flag_change);
     }
 
     fn compare(&mut self, target: LogicalOpTarget) {
@@ -1214,9 +1450,27 @@ fn swap_nibbles(value: u8) -> u8 {
    ((value & 0x0F) << 4) | ((value & 0xF0) >> 4)
 }
 
+// Used by `ADD SP, e` and `LD HL, SP + e`. The offset is signed, but the flags are
+// computed from the unsigned addition of the offset and the low byte of SP.
+fn add_signed_offset(sp: u16, offset: u8) -> (u16, FlagChange) {
+    let result = sp.wrapping_add(offset as i8 as u16);
+
+    let half_carry = (sp & 0xF) + (offset as u16 & 0xF) > 0xF;
+    let carry = (sp & 0xFF) + (offset as u16) > 0xFF;
+
+    (result, FlagChange {
+        z: Some(false),
+        n: Some(false),
+        h: Some(half_carry),
+        c: Some(carry),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::gameboy::cartridge::create_for_cartridge_type;
+    use crate::gameboy::header::{CartridgeType, RamSize};
 
     #[test]
     fn test_swap_nibbles() {
@@ -1225,6 +1479,585 @@ mod tests {
         assert_eq!(swap_nibbles(0xF0), 0x0F);
     }
 
+    #[test]
+    fn test_add_signed_offset() {
+        let (result, flags) = add_signed_offset(0xFFF8, 0x02);
+        assert_eq!(result, 0xFFFA);
+        assert_eq!((flags.h, flags.c), (Some(false), Some(false)));
+
+        let (result, flags) = add_signed_offset(0x000F, 0x01);
+        assert_eq!(result, 0x0010);
+        assert_eq!((flags.h, flags.c), (Some(true), Some(false)));
+
+        let (result, flags) = add_signed_offset(0x00FF, 0x01);
+        assert_eq!(result, 0x0100);
+        assert_eq!((flags.h, flags.c), (Some(true), Some(true)));
+
+        // -1
+        let (result, flags) = add_signed_offset(0x0000, 0xFF);
+        assert_eq!(result, 0xFFFF);
+        assert_eq!((flags.h, flags.c), (Some(false), Some(false)));
+
+        // -1, carry is still set from the unsigned low byte addition
+        let (result, flags) = add_signed_offset(0xFFFF, 0xFF);
+        assert_eq!(result, 0xFFFE);
+        assert_eq!((flags.h, flags.c), (Some(true), Some(true)));
+
+        // -128
+        let (result, flags) = add_signed_offset(0xD000, 0x80);
+        assert_eq!(result, 0xCF80);
+        assert_eq!((flags.h, flags.c), (Some(false), Some(false)));
+        assert_eq!((flags.z, flags.n), (Some(false), Some(false)));
+    }
+
+    #[test]
+    fn test_stack_pointer_offset_instructions() {
+        let mut cpu = CPU::new_for_test(vec![
+            0x31, 0xF8, 0xC0, // LD SP, 0xC0F8
+            0xE8, 0x08, // ADD SP, 8
+            0xF8, 0xFF, // LD HL, SP-1
+            0xF9, // LD SP, HL
+            0xE8, 0xFF, // ADD SP, -1
+            0xF8, 0x80, // LD HL, SP-128
+        ]);
+        assert_eq!(cpu.tick(None, 0), 3);
+
+        // Flags come from the unsigned addition to the low byte of SP, even for negative offsets
+        assert_eq!(cpu.tick(None, 1), 4);
+        assert_eq!(cpu.registers().sp, 0xC100);
+        assert_eq!(cpu.registers().f, 0x30);
+
+        assert_eq!(cpu.tick(None, 2), 3);
+        let registers = cpu.registers();
+        assert_eq!((registers.h, registers.l), (0xC0, 0xFF));
+        assert_eq!(registers.sp, 0xC100);
+        assert_eq!(registers.f, 0x00);
+
+        assert_eq!(cpu.tick(None, 3), 2);
+        assert_eq!(cpu.tick(None, 4), 4);
+        assert_eq!(cpu.registers().sp, 0xC0FE);
+        assert_eq!(cpu.registers().f, 0x30);
+
+        assert_eq!(cpu.tick(None, 5), 3);
+        let registers = cpu.registers();
+        assert_eq!((registers.h, registers.l), (0xC0, 0x7E));
+        assert_eq!(registers.f, 0x10);
+    }
+
+    #[test]
+    fn test_unknown_opcode_policy() {
+        // Undefined, then LD A, 0x42
+        let program = vec![0xD3, 0x3E, 0x42];
+
+        let mut cpu = CPU::new_for_test(program.clone());
+        cpu.set_unknown_opcode_policy(UnknownOpcodePolicy::Nop);
+        assert_eq!(cpu.tick(None, 0), 1);
+        assert_eq!(cpu.pc, 0x0001);
+        assert_eq!(cpu.tick(None, 1), 2);
+        assert_eq!(cpu.registers().a, 0x42);
+
+        let mut cpu = CPU::new_for_test(program);
+        cpu.set_unknown_opcode_policy(UnknownOpcodePolicy::Halt);
+        cpu.tick(None, 0);
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x0001);
+    }
+
+    #[test]
+    fn test_illegal_opcode_reported_once() {
+        // Undefined, then JR back to it
+        let mut cpu = CPU::new_for_test(vec![0xD3, 0x18, 0xFD]);
+        cpu.set_unknown_opcode_policy(UnknownOpcodePolicy::Nop);
+        for cycle in 0..10 {
+            cpu.tick(None, cycle);
+        }
+        assert_eq!(cpu.reported_illegal_opcodes, HashSet::from([(0x0000, 0xD3)]));
+    }
+
+    #[test]
+    #[should_panic(expected = "Illegal opcode: 0x0000: 0xD3")]
+    fn test_unknown_opcode_panics() {
+        let mut cpu = CPU::new_for_test(vec![0xD3]);
+        cpu.set_unknown_opcode_policy(UnknownOpcodePolicy::Panic);
+        cpu.tick(None, 0);
+    }
+
+    #[test]
+    fn test_illegal_opcode_locks_up() {
+        // EI, illegal, LD A, 0x42
+        let mut cpu = CPU::new_for_test(vec![0xFB, 0xDB, 0x3E, 0x42]);
+        cpu.tick(None, 0);
+        assert_eq!(cpu.tick(None, 1), 1);
+        assert!(cpu.locked);
+        assert_eq!(cpu.pc, 0x0002);
+
+        // Not even an interrupt gets it going again
+        cpu.mmu.write(Address::new(0xFFFF), 0b0000_0001);
+        cpu.mmu.set_interrupt_flag(InterruptSource::VBlank, true);
+        for i in 2..10 {
+            assert_eq!(cpu.tick(None, i), 1);
+        }
+        assert_eq!(cpu.pc, 0x0002);
+        assert_eq!(cpu.registers().a, 0x00);
+    }
+
+    fn new_test_cpu() -> CPU {
+        let cartridge = create_for_cartridge_type(CartridgeType::RomOnly, RamSize::NoBanks, vec![0x00; 0x8000]).unwrap();
+        CPU::new_without_boot_rom(cartridge, TraceMode::Off, false)
+    }
+
+    fn to_bcd(value: u8) -> u8 {
+        ((value / 10) << 4) | (value % 10)
+    }
+
+    // Reference DAA, written in terms of the adjustment that is applied rather than
+    // the magic constants used by the implementation. The low nibble is adjusted by 6
+    // if it overflowed (H) or, after an addition, isn't a valid BCD digit. Likewise the
+    // high nibble is adjusted by 0x60 if the byte overflowed (C) or is above 0x99. After
+    // a subtraction the digits can't be out of range, so only the flags are considered,
+    // and the adjustment is subtracted instead. C is only ever set, never cleared.
+    fn reference_daa(a: u8, n: bool, h: bool, c: bool) -> (u8, bool) {
+        let mut adjustment = 0;
+        let mut carry = c;
+
+        if h || (!n && a & 0x0F > 0x09) {
+            adjustment |= 0x06;
+        }
+        if c || (!n && a > 0x99) {
+            adjustment |= 0x60;
+            carry = true;
+        }
+
+        let result = if n {
+            a.wrapping_sub(adjustment)
+        } else {
+            a.wrapping_add(adjustment)
+        };
+        (result, carry)
+    }
+
+    #[test]
+    fn test_daa_all_inputs() {
+        let mut cpu = new_test_cpu();
+
+        for a in 0..=0xFF {
+            for flags in 0..8 {
+                let (n, h, c) = (flags & 0b100 != 0, flags & 0b010 != 0, flags & 0b001 != 0);
+                cpu.a = a;
+                cpu.flag_register.set_z(false);
+                cpu.flag_register.set_n(n);
+                cpu.flag_register.set_h(h);
+                cpu.flag_register.set_c(c);
+
+                cpu.daa();
+
+                let (expected_a, expected_c) = reference_daa(a, n, h, c);
+                let context = format!("A={:#04X} N={} H={} C={}", a, n, h, c);
+                assert_eq!(cpu.a, expected_a, "{}", context);
+                assert_eq!(cpu.flag_register.get_z(), expected_a == 0, "{}", context);
+                assert_eq!(cpu.flag_register.get_n(), n, "{}", context);
+                assert!(!cpu.flag_register.get_h(), "{}", context);
+                assert_eq!(cpu.flag_register.get_c(), expected_c, "{}", context);
+            }
+        }
+    }
+
+    // DAA after ADD/SUB of two BCD numbers should give the decimal result
+    #[test]
+    fn test_daa_after_bcd_arithmetic() {
+        let mut cpu = new_test_cpu();
+        let operand = || LogicalOpTarget::Common(CommonOperand::Register(RegisterU8::B));
+
+        for x in 0..100 {
+            for y in 0..100 {
+                cpu.a = to_bcd(x);
+                cpu.b = to_bcd(y);
+                cpu.add_u8(operand());
+                cpu.daa();
+                assert_eq!(cpu.a, to_bcd((x + y) % 100), "{} + {}", x, y);
+                assert_eq!(cpu.flag_register.get_c(), x + y >= 100, "{} + {}", x, y);
+
+                cpu.a = to_bcd(x);
+                cpu.b = to_bcd(y);
+                cpu.sub(operand());
+                cpu.daa();
+                assert_eq!(cpu.a, to_bcd((x + 100 - y) % 100), "{} - {}", x, y);
+                assert_eq!(cpu.flag_register.get_c(), x < y, "{} - {}", x, y);
+            }
+        }
+    }
+
+    // Shares the written data, so it can be inspected after handing it to the CPU
+    #[derive(Clone)]
+    struct SharedBuffer(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace_writer() {
+        let mut rom_data = vec![0x00; 0x8000];
+        // XOR A
+        rom_data[0x0101] = 0xAF;
+        let cartridge = create_for_cartridge_type(CartridgeType::RomOnly, RamSize::NoBanks, rom_data).unwrap();
+        let mut cpu = CPU::new_without_boot_rom(cartridge, TraceMode::WithBoot, false);
+
+        let buffer = SharedBuffer(Default::default());
+        cpu.set_trace_writer(Box::new(buffer.clone()));
+        cpu.tick(None, 0);
+        cpu.tick(None, 1);
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "0x0100: 0x00 (Noop)");
+        assert!(lines[1].starts_with("0x0101: 0xAF (Xor("), "{}", lines[1]);
+    }
+
+    #[test]
+    fn test_interrupt_trace() {
+        let cartridge = create_for_cartridge_type(CartridgeType::RomOnly, RamSize::NoBanks, vec![0x00; 0x8000]).unwrap();
+        let mut cpu = CPU::new_without_boot_rom(cartridge, TraceMode::Interrupts, false);
+
+        let buffer = SharedBuffer(Default::default());
+        cpu.set_trace_writer(Box::new(buffer.clone()));
+        cpu.tick(None, 0);
+
+        cpu.interrupts_enabled = true;
+        cpu.mmu.write(Address::new(0xFFFF), 0b0000_0101);
+        cpu.mmu.set_interrupt_flag(InterruptSource::Timer, true);
+        cpu.tick(None, 1);
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(trace, "Interrupt Timer: 0x0101 -> 0x0050 (IE: 0x05, IF: 0x04)\n");
+        assert_eq!(cpu.pc, 0x0051);
+    }
+
+    #[test]
+    fn test_mmio_trace() {
+        let mut rom_data = vec![0x00; 0x8000];
+        // LD A, 0xE4; LDH (0x47), A; LDH A, (0x47)
+        rom_data[0x0100..0x0106].copy_from_slice(&[0x3E, 0xE4, 0xE0, 0x47, 0xF0, 0x47]);
+        let cartridge = create_for_cartridge_type(CartridgeType::RomOnly, RamSize::NoBanks, rom_data).unwrap();
+        let mut cpu = CPU::new_without_boot_rom(cartridge, TraceMode::Mmio, false);
+        cpu.mmu.disable_boot_rom();
+
+        let buffer = SharedBuffer(Default::default());
+        cpu.set_trace_writer(Box::new(buffer.clone()));
+        for i in 0..3 {
+            cpu.tick(None, i);
+        }
+
+        let trace = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        assert_eq!(trace, "0x0102: write 0xFF47 = 0xE4\n0x0104: read 0xFF47 = 0xE4\n");
+    }
+
+    #[test]
+    fn test_flat_memory_program() {
+        // LD A, 0x42
+        let mut cpu = CPU::new_for_test(vec![0x3E, 0x42]);
+        let cycles = cpu.tick(None, 0);
+
+        assert_eq!(cycles, 2);
+        assert_eq!(cpu.registers(), Registers { pc: 0x0002, sp: 0xFFFE, a: 0x42, ..Default::default() });
+
+        // Memory is writable across the whole address space, ROM included
+        cpu.mmu.write(Address::new(0x0000), 0xAB);
+        assert_eq!(cpu.mmu.read(Address::new(0x0000)), 0xAB);
+    }
+
+    // Returns the cycles for the instruction and how many of them were memory accesses
+    fn run_program(program: Vec<u8>, hl: u16) -> (u8, u8) {
+        let mut cpu = CPU::new_for_test(program);
+        cpu.resolve_u16_reg(&RegisterU16::HL).set(hl);
+        let cycles = cpu.tick(None, 0);
+        (cycles, cpu.mmu.take_consumed_cycles())
+    }
+
+    // Cycles are in M-cycles, i.e. 4 T-cycles each
+    #[test]
+    fn test_cb_address_hl_cycles() {
+        // SET 0, (HL): 16 T-cycles, fetching CB and the opcode then reading and writing (HL)
+        assert_eq!(run_program(vec![0xCB, 0xC6], 0xC000), (4, 4));
+        // BIT 0, (HL): 12 T-cycles, (HL) is only read
+        assert_eq!(run_program(vec![0xCB, 0x46], 0xC000), (3, 3));
+        // SET 0, B: 8 T-cycles
+        assert_eq!(run_program(vec![0xCB, 0xC0], 0xC000), (2, 2));
+
+        for opcode in 0..=0xFF {
+            let expected = match (opcode & 0x07, opcode) {
+                (0x06, 0x40..=0x7F) => 3,
+                (0x06, _) => 4,
+                _ => 2,
+            };
+            assert_eq!(cycles::CB_OPCODE_CYCLES[opcode as usize], expected, "CB {:#04X}", opcode);
+            assert_eq!(run_program(vec![0xCB, opcode], 0xC000), (expected, expected), "CB {:#04X}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_opcode_histogram() {
+        // INC A; SWAP A; JR -5
+        let program = vec![0x3C, 0xCB, 0x37, 0x18, 0xFB];
+        let mut cpu = CPU::new_for_test(program.clone());
+        cpu.set_count_opcodes(true);
+        for _ in 0..9 {
+            cpu.tick(None, 0);
+        }
+
+        assert_eq!(cpu.opcode_histogram()[0x3C], 3);
+        assert_eq!(cpu.opcode_histogram()[0x18], 3);
+        // The prefix itself isn't counted, only the CB opcode
+        assert_eq!(cpu.opcode_histogram()[0xCB], 0);
+        assert_eq!(cpu.cb_opcode_histogram()[0x37], 3);
+        assert_eq!(cpu.opcode_histogram().iter().sum::<u64>(), 6);
+
+        // Nothing is counted unless enabled
+        let mut cpu = CPU::new_for_test(program);
+        cpu.tick(None, 0);
+        assert!(cpu.opcode_histogram().iter().all(|&count| count == 0));
+    }
+
+    #[test]
+    fn test_stack_push_pop() {
+        let mut cpu = CPU::new_for_test(vec![]);
+        cpu.sp = 0xC000;
+        cpu.stack_push(0x1234);
+        cpu.stack_push(0xBEEF);
+        assert_eq!(cpu.sp, 0xBFFC);
+        // Pushed little-endian, growing downwards
+        assert_eq!(cpu.mmu.read(Address::new(0xBFFC)), 0xEF);
+        assert_eq!(cpu.mmu.read(Address::new(0xBFFD)), 0xBE);
+        assert_eq!(cpu.stack_pop(), 0xBEEF);
+        assert_eq!(cpu.stack_pop(), 0x1234);
+        assert_eq!(cpu.sp, 0xC000);
+    }
+
+    #[test]
+    fn test_stack_instruction_cycles() {
+        // Internal M-cycles are consumed like memory accesses, so nothing is left over
+        // PUSH BC, POP BC, RST 0x38
+        assert_eq!(run_program(vec![0xC5], 0), (4, 4));
+        assert_eq!(run_program(vec![0xC1], 0), (3, 3));
+        assert_eq!(run_program(vec![0xFF], 0), (4, 4));
+        // CALL 0x1234, RET, RETI
+        assert_eq!(run_program(vec![0xCD, 0x34, 0x12], 0), (6, 6));
+        assert_eq!(run_program(vec![0xC9], 0), (4, 4));
+        assert_eq!(run_program(vec![0xD9], 0), (4, 4));
+
+        // RET NZ, taken and not taken
+        assert_eq!(run_program(vec![0xC0], 0), (5, 5));
+        let mut cpu = CPU::new_for_test(vec![0xC0]);
+        cpu.flag_register.value = 0b1000_0000;
+        assert_eq!(cpu.tick(None, 0), 2);
+        assert_eq!(cpu.mmu.take_consumed_cycles(), 2);
+    }
+
+    #[test]
+    fn test_call_ticks_timers() {
+        let mut rom_data = vec![0x00; 0x8000];
+        // CALL 0x1234
+        rom_data[0x0100..0x0103].copy_from_slice(&[0xCD, 0x34, 0x12]);
+        let cartridge = create_for_cartridge_type(CartridgeType::RomOnly, RamSize::NoBanks, rom_data).unwrap();
+        let mut cpu = CPU::new_without_boot_rom(cartridge, TraceMode::Off, false);
+        cpu.mmu.disable_boot_rom();
+        // Timer enabled, incrementing every 4 M-cycles
+        cpu.mmu.write(Address::new(0xFF07), 0b101);
+        cpu.mmu.write(Address::new(0xFF05), 0x00);
+        cpu.mmu.reset_divider();
+        cpu.mmu.take_consumed_cycles();
+
+        assert_eq!(cpu.tick(None, 0), 6);
+        assert_eq!(cpu.mmu.take_consumed_cycles(), 6);
+        assert_eq!(cpu.pc, 0x1234);
+        // Reading takes one more M-cycle, so 7 have passed
+        assert_eq!(cpu.mmu.read(Address::new(0xFF05)), 1);
+        // And the second increment is exactly at 8
+        cpu.mmu.maybe_tick_timers(1);
+        assert_eq!(cpu.mmu.read(Address::new(0xFF05)), 2);
+    }
+
+    // Returns the cycles for the instruction and the PC after it
+    fn run_with_flags(program: Vec<u8>, flags: u8) -> (u8, u16) {
+        let mut cpu = CPU::new_for_test(program);
+        cpu.flag_register.value = flags;
+        cpu.sp = 0xC000;
+        let cycles = cpu.tick(None, 0);
+        (cycles, cpu.pc)
+    }
+
+    #[test]
+    fn test_conditional_branch_cycles() {
+        let z = 0b1000_0000;
+        let c = 0b0001_0000;
+
+        // JR NZ, +5
+        assert_eq!(run_with_flags(vec![0x20, 0x05], 0), (3, 0x0007));
+        assert_eq!(run_with_flags(vec![0x20, 0x05], z), (2, 0x0002));
+        // CALL Z, 0x1234
+        assert_eq!(run_with_flags(vec![0xCC, 0x34, 0x12], z), (6, 0x1234));
+        assert_eq!(run_with_flags(vec![0xCC, 0x34, 0x12], 0), (3, 0x0003));
+        // RET C, the stack is zeroed so it returns to 0x0000
+        assert_eq!(run_with_flags(vec![0xD8], c), (5, 0x0000));
+        assert_eq!(run_with_flags(vec![0xD8], 0), (2, 0x0001));
+
+        // The unconditional variants always take the branch, and use the base table
+        // JR +5, CALL 0x1234, RET, JP 0x1234
+        assert_eq!(run_with_flags(vec![0x18, 0x05], 0), (3, 0x0007));
+        assert_eq!(run_with_flags(vec![0xCD, 0x34, 0x12], 0), (6, 0x1234));
+        assert_eq!(run_with_flags(vec![0xC9], 0), (4, 0x0000));
+        assert_eq!(run_with_flags(vec![0xC3, 0x34, 0x12], 0), (4, 0x1234));
+    }
+
+    #[test]
+    fn test_stop_resets_divider() {
+        let mut rom_data = vec![0x00; 0x8000];
+        // STOP
+        rom_data[0x0100..0x0102].copy_from_slice(&[0x10, 0x00]);
+        let cartridge = create_for_cartridge_type(CartridgeType::RomOnly, RamSize::NoBanks, rom_data).unwrap();
+        let mut cpu = CPU::new_without_boot_rom(cartridge, TraceMode::Off, false);
+        cpu.mmu.disable_boot_rom();
+
+        let div = Address::new(0xFF04);
+        let tima = Address::new(0xFF05);
+        // Timer enabled, incrementing every 256 M-cycles
+        cpu.mmu.write(Address::new(0xFF07), 0b100);
+        let tick_timers = |cpu: &mut CPU, m_cycles: usize| {
+            for _ in 0..m_cycles {
+                cpu.mmu.maybe_tick_timers(1);
+            }
+        };
+
+        tick_timers(&mut cpu, 400);
+        assert_eq!(cpu.mmu.read(div), 6);
+        assert_eq!(cpu.mmu.read(tima), 1);
+
+        cpu.tick(None, 0);
+        assert_eq!(cpu.mmu.read(div), 0);
+
+        // Without the reset, the 144 M-cycles left over would have incremented TIMA by now
+        tick_timers(&mut cpu, 200);
+        assert_eq!(cpu.mmu.read(tima), 1);
+        tick_timers(&mut cpu, 56);
+        assert_eq!(cpu.mmu.read(tima), 2);
+    }
+
+    #[test]
+    fn test_halt_exit_cycles() {
+        let mut cpu = new_test_cpu();
+        cpu.mmu.disable_boot_rom();
+        cpu.interrupts_enabled = true;
+        cpu.halted = true;
+        cpu.mmu.write(Address::new(0xFFFF), 0b0000_0100);
+        // Timer enabled, incrementing every 4 M-cycles
+        cpu.mmu.write(Address::new(0xFF07), 0b101);
+        cpu.mmu.write(Address::new(0xFF05), 0xFF);
+        cpu.mmu.take_consumed_cycles();
+
+        // Nothing pending yet, so the CPU idles
+        assert_eq!(cpu.tick(None, 0), 1);
+        assert!(cpu.halted);
+
+        while !cpu.mmu.has_interrupt_flag(InterruptSource::Timer) {
+            cpu.mmu.maybe_tick_timers(1);
+        }
+
+        // 1 M-cycle to exit HALT, 5 to dispatch, then the NOP at the handler
+        let pc = cpu.pc;
+        assert_eq!(cpu.tick(None, 1), 1 + 5 + 1);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, interrupt_vector(InterruptSource::Timer) as u16 + 1);
+        assert_eq!(cpu.mmu.read_word(Address::new(cpu.sp)).value, pc);
+
+        // Without HALT, there's only the dispatch
+        cpu.interrupts_enabled = true;
+        cpu.mmu.set_interrupt_flag(InterruptSource::Timer, true);
+        assert_eq!(cpu.tick(None, 2), 5 + 1);
+    }
+
+    #[test]
+    fn test_halt_wakes_to_handler_and_returns() {
+        let mut rom_data = vec![0x00; 0x8000];
+        // VBlank handler: RETI
+        rom_data[0x0040] = 0xD9;
+        // EI, HALT, LD A, 0x42, JR -2
+        rom_data[0x0100..0x0106].copy_from_slice(&[0xFB, 0x76, 0x3E, 0x42, 0x18, 0xFE]);
+        let cartridge = create_for_cartridge_type(CartridgeType::RomOnly, RamSize::NoBanks, rom_data).unwrap();
+        let mut cpu = CPU::new_without_boot_rom(cartridge, TraceMode::Off, false);
+        cpu.mmu.disable_boot_rom();
+        cpu.mmu.write(Address::new(0xFFFF), 0b0000_0001);
+
+        assert_eq!(cpu.tick(None, 0), 1);
+        assert_eq!(cpu.tick(None, 1), 1);
+        assert!(cpu.halted);
+        assert_eq!(cpu.pc, 0x0102);
+
+        // Stays halted until the interrupt is pending
+        assert_eq!(cpu.tick(None, 2), 1);
+        assert!(cpu.halted);
+        cpu.mmu.set_interrupt_flag(InterruptSource::VBlank, true);
+
+        // 1 M-cycle to exit HALT, 5 to dispatch, then the RETI at the handler
+        assert_eq!(cpu.tick(None, 3), 1 + 5 + 4);
+        assert!(!cpu.halted);
+        assert!(!cpu.mmu.has_interrupt_flag(InterruptSource::VBlank));
+        assert!(cpu.interrupts_enabled);
+        assert_eq!(cpu.mmu.read_word(Address::new(0xFFFC)).value, 0x0102);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.pc, 0x0102);
+
+        // Execution continues right after HALT
+        assert_eq!(cpu.tick(None, 4), 2);
+        assert_eq!(cpu.registers().a, 0x42);
+        assert_eq!(cpu.pc, 0x0104);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch_ticks_timers() {
+        let mut cpu = new_test_cpu();
+        cpu.mmu.disable_boot_rom();
+        // Timer enabled, incrementing every 4 M-cycles
+        cpu.mmu.write(Address::new(0xFF07), 0b101);
+        cpu.mmu.write(Address::new(0xFF05), 0x00);
+        cpu.mmu.reset_divider();
+        cpu.mmu.take_consumed_cycles();
+
+        assert_eq!(cpu.handle_interrupt(InterruptSource::Timer), 5);
+        // All of the dispatch is consumed as it happens, so nothing is left to tick afterwards
+        assert_eq!(cpu.mmu.take_consumed_cycles(), 5);
+        // Reading takes one more M-cycle, so 6 have passed
+        assert_eq!(cpu.mmu.read(Address::new(0xFF05)), 1);
+        // And the second increment is exactly at 8
+        cpu.mmu.maybe_tick_timers(1);
+        assert_eq!(cpu.mmu.read(Address::new(0xFF05)), 2);
+    }
+
+    #[test]
+    fn test_stack_push_wraps() {
+        let mut cpu = new_test_cpu();
+
+        cpu.sp = 0x0000;
+        cpu.stack_push(0x1234);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.mmu.read(Address::new(0xFFFE)), 0x34);
+        assert_eq!(cpu.mmu.read(Address::new(0xFFFF)), 0x12);
+        assert_eq!(cpu.stack_pop(), 0x1234);
+        assert_eq!(cpu.sp, 0x0000);
+
+        // Interrupt dispatch pushes the PC the same way
+        cpu.sp = 0x0000;
+        cpu.handle_interrupt(InterruptSource::Timer);
+        assert_eq!(cpu.sp, 0xFFFE);
+        assert_eq!(cpu.pc, 0x0050);
+    }
+
     #[test]
     fn test_get_bit() {
         assert_eq!(get_bit(0b1011_0010, 0), false);
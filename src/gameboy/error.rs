@@ -0,0 +1,41 @@
+use std::error::Error;
+use std::fmt;
+
+use super::header::CartridgeType;
+
+#[derive(Debug, PartialEq)]
+pub enum EmulatorError {
+    // The cartridge header is missing or holds invalid values
+    InvalidHeader(String),
+    UnsupportedCartridge(CartridgeType),
+    CgbRequired,
+    // Holds the size of the ROM, which is too small to contain a header
+    RomTooSmall(usize),
+    // Creating the window or renderer failed
+    Platform(String),
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::InvalidHeader(message) => write!(f, "{}", message),
+            EmulatorError::UnsupportedCartridge(cartridge_type) => {
+                write!(f, "Unsupported cartridge: {:?}", cartridge_type)
+            }
+            EmulatorError::CgbRequired => write!(f, "The cartridge requires CGB functionality"),
+            EmulatorError::RomTooSmall(size) => {
+                write!(f, "Too little ROM data to read header. Need 0x0150, got {}", size)
+            }
+            EmulatorError::Platform(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl Error for EmulatorError {}
+
+// Lets the frontend keep reporting errors as plain strings
+impl From<EmulatorError> for String {
+    fn from(error: EmulatorError) -> Self {
+        error.to_string()
+    }
+}
@@ -1,61 +1,142 @@
-use crate::common::framebuffer::FrameBuffer;
-use crate::common::joypad_events::JoypadEvent;
+use std::io::Write;
+use std::ops::RangeInclusive;
+use std::time::Duration;
 
+use crate::common::clock::{Clock, FramePacer, SystemClock};
+use crate::common::framebuffer::{FrameBuffer, RgbColor};
+use crate::common::joypad_events::{JoypadButton, JoypadEvent};
+
+use super::address::Address;
 use super::cartridge::create_for_cartridge_type;
-use super::cpu::CPU;
-use super::cpu::TraceMode;
-use super::header::{Header, FlagCGB};
-use super::mmu::InterruptSource;
+use super::cpu::{Registers, CPU};
+use super::cpu::{TraceMode, UnknownOpcodePolicy};
+use super::error::EmulatorError;
+use super::header::{CartridgeType, Header, FlagCGB, FlagSGB};
+use super::mmu::{RamInit, SocdPolicy};
 use super::reference::ReferenceMetadata;
-use super::video::VideoInterrupt;
+use super::rewind::RewindBuffer;
+use super::video::ScanlineHook;
+
+const MIN_SPEED_MULTIPLIER: f32 = 0.25;
+const MAX_SPEED_MULTIPLIER: f32 = 8.0;
+// The DMG refreshes the screen at ~59.73 Hz
+const FRAME_RATE: f64 = 59.7275;
+
+// A snapshot of the whole machine, see CPU::save_state
+#[derive(Clone)]
+pub struct SaveState {
+    cpu: CPU,
+    cycle_count: u64,
+    // Where we are in the reference metadata, which follows the executed instructions
+    index: usize,
+}
 
 pub struct Gameboy {
     cpu: CPU,
+    // Only affects the presentation pacing, the emulation itself is unaffected.
+    speed_multiplier: f32,
+    clock: Box<dyn Clock>,
+    frame_pacer: FramePacer,
+    title: String,
+    // Only captured when enabled by the frontend
+    rewind_buffer: Option<RewindBuffer<SaveState>>,
 
     // Internal / debug
     index: usize,
+    cycle_count: u64,
     maybe_reference_metadata: Option<Vec<ReferenceMetadata>>,
 }
 
+// How to set up the emulator, besides the ROM itself
+pub struct GameboyOptions {
+    pub trace_mode: TraceMode,
+    pub skip_boot_rom: bool,
+    pub ram_init: RamInit,
+    // Used instead of the type in the header, for ROMs declaring the wrong one
+    pub force_cartridge_type: Option<CartridgeType>,
+    // Run ROMs with CGB enhancements as a DMG, without any of the CGB registers
+    pub force_dmg: bool,
+}
+
+impl Default for GameboyOptions {
+    fn default() -> Self {
+        Self {
+            trace_mode: TraceMode::Off,
+            skip_boot_rom: false,
+            ram_init: RamInit::Zero,
+            force_cartridge_type: None,
+            force_dmg: false,
+        }
+    }
+}
+
 impl Gameboy {
+    // The ROM is given in memory, so the core never touches the filesystem itself.
+    // Only the optional reference metadata is loaded from a file, by the frontend.
     pub fn new(
         rom_data: Vec<u8>,
         reference_metadata: Option<Vec<ReferenceMetadata>>,
-        trace_mode: TraceMode,
-        skip_boot_rom: bool,
-    ) -> Self {
-        let header = Header::read_from_rom(&rom_data).unwrap();
+        options: GameboyOptions,
+    ) -> Result<Self, EmulatorError> {
+        let GameboyOptions { trace_mode, skip_boot_rom, ram_init, force_cartridge_type, force_dmg } = options;
+        let header = Header::read_from_rom(&rom_data)?;
         println!("{:#?}", header);
 
-        if !matches!(header.cgb_flag, FlagCGB::WorksWithOld) {
-            panic!("Only DMG ROMs support for now");
+        if matches!(header.cgb_flag, FlagCGB::RequiresNew) {
+            return Err(EmulatorError::CgbRequired);
         }
 
         match header.sgb_flag {
-            crate::gameboy::header::FlagSGB::NoSGB => (),
-            crate::gameboy::header::FlagSGB::SGB => panic!("SGB features are currently not supported"),
+            FlagSGB::NoSGB => (),
+            // SGB command packets sent through the joypad register are simply ignored
+            FlagSGB::SGB => println!("SGB features are currently not supported, running as DMG"),
+        }
+
+        for warning in header.ram_size_warnings() {
+            println!("Warning: {}", warning);
         }
 
-        let cartridge = match create_for_cartridge_type(header.cartridge_type, rom_data) {
-            Some(cartridge) => cartridge,
-            None => todo!(
-                "Cartridge not implemented for type: {:?}",
-                header.cartridge_type
-            ),
+        // ROMs with CGB enhancements get the CGB registers, like KEY1 and the RAM banks. The
+        // state after boot is still the one of a DMG, so they take their DMG code path.
+        let is_cgb = !force_dmg && matches!(header.cgb_flag, FlagCGB::SupportsNew);
+
+        let cartridge_type = match force_cartridge_type {
+            Some(cartridge_type) => {
+                println!("Forcing cartridge type {:?}, the header declares {:?}", cartridge_type, header.cartridge_type);
+                cartridge_type
+            }
+            None => header.cartridge_type,
         };
+        let cartridge = create_for_cartridge_type(cartridge_type, header.ram_size, rom_data)?;
 
-        Self {
-            cpu: if skip_boot_rom {
-                let mut tmp = CPU::new_without_boot_rom(cartridge, trace_mode);
-                tmp.mmu().disable_boot_rom();
-                tmp
-            } else {
-                CPU::new(cartridge, trace_mode)
-            },
+        let mut cpu = if skip_boot_rom {
+            let mut tmp = CPU::new_without_boot_rom(cartridge, trace_mode, is_cgb);
+            tmp.mmu_mut().disable_boot_rom();
+            tmp.mmu_mut().init_post_boot_registers();
+            tmp
+        } else {
+            CPU::new(cartridge, trace_mode, is_cgb)
+        };
+        cpu.mmu_mut().init_ram(ram_init);
+
+        Ok(Self {
+            cpu,
 
+            speed_multiplier: 1.0,
+            clock: Box::new(SystemClock::new()),
+            frame_pacer: FramePacer::new(Duration::ZERO),
+            title: header.title,
+            rewind_buffer: None,
             index: 0,
+            cycle_count: 0,
             maybe_reference_metadata: reference_metadata,
-        }
+        })
+    }
+
+    // For frontends which only borrow the ROM data
+    #[allow(dead_code)]
+    pub fn from_bytes(rom_data: &[u8], trace_mode: TraceMode, skip_boot_rom: bool) -> Result<Self, EmulatorError> {
+        Gameboy::new(rom_data.to_vec(), None, GameboyOptions { trace_mode, skip_boot_rom, ..Default::default() })
     }
 
     pub fn tick(&mut self) -> Option<&FrameBuffer> {
@@ -69,26 +150,537 @@ impl Gameboy {
         };
 
         let cycles = self.cpu.tick(current_metadata, self.index);
-        for _ in 0..cycles {
-            // TODO: Should we tick cycles * 4 here?
-            let video_interrupts = self.cpu.mmu().video().tick();
-            for interrupt in video_interrupts {
-                let interrupt_flag = match interrupt {
-                    VideoInterrupt::Stat => InterruptSource::Lcd,
-                    VideoInterrupt::VBlank => InterruptSource::VBlank,
-                };
-                self.cpu.mmu().set_interrupt_flag(interrupt_flag, true);
+        let consumed_memory_cycles = self.cpu.mmu_mut().take_consumed_cycles();
+        let remaining_cycles = cycles - consumed_memory_cycles;
+        // In lockstep the PPU already advanced with each memory access, like the timers
+        let video_cycles = if self.cpu.mmu().lockstep_video() {
+            remaining_cycles
+        } else {
+            cycles
+        };
+        self.cpu.mmu_mut().tick_video(video_cycles);
+        self.cpu.mmu_mut().maybe_tick_timers(remaining_cycles);
+
+        self.index += 1;
+        self.cycle_count += cycles as u64;
+
+        self.cpu.mmu_mut().video().try_take_frame()?;
+        self.maybe_capture_rewind_state();
+        Some(self.cpu.mmu_mut().video().frame_buffer())
+    }
+
+    // Steps until the next frame is ready, for when step-level control isn't needed.
+    pub fn run_until_vblank(&mut self) -> &FrameBuffer {
+        while self.tick().is_none() {}
+        self.cpu.mmu_mut().video().frame_buffer()
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            cpu: self.cpu.save_state(),
+            cycle_count: self.cycle_count,
+            index: self.index,
+        }
+    }
+
+    pub fn load_state(&mut self, state: &SaveState) {
+        self.cpu.load_state(&state.cpu);
+        self.cycle_count = state.cycle_count;
+        self.index = state.index;
+    }
+
+    // Captures a snapshot every `interval_frames` frames, keeping the last `capacity` of them.
+    // A snapshot is a few hundred KB (mostly WRAM, VRAM and the frame buffers), the ROM
+    // itself is shared between them.
+    pub fn enable_rewind(&mut self, interval_frames: usize, capacity: usize) {
+        self.rewind_buffer = Some(RewindBuffer::new(interval_frames, capacity));
+    }
+
+    // Restores the most recent snapshot, going further back on each call.
+    // Returns false once there's nothing left to rewind.
+    pub fn rewind_step(&mut self) -> bool {
+        match self.rewind_buffer.as_mut().and_then(|buffer| buffer.pop()) {
+            Some(state) => {
+                self.load_state(&state);
+                true
             }
+            None => false,
         }
-        let consumed_memory_cycles = self.cpu.mmu().take_consumed_cycles();
-        self.cpu.mmu().maybe_tick_timers(cycles - consumed_memory_cycles);
+    }
 
-        self.index += 1;
+    fn maybe_capture_rewind_state(&mut self) {
+        if !self.rewind_buffer.as_mut().is_some_and(|buffer| buffer.on_frame()) {
+            return;
+        }
+        let state = self.save_state();
+        if let Some(buffer) = self.rewind_buffer.as_mut() {
+            buffer.push(state);
+        }
+    }
+
+    pub fn speed_multiplier(&self) -> f32 {
+        self.speed_multiplier
+    }
+
+    pub fn set_speed_multiplier(&mut self, speed_multiplier: f32) {
+        self.speed_multiplier = speed_multiplier.clamp(MIN_SPEED_MULTIPLIER, MAX_SPEED_MULTIPLIER);
+    }
+
+    pub fn set_trace_writer(&mut self, writer: Box<dyn Write>) {
+        self.cpu.set_trace_writer(writer);
+    }
+
+    // Used for anything depending on time, replace for reproducible runs
+    #[allow(dead_code)]
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.frame_pacer = FramePacer::new(clock.now());
+        self.clock = clock;
+    }
+
+    // How long to wait before presenting the frame, to run at the current speed
+    pub fn time_until_next_frame(&mut self) -> Duration {
+        let frame_interval = Duration::from_secs_f64(1.0 / (FRAME_RATE * self.speed_multiplier as f64));
+        self.frame_pacer.on_frame(self.clock.now(), frame_interval)
+    }
+
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    pub fn frame_buffer(&mut self) -> &FrameBuffer {
+        self.cpu.mmu_mut().video().frame_buffer()
+    }
+
+    pub fn debug_pc(&self) -> u16 {
+        self.cpu.pc()
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.registers()
+    }
+
+    pub fn debug_ppu_mode(&mut self) -> u8 {
+        self.cpu.mmu_mut().video().debug_mode()
+    }
+
+    // Overrides the colors of the four shades, from white to black. The game still
+    // picks which shade each color ID uses through its palettes.
+    pub fn set_bg_palette_colors(&mut self, colors: [RgbColor; 4]) {
+        self.cpu.mmu_mut().video().set_bg_screen_colors(colors);
+    }
+
+    pub fn set_obj_palette_colors(&mut self, colors: [RgbColor; 4]) {
+        self.cpu.mmu_mut().video().set_obj_screen_colors(colors);
+    }
+
+    // Only draws every (frame_skip + 1)th frame, for slow hosts. Frames are still completed at
+    // the same rate, the skipped ones just keep showing the last frame drawn.
+    pub fn set_frame_skip(&mut self, frame_skip: usize) {
+        self.cpu.mmu_mut().video().set_frame_skip(frame_skip);
+    }
+
+    // For capturing mid-frame effects line by line
+    #[allow(dead_code)]
+    pub fn set_scanline_hook(&mut self, hook: Option<ScanlineHook>) {
+        self.cpu.mmu_mut().video().set_scanline_hook(hook);
+    }
+
+    pub fn debug_ly(&mut self) -> u8 {
+        self.cpu.mmu_mut().video().debug_ly()
+    }
+
+    // Logs CPU writes to the given address range, for reverse engineering
+    pub fn set_write_watch(&mut self, range: RangeInclusive<u16>) {
+        self.cpu.mmu_mut().set_write_watch(Some(range));
+    }
+
+    // Returns (pc, address, value) for each watched write since the last call
+    pub fn take_write_log(&mut self) -> Vec<(u16, u16, u8)> {
+        self.cpu.mmu_mut().take_write_log()
+    }
+
+    pub fn export_cartridge_ram(&self) -> Option<Vec<u8>> {
+        self.cpu.mmu().cartridge().export_ram()
+    }
+
+    pub fn import_cartridge_ram(&mut self, data: &[u8]) -> Result<(), String> {
+        self.cpu.mmu_mut().cartridge_mut().import_ram(data)
+    }
+
+    // Advances the PPU on each memory access within an instruction, rather than once it's
+    // done. Slower, but mid-instruction reads see the PPU state of that exact cycle.
+    pub fn set_lockstep_video(&mut self, enabled: bool) {
+        self.cpu.mmu_mut().set_lockstep_video(enabled);
+    }
+
+    // Counts how many times each opcode executes, for profiling and coverage
+    pub fn set_count_opcodes(&mut self, enabled: bool) {
+        self.cpu.set_count_opcodes(enabled);
+    }
+
+    pub fn opcode_histogram(&self) -> &[u64; 256] {
+        self.cpu.opcode_histogram()
+    }
+
+    pub fn cb_opcode_histogram(&self) -> &[u64; 256] {
+        self.cpu.cb_opcode_histogram()
+    }
+
+    // Reads start..=end without any side effects. Banked regions (cartridge ROM and RAM,
+    // and WRAM/VRAM in CGB mode) show whichever bank is currently mapped.
+    pub fn dump_region(&self, start: u16, end: u16) -> Vec<u8> {
+        (start..=end).map(|address| self.peek(address)).collect()
+    }
+
+    // A single byte of dump_region
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.mmu().peek(Address::new(address))
+    }
+
+    // What to do when running into an undefined opcode
+    pub fn set_unknown_opcode_policy(&mut self, policy: UnknownOpcodePolicy) {
+        self.cpu.set_unknown_opcode_policy(policy);
+    }
+
+    // Reports the first ignored write to ROM, for cartridges without an MBC
+    pub fn set_log_rom_writes(&mut self, enabled: bool) {
+        self.cpu.mmu_mut().cartridge_mut().set_log_rom_writes(enabled);
+    }
+
+    // The title from the cartridge header
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    pub fn handle_joypad(&mut self, event: JoypadEvent) {
+        self.cpu.mmu_mut().joypad_mut().consume_platform_event(event);
+    }
+
+    pub fn set_socd_policy(&mut self, policy: SocdPolicy) {
+        self.cpu.mmu_mut().joypad_mut().set_socd_policy(policy);
+    }
+
+    // For input display overlays
+    pub fn pressed_buttons(&self) -> Vec<JoypadButton> {
+        self.cpu.mmu().joypad().pressed_buttons()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::common::clock::FixedClock;
+
+    // A ROM only cartridge which loops forever at the entry point
+    fn looping_rom() -> Vec<u8> {
+        let mut rom_data = vec![0x00; 0x8000];
+        // JR -2
+        rom_data[0x0100] = 0x18;
+        rom_data[0x0101] = 0xFE;
+        rom_data
+    }
+
+    #[test]
+    fn test_boots_with_sgb_flag() {
+        let mut rom_data = looping_rom();
+        rom_data[0x0146] = 0x03;
+
+        let mut gameboy = Gameboy::new(rom_data, None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+        gameboy.run_until_vblank();
+        gameboy.run_until_vblank();
+    }
+
+    #[test]
+    fn test_unsupported_cartridge() {
+        let mut rom_data = looping_rom();
+        rom_data[0x0147] = 0x11;
+
+        let result = Gameboy::new(rom_data, None, GameboyOptions { skip_boot_rom: true, ..Default::default() });
+        assert!(matches!(result, Err(EmulatorError::UnsupportedCartridge(CartridgeType::MBC3))));
+    }
+
+    #[test]
+    fn test_cgb_only_rom_is_rejected() {
+        let mut rom_data = looping_rom();
+        rom_data[0x0143] = 0xC0;
+
+        let result = Gameboy::new(rom_data, None, GameboyOptions { skip_boot_rom: true, ..Default::default() });
+        assert!(matches!(result, Err(EmulatorError::CgbRequired)));
+    }
+
+    #[test]
+    fn test_force_cartridge_type() {
+        // A header without an MBC, on a ROM with a distinct value at the start of each bank
+        let mut rom_data = vec![0x00; 0x10000];
+        for bank in 0..4 {
+            rom_data[0x4000 * bank] = bank as u8;
+        }
+        // LD A, 2; LD (0x2000), A; JR -2
+        rom_data[0x0100..0x0107].copy_from_slice(&[0x3E, 0x02, 0xEA, 0x00, 0x20, 0x18, 0xFE]);
+
+        let mut gameboy = Gameboy::new(rom_data.clone(), None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+        for _ in 0..3 {
+            gameboy.tick();
+        }
+        assert_eq!(gameboy.peek(0x4000), 1);
+
+        // MBC1 switches to the bank written
+        let mut gameboy = Gameboy::new(rom_data, None, GameboyOptions { skip_boot_rom: true, force_cartridge_type: Some(CartridgeType::MBC1), ..Default::default() }).unwrap();
+        for _ in 0..3 {
+            gameboy.tick();
+        }
+        assert_eq!(gameboy.peek(0x4000), 2);
+
+        let result = Gameboy::new(looping_rom(), None, GameboyOptions { skip_boot_rom: true, force_cartridge_type: Some(CartridgeType::MBC5), ..Default::default() });
+        assert!(matches!(result, Err(EmulatorError::UnsupportedCartridge(CartridgeType::MBC5))));
+    }
+
+    #[test]
+    fn test_force_dmg() {
+        // Has CGB enhancements, but works on DMG
+        let mut rom_data = looping_rom();
+        rom_data[0x0143] = 0x80;
+
+        let gameboy = Gameboy::new(rom_data.clone(), None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+        // KEY1, in normal speed without a switch prepared
+        assert_eq!(gameboy.peek(0xFF4D), 0x7E);
+
+        let gameboy = Gameboy::new(rom_data, None, GameboyOptions { skip_boot_rom: true, force_dmg: true, ..Default::default() }).unwrap();
+        // KEY1 doesn't exist on DMG
+        assert_eq!(gameboy.peek(0xFF4D), 0xFF);
+    }
+
+    #[test]
+    fn test_skip_boot_rom_sets_io_registers() {
+        let gameboy = Gameboy::new(looping_rom(), None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+        assert_eq!(gameboy.peek(0xFF40), 0x91);
+        assert_eq!(gameboy.peek(0xFF47), 0xFC);
+        assert_eq!(gameboy.peek(0xFF04), 0xAB);
+        assert_eq!(gameboy.peek(0xFF24), 0x77);
+        assert_eq!(gameboy.peek(0xFF25), 0xF3);
+        assert_eq!(gameboy.peek(0xFF0F) & 0x1F, 0x01);
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        let rom_data = looping_rom();
+        let mut gameboy = Gameboy::from_bytes(&rom_data, TraceMode::Off, true).unwrap();
+        gameboy.run_until_vblank();
+        assert_eq!(gameboy.debug_pc(), 0x0100);
+    }
+
+    #[test]
+    fn test_run_until_vblank() {
+        let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+
+        let frame = gameboy.run_until_vblank();
+        assert_eq!(frame.width, 160);
+
+        // A frame is 154 lines of 456 dots
+        let elapsed_cycles = gameboy.cycle_count();
+        assert!(elapsed_cycles.abs_diff(70224) < 10, "elapsed cycles: {}", elapsed_cycles);
+    }
+
+    fn hash_frame(frame: &FrameBuffer) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        for y in 0..frame.height {
+            for x in 0..frame.width {
+                frame.get_pixel(x, y).hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    // Runs through the boot ROM with each frame taking 5 ms on a FixedClock, returning the
+    // cycle count, and the hash and pacing delay of each frame
+    fn run_with_fixed_clock(frame_count: usize) -> (u64, Vec<(u64, Duration)>) {
+        let clock = FixedClock::new(Duration::ZERO);
+        let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions::default()).unwrap();
+        gameboy.set_clock(Box::new(clock.clone()));
+
+        let frames = (0..frame_count)
+            .map(|_| {
+                let hash = hash_frame(gameboy.run_until_vblank());
+                clock.advance(Duration::from_millis(5));
+                let delay = gameboy.time_until_next_frame();
+                clock.advance(delay);
+                (hash, delay)
+            })
+            .collect();
+        (gameboy.cycle_count(), frames)
+    }
+
+    #[test]
+    fn test_runs_are_deterministic() {
+        let (cycle_count, frames) = run_with_fixed_clock(10);
+        assert_eq!((cycle_count, frames.clone()), run_with_fixed_clock(10));
+
+        // The rest of each frame interval is waited out
+        let frame_interval = Duration::from_secs_f64(1.0 / FRAME_RATE);
+        assert!(frames.iter().all(|&(_, delay)| delay == frame_interval - Duration::from_millis(5)));
+    }
+
+    #[test]
+    fn test_frame_skip() {
+        // Cycle count, PC and frame hash after each frame, and the number of scanlines drawn
+        let run_frames = |frame_skip: usize| -> (Vec<(u64, u16, u64)>, usize) {
+            let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions::default()).unwrap();
+            gameboy.set_frame_skip(frame_skip);
+            let drawn_lines = Rc::new(Cell::new(0));
+            let hook_lines = drawn_lines.clone();
+            gameboy.set_scanline_hook(Some(Box::new(move |_, _| hook_lines.set(hook_lines.get() + 1))));
+
+            let frames = (0..12)
+                .map(|_| {
+                    let hash = hash_frame(gameboy.run_until_vblank());
+                    (gameboy.cycle_count(), gameboy.debug_pc(), hash)
+                })
+                .collect();
+            (frames, drawn_lines.get())
+        };
+        let (frames, drawn_lines) = run_frames(0);
+        let (skipped_frames, skipped_drawn_lines) = run_frames(2);
+
+        // Every third frame is drawn, starting with the first
+        for (index, (frame, skipped_frame)) in frames.iter().zip(&skipped_frames).enumerate() {
+            assert_eq!((frame.0, frame.1), (skipped_frame.0, skipped_frame.1), "frame {}", index);
+            let drawn_index = index - index % 3;
+            assert_eq!(skipped_frame.2, frames[drawn_index].2, "frame {}", index);
+        }
+        assert!(skipped_drawn_lines < drawn_lines / 2, "{} of {} lines drawn", skipped_drawn_lines, drawn_lines);
+    }
+
+    #[test]
+    fn test_lockstep_video_matches_batched() {
+        // The boot ROM waits for VBlank by polling LY, so its reads land on different
+        // dots in the two modes, but each frame should still come out the same.
+        let run_frames = |lockstep_video: bool| -> Vec<u64> {
+            let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions::default()).unwrap();
+            gameboy.set_lockstep_video(lockstep_video);
+            (0..10).map(|_| hash_frame(gameboy.run_until_vblank())).collect()
+        };
+        assert_eq!(run_frames(true), run_frames(false));
+    }
+
+    #[test]
+    fn test_rewind() {
+        let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions::default()).unwrap();
+        gameboy.enable_rewind(2, 3);
+
+        // Snapshots are captured on the even frames, with the boot logo scrolling
+        let mut cycle_counts = vec![];
+        let frame_hashes: Vec<u64> = (0..10)
+            .map(|_| {
+                let frame_hash = hash_frame(gameboy.run_until_vblank());
+                cycle_counts.push(gameboy.cycle_count());
+                frame_hash
+            })
+            .collect();
+
+        // The snapshot of the frame being shown is skipped
+        assert!(gameboy.rewind_step());
+        assert_eq!(hash_frame(gameboy.frame_buffer()), frame_hashes[7]);
+        assert_eq!(gameboy.cycle_count(), cycle_counts[7]);
+        // Only the last three snapshots were kept
+        assert!(gameboy.rewind_step());
+        assert_eq!(hash_frame(gameboy.frame_buffer()), frame_hashes[5]);
+        assert!(!gameboy.rewind_step());
+
+        // Running again from the restored state ends up at the same frames
+        assert_eq!(hash_frame(gameboy.run_until_vblank()), frame_hashes[6]);
+        assert_eq!(hash_frame(gameboy.run_until_vblank()), frame_hashes[7]);
+        assert_eq!(gameboy.cycle_count(), cycle_counts[7]);
+
+        // One snapshot was captured since, a frame ago
+        gameboy.run_until_vblank();
+        assert!(gameboy.rewind_step());
+        assert_eq!(hash_frame(gameboy.frame_buffer()), frame_hashes[7]);
+    }
+
+    #[test]
+    fn test_write_watch() {
+        let mut rom_data = looping_rom();
+        // LD A, 0x42
+        rom_data[0x0100..0x0102].copy_from_slice(&[0x3E, 0x42]);
+        // LD (0xC010), A
+        rom_data[0x0102..0x0105].copy_from_slice(&[0xEA, 0x10, 0xC0]);
+        // LD (0xD000), A
+        rom_data[0x0105..0x0108].copy_from_slice(&[0xEA, 0x00, 0xD0]);
+        // JR -2
+        rom_data[0x0108..0x010A].copy_from_slice(&[0x18, 0xFE]);
+
+        let mut gameboy = Gameboy::new(rom_data, None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+        gameboy.set_write_watch(0xC000..=0xCFFF);
+        let state = gameboy.save_state();
+        for _ in 0..10 {
+            gameboy.tick();
+        }
 
-        return self.cpu.mmu().video().try_take_frame();
+        // The log isn't part of the save state, so loading one keeps it
+        gameboy.load_state(&state);
+        assert_eq!(gameboy.take_write_log(), vec![(0x0102, 0xC010, 0x42)]);
+        assert!(gameboy.take_write_log().is_empty());
     }
 
-    pub fn take_joypad_event(&mut self, event: JoypadEvent) {
-        self.cpu.mmu().joypad().consume_platform_event(event);
+    #[test]
+    fn test_load_state_restores_reference_position() {
+        let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions::default()).unwrap();
+        let state = gameboy.save_state();
+        for _ in 0..10 {
+            gameboy.tick();
+        }
+        assert_eq!(gameboy.index, 10);
+
+        gameboy.load_state(&state);
+        assert_eq!(gameboy.index, 0);
+    }
+
+    #[test]
+    fn test_ram_init() {
+        let gameboy = Gameboy::new(looping_rom(), None, GameboyOptions { skip_boot_rom: true, ram_init: RamInit::Ones, ..Default::default() }).unwrap();
+        assert_eq!(gameboy.dump_region(0xC000, 0xC00F), vec![0xFF; 0x10]);
+        assert_eq!(gameboy.dump_region(0xDFF0, 0xDFFF), vec![0xFF; 0x10]);
+    }
+
+    #[test]
+    fn test_dump_region() {
+        let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+        for i in 0..0x10 {
+            gameboy.cpu.mmu_mut().write(Address::new(0xC000 + i), 0xA0 | i as u8);
+        }
+        gameboy.cpu.mmu_mut().take_consumed_cycles();
+
+        let expected: Vec<u8> = (0..0x10).map(|i| 0xA0 | i).collect();
+        assert_eq!(gameboy.dump_region(0xC000, 0xC00F), expected);
+        // Echo RAM mirrors WRAM
+        assert_eq!(gameboy.dump_region(0xE000, 0xE00F), expected);
+        assert_eq!(gameboy.cpu.mmu_mut().take_consumed_cycles(), 0);
+    }
+
+    #[test]
+    fn test_joypad_event_reaches_register() {
+        let mut gameboy = Gameboy::new(looping_rom(), None, GameboyOptions { skip_boot_rom: true, ..Default::default() }).unwrap();
+        let joypad_register = Address::new(0xFF00);
+
+        // Select the action buttons
+        gameboy.cpu.mmu_mut().write(joypad_register, 0b0001_0000);
+        assert_eq!(gameboy.cpu.mmu_mut().read(joypad_register) & 0x0F, 0x0F);
+
+        // Start is bit 3, and pressed buttons read as 0
+        gameboy.handle_joypad(JoypadEvent::new_down(JoypadButton::Start));
+        assert_eq!(gameboy.cpu.mmu_mut().read(joypad_register) & 0x0F, 0b0111);
+
+        gameboy.handle_joypad(JoypadEvent::new_up(JoypadButton::Start));
+        assert_eq!(gameboy.cpu.mmu_mut().read(joypad_register) & 0x0F, 0x0F);
     }
 }
@@ -1,8 +1,48 @@
+use std::fmt;
 use std::str;
 
+use clap::ValueEnum;
+
+use super::error::EmulatorError;
+
+// https://gbdev.io/pandocs/The_Cartridge_Header.html#0104-0133--nintendo-logo
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+const LOGO_OFFSET: usize = 0x0104;
+
+// Some dumps made with copier devices are prefixed by a 512 byte header
+const COPIER_HEADER_SIZE: usize = 512;
+
+// The logo from the header, which the boot ROM compares against its own copy before
+// starting the game
+#[derive(Clone, Copy, PartialEq)]
+pub struct Logo(pub [u8; 48]);
+
+impl Logo {
+    pub fn is_valid(&self) -> bool {
+        self.0 == NINTENDO_LOGO
+    }
+}
+
+// Kept on one line, since the whole header is printed at startup
+impl fmt::Debug for Logo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Logo(")?;
+        for byte in self.0 {
+            write!(f, "{:02X}", byte)?;
+        }
+        write!(f, ")")
+    }
+}
+
 #[derive(Debug)]
 pub enum FlagCGB {
     WorksWithOld,
+    // CGB enhancements, while still working on DMG
+    SupportsNew,
     RequiresNew,
 }
 
@@ -19,7 +59,7 @@ pub enum FlagSGB {
     SGB,
 }
 
-fn read_title_info(data: &Vec<u8>) -> Result<TitleInfo, String> {
+fn read_title_info(data: &Vec<u8>) -> Result<TitleInfo, EmulatorError> {
     // TODO: Take different size into better consideration:
     // https://gbdev.io/pandocs/The_Cartridge_Header.html#0134-0143--title
     // Title was originally 15 bytes, but support only 11 bytes (due to newer gameboys)
@@ -30,7 +70,7 @@ fn read_title_info(data: &Vec<u8>) -> Result<TitleInfo, String> {
         None => title_bytes,
     };
     let title = str::from_utf8(title_bytes)
-        .map_err(|x| x.to_string())?
+        .map_err(|x| EmulatorError::InvalidHeader(x.to_string()))?
         .to_owned();
 
     let manufacturer_code_bytes: &[u8] = &data[0x013F..=0x0142];
@@ -41,24 +81,19 @@ fn read_title_info(data: &Vec<u8>) -> Result<TitleInfo, String> {
     let manufacturer_code = if has_manufacturer_code {
         Some(
             str::from_utf8(manufacturer_code_bytes)
-                .map_err(|x| x.to_string())?
+                .map_err(|x| EmulatorError::InvalidHeader(x.to_string()))?
                 .to_owned(),
         )
     } else {
         None
     };
 
+    // CGB only ROMs are rejected when creating the emulator, not here, so that
+    // their header can still be shown
     let flag_byte: &u8 = &data[0x0143];
 
-    match flag_byte {
-        0xC0 => {
-            panic!("The cartridge requires CGB functionality");
-        },
-        _ => (),
-    }
-
     let flag: FlagCGB = match flag_byte {
-        // 0x80 => Ok(FlagCGB::WorksWithOld),
+        0x80 => FlagCGB::SupportsNew,
         0xC0 => FlagCGB::RequiresNew,
         // _ => Err(format!("Invalid flag: {}", flag_byte)),
         _ => FlagCGB::WorksWithOld,
@@ -71,7 +106,7 @@ fn read_title_info(data: &Vec<u8>) -> Result<TitleInfo, String> {
     })
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
 pub enum CartridgeType {
     RomOnly,
     MBC1,
@@ -100,11 +135,38 @@ pub enum CartridgeType {
     MBC5RumbleRamBattery,
     PocketCamera,
     BandaiTama5,
+    #[value(name = "huc3")]
     HuC3,
+    #[value(name = "huc1-ram-battery")]
     HuC1RamBattery,
 }
 
 impl CartridgeType {
+    // Whether the RAM size in the header applies. MBC2 has its RAM built in instead.
+    pub fn has_external_ram(&self) -> bool {
+        matches!(
+            self,
+            CartridgeType::MBC1Ram
+                | CartridgeType::MBC1RamBattery
+                | CartridgeType::RomRam
+                | CartridgeType::RomRamBattery
+                | CartridgeType::Mmm01Ram
+                | CartridgeType::Mmm01RamBattery
+                | CartridgeType::MBC3TimerRamBattery
+                | CartridgeType::MBC3Ram
+                | CartridgeType::MBC3RamBattery
+                | CartridgeType::MBC4Ram
+                | CartridgeType::MBC4RamBattery
+                | CartridgeType::MBC5Ram
+                | CartridgeType::MBC5RamBattery
+                | CartridgeType::MBC5RumbleRam
+                | CartridgeType::MBC5RumbleRamBattery
+                | CartridgeType::PocketCamera
+                | CartridgeType::HuC3
+                | CartridgeType::HuC1RamBattery
+        )
+    }
+
     fn from_byte(byte: u8) -> Option<CartridgeType> {
         match byte {
             0x00 => Some(CartridgeType::RomOnly),
@@ -148,6 +210,13 @@ pub enum RomSize {
 }
 
 impl RomSize {
+    pub fn byte_count(&self) -> usize {
+        match self {
+            RomSize::NoBanking => 2 * 0x4000,
+            RomSize::WithBanking(bank_count) => bank_count * 0x4000,
+        }
+    }
+
     fn from_byte(byte: u8) -> Option<RomSize> {
         match byte {
             0x00 => Some(RomSize::NoBanking),
@@ -168,8 +237,7 @@ impl RomSize {
     }
 }
 
-#[derive(Debug)]
-#[allow(dead_code)]
+#[derive(Debug, Copy, Clone)]
 pub enum RamSize {
     NoBanks,
     Size {
@@ -188,6 +256,13 @@ impl RamSize {
             _ => None,
         }
     }
+
+    pub fn byte_count(&self) -> usize {
+        match self {
+            RamSize::NoBanks => 0,
+            RamSize::Size { bank_count, bank_size_kb } => bank_count * bank_size_kb * 1024,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -196,29 +271,62 @@ pub struct Header {
     pub title: String,
     pub manufacturer_code: Option<String>,
     pub cgb_flag: FlagCGB,
+    // The new licensee code, only used when the old one is 0x33
     pub license_code: Option<String>,
+    pub old_license_code: u8,
     pub sgb_flag: FlagSGB,
     pub cartridge_type: CartridgeType,
     pub rom_size: RomSize,
     pub ram_size: RamSize,
+    pub logo: Logo,
+    pub header_checksum_valid: bool,
+}
+
+// https://gbdev.io/pandocs/The_Cartridge_Header.html#014d--header-checksum
+fn compute_header_checksum(rom_data: &[u8]) -> u8 {
+    rom_data[0x0134..=0x014C]
+        .iter()
+        .fold(0u8, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1))
+}
+
+pub fn has_nintendo_logo(rom_data: &[u8]) -> bool {
+    rom_data.get(LOGO_OFFSET..LOGO_OFFSET + NINTENDO_LOGO.len()) == Some(&NINTENDO_LOGO[..])
+}
+
+// Makes sure the cartridge header is where we expect it, stripping any copier header.
+pub fn validate_rom_data(mut rom_data: Vec<u8>) -> Result<Vec<u8>, EmulatorError> {
+    if has_nintendo_logo(&rom_data) {
+        return Ok(rom_data);
+    }
+
+    if rom_data.len() > COPIER_HEADER_SIZE && has_nintendo_logo(&rom_data[COPIER_HEADER_SIZE..]) {
+        println!("Stripping {} byte copier header from ROM", COPIER_HEADER_SIZE);
+        rom_data.drain(..COPIER_HEADER_SIZE);
+        return Ok(rom_data);
+    }
+
+    Err(EmulatorError::InvalidHeader(
+        "No valid cartridge header found, the Nintendo logo is missing".to_owned(),
+    ))
 }
 
 impl Header {
-    pub fn read_from_rom(rom_data: &Vec<u8>) -> Result<Header, String> {
+    pub fn read_from_rom(rom_data: &Vec<u8>) -> Result<Header, EmulatorError> {
         if rom_data.len() < 0x0150 {
-            return Err(format!(
-                "Too little ROM data to read header. Need 0x0150, got {}",
-                rom_data.len()
-            ));
+            return Err(EmulatorError::RomTooSmall(rom_data.len()));
         }
         let title_info = read_title_info(rom_data)?;
-        let license_code = match (rom_data[0x0114], rom_data[0x0145]) {
-            (_, 0x00) => None,
-            (0x00, _) => None,
-            _ => {
-                let data = &rom_data[0x0114..=0x0145];
-                Some(str::from_utf8(data).unwrap().to_owned())
-            }
+        // https://gbdev.io/pandocs/The_Cartridge_Header.html#01440145--new-licensee-code
+        let old_license_code = rom_data[0x014B];
+        let license_code = if old_license_code == 0x33 {
+            let data = &rom_data[0x0144..=0x0145];
+            Some(
+                str::from_utf8(data)
+                    .map_err(|x| EmulatorError::InvalidHeader(x.to_string()))?
+                    .to_owned(),
+            )
+        } else {
+            None
         };
 
         let sgb_flag = match rom_data[0x0146] {
@@ -228,13 +336,13 @@ impl Header {
         };
 
         let cartridge_type = CartridgeType::from_byte(rom_data[0x147])
-            .ok_or(format!("Invalid cartridge type: {}", rom_data[0x147]))?;
+            .ok_or(EmulatorError::InvalidHeader(format!("Invalid cartridge type: {}", rom_data[0x147])))?;
 
         let rom_size = RomSize::from_byte(rom_data[0x148])
-            .ok_or(format!("Invalid ROM size: {}", rom_data[0x148]))?;
+            .ok_or(EmulatorError::InvalidHeader(format!("Invalid ROM size: {}", rom_data[0x148])))?;
 
         let ram_size = RamSize::from_byte(rom_data[0x149])
-            .ok_or(format!("Invalid RAM size: {}", rom_data[0x149]))?;
+            .ok_or(EmulatorError::InvalidHeader(format!("Invalid RAM size: {}", rom_data[0x149])))?;
 
 
         Ok(Header {
@@ -242,10 +350,170 @@ impl Header {
             manufacturer_code: title_info.manufacturer_code,
             cgb_flag: title_info.flag,
             license_code,
+            old_license_code,
             sgb_flag,
             cartridge_type,
             rom_size,
             ram_size,
+            logo: Logo(rom_data[LOGO_OFFSET..LOGO_OFFSET + NINTENDO_LOGO.len()].try_into().unwrap()),
+            header_checksum_valid: compute_header_checksum(rom_data) == rom_data[0x014D],
         })
     }
+
+    // Whether the boot ROM would accept the cartridge, it locks up otherwise
+    pub fn has_valid_logo(&self) -> bool {
+        self.logo.is_valid()
+    }
+
+    // Combinations of cartridge type and RAM size which don't make sense. The cartridge
+    // goes by the type, see create_for_cartridge_type.
+    pub fn ram_size_warnings(&self) -> Vec<String> {
+        let declared_bytes = self.ram_size.byte_count();
+        let mut warnings = vec![];
+        if self.cartridge_type.has_external_ram() {
+            if declared_bytes == 0 {
+                warnings.push(format!("{:?} should have RAM, but the header declares none", self.cartridge_type));
+            }
+        } else if declared_bytes > 0 {
+            warnings.push(format!(
+                "{:?} has no external RAM, ignoring the declared {} bytes",
+                self.cartridge_type, declared_bytes
+            ));
+        }
+        warnings
+    }
+
+    // One "key: value" line per field, for --info
+    pub fn format_info(&self) -> String {
+        let licensee = match &self.license_code {
+            Some(code) => code.clone(),
+            None => format!("{:#04X}", self.old_license_code),
+        };
+        let lines = [
+            ("title", self.title.clone()),
+            ("manufacturer_code", self.manufacturer_code.clone().unwrap_or_default()),
+            ("cartridge_type", format!("{:?}", self.cartridge_type)),
+            ("rom_size", self.rom_size.byte_count().to_string()),
+            ("ram_size", self.ram_size.byte_count().to_string()),
+            ("licensee", licensee),
+            ("cgb_flag", format!("{:?}", self.cgb_flag)),
+            ("sgb_flag", format!("{:?}", self.sgb_flag)),
+            ("logo", if self.has_valid_logo() { "valid" } else { "invalid" }.to_owned()),
+            ("header_checksum", if self.header_checksum_valid { "valid" } else { "invalid" }.to_owned()),
+        ];
+        lines.iter().map(|(key, value)| format!("{}: {}\n", key, value)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom_with_logo() -> Vec<u8> {
+        let mut rom_data = vec![0x00; 0x8000];
+        rom_data[LOGO_OFFSET..LOGO_OFFSET + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        rom_data[0x0134..0x0138].copy_from_slice(b"TEST");
+        rom_data
+    }
+
+    #[test]
+    fn test_validate_rom_data() {
+        let rom_data = rom_with_logo();
+        assert_eq!(validate_rom_data(rom_data.clone()), Ok(rom_data));
+    }
+
+    #[test]
+    fn test_strips_copier_header() {
+        let mut prefixed_rom_data = vec![0xAA; COPIER_HEADER_SIZE];
+        prefixed_rom_data.extend(rom_with_logo());
+
+        let rom_data = validate_rom_data(prefixed_rom_data).unwrap();
+        assert_eq!(rom_data, rom_with_logo());
+
+        let header = Header::read_from_rom(&rom_data).unwrap();
+        assert_eq!(header.title, "TEST");
+    }
+
+    #[test]
+    fn test_missing_logo() {
+        assert!(matches!(validate_rom_data(vec![0x00; 0x8000]), Err(EmulatorError::InvalidHeader(_))));
+        assert!(matches!(validate_rom_data(vec![0x00; 0x10]), Err(EmulatorError::InvalidHeader(_))));
+    }
+
+    #[test]
+    fn test_ram_size_warnings() {
+        let warnings = |cartridge_type: u8, ram_size: u8| {
+            let mut rom_data = rom_with_logo();
+            rom_data[0x0147] = cartridge_type;
+            rom_data[0x0149] = ram_size;
+            Header::read_from_rom(&rom_data).unwrap().ram_size_warnings()
+        };
+
+        // MBC1+RAM with 8 KB, and MBC2 without external RAM
+        assert!(warnings(0x02, 0x02).is_empty());
+        assert!(warnings(0x05, 0x00).is_empty());
+
+        assert_eq!(warnings(0x05, 0x01), vec!["MBC2 has no external RAM, ignoring the declared 2048 bytes"]);
+        assert_eq!(warnings(0x03, 0x00), vec!["MBC1RamBattery should have RAM, but the header declares none"]);
+    }
+
+    #[test]
+    fn test_format_info() {
+        let mut rom_data = rom_with_logo();
+        // MBC1, 64 KB ROM, 8 KB RAM
+        rom_data[0x0147..0x014A].copy_from_slice(&[0x01, 0x01, 0x02]);
+        rom_data[0x0144..0x0146].copy_from_slice(b"01");
+        rom_data[0x014B] = 0x33;
+        rom_data[0x014D] = compute_header_checksum(&rom_data);
+
+        let info = Header::read_from_rom(&rom_data).unwrap().format_info();
+        assert_eq!(
+            info,
+            "title: TEST\n\
+             manufacturer_code: \n\
+             cartridge_type: MBC1\n\
+             rom_size: 65536\n\
+             ram_size: 8192\n\
+             licensee: 01\n\
+             cgb_flag: WorksWithOld\n\
+             sgb_flag: NoSGB\n\
+             logo: valid\n\
+             header_checksum: valid\n"
+        );
+
+        rom_data[0x014D] ^= 0xFF;
+        let header = Header::read_from_rom(&rom_data).unwrap();
+        assert!(!header.header_checksum_valid);
+        assert_eq!(header.license_code.as_deref(), Some("01"));
+    }
+
+    #[test]
+    fn test_logo() {
+        let mut rom_data = rom_with_logo();
+        let header = Header::read_from_rom(&rom_data).unwrap();
+        assert_eq!(header.logo, Logo(NINTENDO_LOGO));
+        assert!(header.has_valid_logo());
+
+        rom_data[LOGO_OFFSET + 10] ^= 0x01;
+        let header = Header::read_from_rom(&rom_data).unwrap();
+        assert!(!header.has_valid_logo());
+        assert!(format!("{:?}", header.logo).starts_with("Logo(CEED6666CC0D000B037301"));
+    }
+
+    #[test]
+    fn test_header_errors() {
+        assert_eq!(Header::read_from_rom(&vec![0x00; 0x0100]).unwrap_err(), EmulatorError::RomTooSmall(0x0100));
+
+        // CGB only, which can still be read
+        let mut rom_data = rom_with_logo();
+        rom_data[0x0143] = 0xC0;
+        let header = Header::read_from_rom(&rom_data).unwrap();
+        assert!(matches!(header.cgb_flag, FlagCGB::RequiresNew));
+        assert!(header.format_info().contains("cgb_flag: RequiresNew"));
+
+        let mut rom_data = rom_with_logo();
+        rom_data[0x0147] = 0x04;
+        let error = Header::read_from_rom(&rom_data).unwrap_err();
+        assert_eq!(error.to_string(), "Invalid cartridge type: 4");
+    }
 }
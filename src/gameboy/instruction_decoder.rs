@@ -138,6 +138,8 @@ pub enum Instruction {
     Daa,
     Rst(u16),
     Stop,
+    // Undefined, these lock up the CPU
+    Illegal(u8),
 }
 
 fn resolve_common_operand_from_col(col: u8) -> CommonOperand {
@@ -596,6 +598,9 @@ pub fn decode(opcode: u8) -> Option<Instruction> {
         0xE9 => Some(Instruction::JumpAddressHL),
         0xF3 => Some(Instruction::DisableInterrupts),
         0xFB => Some(Instruction::EnableInterrupts),
+        0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+            Some(Instruction::Illegal(opcode))
+        }
         _ => None,
     }
 }
@@ -610,8 +615,8 @@ pub fn decode_cb(opcode: u8) -> Option<Instruction> {
         0x18..=0x1F => Instruction::CbRr(target),
         0x20..=0x27 => Instruction::CbSla(target),
         0x28..=0x2F => Instruction::CbSra(target),
-        0x38..=0x3F => Instruction::CbSrl(target),
         0x30..=0x37 => Instruction::CbSwap(target),
+        0x38..=0x3F => Instruction::CbSrl(target),
         0x40..=0x47 => Instruction::CbBit { n: 0, target, },
         0x48..=0x4F => Instruction::CbBit { n: 1, target, },
         0x50..=0x57 => Instruction::CbBit { n: 2, target, },
@@ -638,3 +643,152 @@ pub fn decode_cb(opcode: u8) -> Option<Instruction> {
         0xF8..=0xFF => Instruction::CbSet { n: 7, target, },
     })
 }
+
+// In bytes, including the opcode and any 0xCB prefix
+pub fn instruction_length(instruction: &Instruction) -> u16 {
+    let logical_op_length = |target: &LogicalOpTarget| match target {
+        LogicalOpTarget::Common(_) => 1,
+        LogicalOpTarget::ImmediateU8 => 2,
+    };
+
+    match instruction {
+        Instruction::LoadU8 { dst, src } => match (dst, src) {
+            (LoadDstU8::ImmediateAddressU16, _) | (_, LoadSrcU8::ImmediateAddressU16) => 3,
+            (LoadDstU8::ImmediateAddressU8, _)
+            | (_, LoadSrcU8::ImmediateAddressU8 | LoadSrcU8::ImmediateU8) => 2,
+            _ => 1,
+        },
+        Instruction::LoadU16 { dst, src } => match (dst, src) {
+            (LoadDstU16::ImmediateAddress, _) | (_, LoadSrcU16::ImmediateU16) => 3,
+            _ => 1,
+        },
+        Instruction::JumpImmediate(_) | Instruction::Call(_) => 3,
+        // STOP is followed by a padding byte
+        Instruction::JumpRelative(_)
+        | Instruction::LoadHlWithOffsetSp
+        | Instruction::AddStackPointer
+        | Instruction::Stop => 2,
+        Instruction::Or(target)
+        | Instruction::Compare(target)
+        | Instruction::And(target)
+        | Instruction::Xor(target)
+        | Instruction::AddU8(target)
+        | Instruction::Sub(target)
+        | Instruction::Adc(target)
+        | Instruction::Sbc(target) => logical_op_length(target),
+        Instruction::CbSrl(_)
+        | Instruction::CbRr(_)
+        | Instruction::CbRl(_)
+        | Instruction::CbRlc(_)
+        | Instruction::CbRrc(_)
+        | Instruction::CbBit { .. }
+        | Instruction::CbRes { .. }
+        | Instruction::CbSet { .. }
+        | Instruction::CbSwap(_)
+        | Instruction::CbSla(_)
+        | Instruction::CbSra(_) => 2,
+        _ => 1,
+    }
+}
+
+// Decodes the instructions starting at `start`, up to and including the one at `end`, as
+// (address, instruction, length). Unknown opcodes are skipped a byte at a time, and an
+// instruction cut off by the end of the ROM is left out.
+pub fn disassemble_range(rom: &[u8], start: u16, end: u16) -> Vec<(u16, Instruction, usize)> {
+    let mut instructions = vec![];
+    let mut address = start as usize;
+    while address <= end as usize && address < rom.len() {
+        let decoded = match rom[address] {
+            0xCB => rom.get(address + 1).and_then(|&opcode| decode_cb(opcode)),
+            opcode => decode(opcode),
+        };
+        let instruction = match decoded {
+            Some(instruction) => instruction,
+            None => {
+                address += 1;
+                continue;
+            }
+        };
+
+        let length = instruction_length(&instruction) as usize;
+        if address + length > rom.len() {
+            break;
+        }
+        instructions.push((address as u16, instruction, length));
+        address += length;
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble_range() {
+        let rom = [
+            0x00, // NOP
+            0x3E, 0x42, // LD A, 0x42
+            0xC3, 0x50, 0x01, // JP 0x0150
+            0xCB, 0x7C, // BIT 7, H
+            0xD3, // Illegal
+            0xAF, // XOR A
+            0xCD, 0x00, // CALL, cut off
+        ];
+
+        let instructions = disassemble_range(&rom, 0, 0xFFFF);
+        let layout: Vec<(u16, usize)> = instructions.iter().map(|(address, _, length)| (*address, *length)).collect();
+        assert_eq!(layout, vec![(0, 1), (1, 2), (3, 3), (6, 2), (8, 1), (9, 1)]);
+
+        assert!(matches!(instructions[2].1, Instruction::JumpImmediate(None)));
+        assert!(matches!(
+            instructions[3].1,
+            Instruction::CbBit { n: 7, target: CommonOperand::Register(RegisterU8::H) }
+        ));
+        assert!(matches!(instructions[4].1, Instruction::Illegal(0xD3)));
+        assert!(matches!(instructions[5].1, Instruction::Xor(LogicalOpTarget::Common(CommonOperand::Register(RegisterU8::A)))));
+
+        // The last instruction starts at the end, and may extend past it
+        let layout: Vec<u16> = disassemble_range(&rom, 1, 3).iter().map(|(address, _, _)| *address).collect();
+        assert_eq!(layout, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_decode_all_opcodes() {
+        // Only the CB prefix is left to decode_cb
+        for opcode in 0..=0xFF_u8 {
+            assert_eq!(decode(opcode).is_some(), opcode != 0xCB, "opcode {:#04X}", opcode);
+        }
+        assert!(matches!(decode(0xDB), Some(Instruction::Illegal(0xDB))));
+        assert!(matches!(decode(0xD9), Some(Instruction::Reti)));
+    }
+
+    #[test]
+    fn test_decode_cb_table() {
+        // Each row of 8 opcodes is one operation, with the target picked by the column
+        let shifts = ["CbRlc", "CbRrc", "CbRl", "CbRr", "CbSla", "CbSra", "CbSwap", "CbSrl"];
+        let bit_ops = ["CbBit", "CbRes", "CbSet"];
+        let targets = [
+            "Register(B)",
+            "Register(C)",
+            "Register(D)",
+            "Register(E)",
+            "Register(H)",
+            "Register(L)",
+            "AddressHL",
+            "Register(A)",
+        ];
+
+        for opcode in 0..=0xFF_u8 {
+            let row = (opcode >> 3) as usize;
+            let target = targets[(opcode & 0x7) as usize];
+            let expected = match row {
+                0..=7 => format!("{}({})", shifts[row], target),
+                _ => format!("{} {{ n: {}, target: {} }}", bit_ops[row / 8 - 1], row % 8, target),
+            };
+            let instruction = decode_cb(opcode).unwrap_or_else(|| panic!("{:#04X} not decoded", opcode));
+            assert_eq!(format!("{:?}", instruction), expected, "opcode {:#04X}", opcode);
+            assert_eq!(instruction_length(&instruction), 2);
+        }
+    }
+}
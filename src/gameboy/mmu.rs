@@ -1,10 +1,16 @@
+use std::collections::VecDeque;
 use std::io::{self, Write};
+use std::ops::RangeInclusive;
 
+use clap::ValueEnum;
+
+use crate::common::attachment::Attachment;
 use crate::common::joypad_events::{JoypadEvent, JoypadButton};
 
 use super::address::Address;
+use super::audio::Audio;
 use super::cartridge::Cartridge;
-use super::video::Video;
+use super::video::{Video, VideoInterrupt};
 use super::utils::{get_bit, set_bit_mut};
 
 pub struct Word {
@@ -35,64 +41,98 @@ impl Word {
         Self { value }
     }
 
-    fn compose_new(high: u8, low: u8) -> Self {
+    // The Game Boy is little-endian, the low byte is stored first
+    pub fn from_le_bytes(bytes: [u8; 2]) -> Self {
         Self {
-            value: ((high as u16) << 8) | low as u16,
+            value: u16::from_le_bytes(bytes),
         }
-
     }
 
-    fn low(&self) -> u8 {
-        (self.value & 0xFF) as u8
-    }
-
-    fn high(&self) -> u8 {
-        ((self.value & 0xFF00) >> 8) as u8
+    pub fn to_le_bytes(&self) -> [u8; 2] {
+        self.value.to_le_bytes()
     }
 }
 
+#[derive(Clone)]
 pub struct IO {
     joypad_input: Joypad,
     serial: Serial,
     timer: Timer,
-    audio: Vec<u8>,
-    wave_pattern: Vec<u8>,
+    audio: Audio,
+    speed_switch: SpeedSwitch,
     boot_rom_disabled: u8,
 }
 
-fn byte_vec_for_range(
-    range_inclusive_start: u16,
-    range_inclusive_end: u16,
-) -> Vec<u8> {
-    let size = (range_inclusive_end - range_inclusive_start + 1)  as usize;
-    vec![0x00; size]
-}
-
 impl IO {
-    fn new(print_serial: bool) -> Self {
+    fn new(print_serial: bool, is_cgb: bool) -> Self {
         Self {
             joypad_input: Joypad::new(),
-            serial: Serial::new(print_serial),
+            serial: Serial::new(print_serial, is_cgb),
             timer: Timer::new(),
-            audio: byte_vec_for_range(0xFF10, 0xFF26),
-            wave_pattern: byte_vec_for_range(0xFF30, 0xFF3F),
+            audio: Audio::new(),
+            speed_switch: SpeedSwitch::new(),
             boot_rom_disabled: 0x00,
         }
     }
 }
 
+const WRAM_BANK_SIZE: usize = 0x1000;
+
+// Oldest writes are dropped once the log is full, so it can't grow forever
+const MAX_WRITE_LOG_LEN: usize = 0x10000;
+// The boot ROM runs for a while before handing over, so DIV is far from 0
+const POST_BOOT_DIVIDER: u16 = 0xABCC;
+// Sound is turned on first, as the other sound registers can't be written while it's off.
+// Channel 1 plays the boot sound, but isn't retriggered here so nothing is heard.
+const POST_BOOT_IO_REGISTERS: &[(u16, u8)] = &[
+    (0xFF05, 0x00), (0xFF06, 0x00), (0xFF07, 0xF8),
+    (0xFF26, 0x80), (0xFF24, 0x77), (0xFF25, 0xF3),
+    (0xFF10, 0x80), (0xFF11, 0xBF), (0xFF12, 0xF3), (0xFF13, 0xFF),
+    (0xFF16, 0x3F), (0xFF17, 0x00), (0xFF18, 0xFF),
+    (0xFF1A, 0x7F), (0xFF1B, 0xFF), (0xFF1C, 0x9F), (0xFF1D, 0xFF),
+    (0xFF20, 0xFF), (0xFF21, 0x00), (0xFF22, 0x00),
+    (0xFF40, 0x91), (0xFF42, 0x00), (0xFF43, 0x00), (0xFF45, 0x00),
+    (0xFF47, 0xFC), (0xFF4A, 0x00), (0xFF4B, 0x00),
+];
+
+#[derive(Clone)]
 pub struct MMU {
     cartridge: Box<dyn Cartridge>,
     video: Video,
     internal_ram: Vec<u8>,
+    // Selects the bank mapped at 0xD000-0xDFFF, always 1 in DMG mode
+    wram_bank: u8,
     io: IO,
     high_ram: Vec<u8>,
     interrupt_enable: u8,
     interrupt_flags: u8,
     consumed_read_write_cycles: u8,
+    is_cgb: bool,
+    pending_half_video_cycle: bool,
+    // Ticks the PPU on each memory access, instead of once the whole instruction is done
+    lockstep_video: bool,
+    // Maps the whole address space to the cartridge, for CPU tests
+    #[cfg(test)]
+    flat_memory: bool,
+
+    // Debug
+    // PC of the instruction currently executing, set by the CPU
+    current_pc: u16,
+    write_watch: Option<RangeInclusive<u16>>,
+    // (pc, address, value) of each CPU write to the watched range, left out of save states
+    write_log: Attachment<VecDeque<(u16, u16, u8)>>,
+    trace_mmio: bool,
+    // (pc, address, value, access) of each CPU access to an IO register, when traced
+    mmio_log: Vec<(u16, u16, u8, MmioAccess)>,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MmioAccess {
+    Read,
+    Write,
+}
+
+#[derive(Copy, Clone, Debug)]
 pub enum InterruptSource {
     VBlank = 0,
     Lcd = 1,
@@ -111,6 +151,7 @@ pub fn interrupt_vector(interrupt: InterruptSource) -> u8 {
     }
 }
 
+#[derive(Clone)]
 struct Timer {
     divider: u16,
     timer_counter: u8,
@@ -119,8 +160,14 @@ struct Timer {
 
     // Internal
     clock_counter: usize,
+    pending_frame_sequencer_ticks: u8,
 }
 
+// The APU frame sequencer is clocked at 512 Hz by the falling edge of
+// DIV bit 4, i.e. bit 12 of the internal 16 bit divider.
+// https://gbdev.io/pandocs/Audio_details.html#div-apu
+const FRAME_SEQUENCER_DIVIDER_BIT: u16 = 12;
+
 #[derive(Copy, Clone)]
 enum ClockSelect {
     Div1024 = 1024,
@@ -137,6 +184,7 @@ impl Timer {
             timer_modulo: 0,
             timer_control: 0,
             clock_counter: 0,
+            pending_frame_sequencer_ticks: 0,
         }
     }
 
@@ -156,7 +204,8 @@ impl Timer {
         match address.value() {
             // Writing any value to this register resets it to $00.
             // https://gbdev.io/pandocs/Timer_and_Divider_Registers.html#ff04--div-divider-register
-            0xFF04 => self.divider = 0,
+            // Since this resets the divider it can also clock the frame sequencer.
+            0xFF04 => self.set_divider(0),
             0xFF05 => self.timer_counter = value,
             0xFF06 => self.timer_modulo = value,
             0xFF07 => {
@@ -169,7 +218,7 @@ impl Timer {
     fn maybe_tick_cycles(&mut self, elapsed_cycles: u8) -> bool {
         let mut fire_interrupt = false;
         for _ in 0..(elapsed_cycles * 4) {
-            self.divider = self.divider.wrapping_add(1);
+            self.set_divider(self.divider.wrapping_add(1));
             if self.is_timer_enabled() {
                 fire_interrupt |= self.tick_clock();
             }
@@ -177,6 +226,30 @@ impl Timer {
         return fire_interrupt;
     }
 
+    fn set_divider(&mut self, value: u16) {
+        let frame_sequencer_bit = 1 << FRAME_SEQUENCER_DIVIDER_BIT;
+        let was_set = self.divider & frame_sequencer_bit != 0;
+        let is_set = value & frame_sequencer_bit != 0;
+
+        if was_set && !is_set {
+            self.pending_frame_sequencer_ticks += 1;
+        }
+
+        self.divider = value;
+    }
+
+    // Along with DIV, the counter towards the next TIMA increment starts over
+    fn reset_divider(&mut self) {
+        self.set_divider(0);
+        self.clock_counter = 0;
+    }
+
+    fn take_frame_sequencer_ticks(&mut self) -> u8 {
+        let ticks = self.pending_frame_sequencer_ticks;
+        self.pending_frame_sequencer_ticks = 0;
+        ticks
+    }
+
     fn tick_clock(&mut self) -> bool {
         self.clock_counter += 1;
 
@@ -216,22 +289,77 @@ impl Timer {
     }
 }
 
+// https://gbdev.io/pandocs/CGB_Registers.html#ff4d--key1-cgb-mode-only-prepare-speed-switch
+#[derive(Clone)]
+struct SpeedSwitch {
+    armed: bool,
+    double_speed: bool,
+}
+
+impl SpeedSwitch {
+    fn new() -> Self {
+        Self {
+            armed: false,
+            double_speed: false,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        // Unused bits read as 1
+        let mut value = 0b0111_1110;
+        set_bit_mut(&mut value, 7, self.double_speed);
+        set_bit_mut(&mut value, 0, self.armed);
+        value
+    }
+
+    fn write(&mut self, value: u8) {
+        // Only the armed bit is writable
+        self.armed = get_bit(value, 0);
+    }
+
+    fn try_switch(&mut self) -> bool {
+        if !self.armed {
+            return false;
+        }
+        self.armed = false;
+        self.double_speed = !self.double_speed;
+        true
+    }
+}
+
+#[derive(Clone)]
 struct Serial {
     transfer_data: u8,
+    control: u8,
     print_serial: bool,
+    // Bit 1 of the control register selects the clock speed, but only on CGB
+    is_cgb: bool,
 }
 
 impl Serial {
-    fn new(print_serial: bool) -> Self {
+    fn new(print_serial: bool, is_cgb: bool) -> Self {
         Self {
             transfer_data: 0,
+            control: 0,
             print_serial,
+            is_cgb,
         }
     }
+
+    // Only bit 7 and 0 are used on DMG, and bit 1 as well on CGB. The rest read as 1.
+    // https://gbdev.io/pandocs/Serial_Data_Transfer_(Link_Cable).html#ff02--sc-serial-transfer-control
+    fn control_mask(&self) -> u8 {
+        if self.is_cgb {
+            0b1000_0011
+        } else {
+            0b1000_0001
+        }
+    }
+
     fn read(&self, address: Address) -> u8 {
         match address.value() {
             0xFF01 => self.transfer_data,
-            0xFF02 => todo!("Read for serial control"),
+            0xFF02 => self.control | !self.control_mask(),
             _ => panic!("Invalid serial address: {:#06X}", address.value()),
         }
     }
@@ -241,6 +369,7 @@ impl Serial {
             0xFF01 => self.transfer_data = value,
             // TODO: Fire interrupt?
             0xFF02 => {
+                self.control = value & self.control_mask();
                 if self.print_serial && get_bit(value, 7) {
                     print!("{}", self.transfer_data as char);
                     io::stdout().flush().unwrap();
@@ -251,8 +380,54 @@ impl Serial {
     }
 }
 
-#[derive(Debug)]
-pub struct Joypad {
+// Contents of WRAM and HRAM at power on
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+pub enum RamInit {
+    Zero,
+    Ones,
+    // The contents differ between units and aren't really random either. This is a rough,
+    // but deterministic, approximation: alternating runs of 8 bytes of 0x00 and 0xFF.
+    Dmg,
+}
+
+impl RamInit {
+    fn value_at(&self, index: usize) -> u8 {
+        match self {
+            RamInit::Zero => 0x00,
+            RamInit::Ones => 0xFF,
+            RamInit::Dmg => if (index / 8).is_multiple_of(2) { 0x00 } else { 0xFF },
+        }
+    }
+}
+
+// Up to four players, as with the SGB multiplayer adapter
+pub const JOYPAD_PLAYER_COUNT: usize = 4;
+
+// How opposing directions held at the same time (SOCD) are resolved.
+// A real D-pad can't press both, but the lines can still be pulled low together.
+#[derive(Debug, Copy, Clone, PartialEq, ValueEnum)]
+pub enum SocdPolicy {
+    // Both directions read as pressed, like on hardware
+    AllowBoth,
+    // Neither direction reads as pressed
+    Neutral,
+    // Only the most recently pressed direction reads as pressed
+    LastInputPriority,
+}
+
+fn resolve_socd(first: bool, second: bool, first_pressed_last: bool, policy: SocdPolicy) -> (bool, bool) {
+    if !(first && second) {
+        return (first, second);
+    }
+    match policy {
+        SocdPolicy::AllowBoth => (true, true),
+        SocdPolicy::Neutral => (false, false),
+        SocdPolicy::LastInputPriority => (first_pressed_last, !first_pressed_last),
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct JoypadState {
     up: bool,
     down: bool,
     left: bool,
@@ -262,27 +437,48 @@ pub struct Joypad {
     select: bool,
     start: bool,
 
-    select_buttons: bool,
-    direction_buttons: bool,
+    // Which of the opposing directions was pressed most recently
+    up_pressed_last: bool,
+    left_pressed_last: bool,
 }
 
-impl Joypad {
-    fn new() -> Self {
-        Self {
-            up: false,
-            down: false,
-            left: false,
-            right: false,
-            a: false,
-            b: false,
-            select: false,
-            start: false,
-            select_buttons: false,
-            direction_buttons: false,
-        }
+impl JoypadState {
+    // In the order of the register lines
+    fn directions(&self, policy: SocdPolicy) -> [bool; 4] {
+        let (up, down) = resolve_socd(self.up, self.down, self.up_pressed_last, policy);
+        let (left, right) = resolve_socd(self.left, self.right, self.left_pressed_last, policy);
+        [right, left, up, down]
     }
 
-    pub fn consume_platform_event(&mut self, event: JoypadEvent) {
+    fn pressed_buttons(&self) -> Vec<JoypadButton> {
+        [
+            (self.up, JoypadButton::Up),
+            (self.down, JoypadButton::Down),
+            (self.left, JoypadButton::Left),
+            (self.right, JoypadButton::Right),
+            (self.a, JoypadButton::A),
+            (self.b, JoypadButton::B),
+            (self.select, JoypadButton::Select),
+            (self.start, JoypadButton::Start),
+        ]
+        .into_iter()
+        .filter_map(|(is_down, button)| is_down.then_some(button))
+        .collect()
+    }
+
+    fn consume_platform_event(&mut self, event: JoypadEvent) {
+        if event.is_down {
+            match event.button {
+                JoypadButton::Up | JoypadButton::Down => {
+                    self.up_pressed_last = matches!(event.button, JoypadButton::Up);
+                }
+                JoypadButton::Left | JoypadButton::Right => {
+                    self.left_pressed_last = matches!(event.button, JoypadButton::Left);
+                }
+                _ => (),
+            }
+        }
+
         let field: &mut bool = match event.button {
             JoypadButton::Up => &mut self.up,
             JoypadButton::Down => &mut self.down,
@@ -295,22 +491,69 @@ impl Joypad {
         };
         *field = event.is_down;
     }
+}
+
+#[derive(Debug, Clone)]
+pub struct Joypad {
+    players: [JoypadState; JOYPAD_PLAYER_COUNT],
+    // The player visible through the register, always player 0 on DMG
+    current_player: usize,
+    socd_policy: SocdPolicy,
+
+    select_buttons: bool,
+    direction_buttons: bool,
+}
+
+impl Joypad {
+    fn new() -> Self {
+        Self {
+            players: [JoypadState::default(); JOYPAD_PLAYER_COUNT],
+            current_player: 0,
+            socd_policy: SocdPolicy::AllowBoth,
+            select_buttons: false,
+            direction_buttons: false,
+        }
+    }
+
+    pub fn consume_platform_event(&mut self, event: JoypadEvent) {
+        self.consume_player_event(0, event);
+    }
+
+    pub fn set_socd_policy(&mut self, policy: SocdPolicy) {
+        self.socd_policy = policy;
+    }
+
+    pub fn consume_player_event(&mut self, player: usize, event: JoypadEvent) {
+        assert!(player < JOYPAD_PLAYER_COUNT, "Invalid joypad player: {}", player);
+        self.players[player].consume_platform_event(event);
+    }
+
+    // What player 0 is holding, regardless of which group the game has selected or the SOCD policy
+    pub fn pressed_buttons(&self) -> Vec<JoypadButton> {
+        self.players[0].pressed_buttons()
+    }
 
     fn read(&self) -> u8 {
-        let mut base: u8 = 0xF;
+        // Both groups share the same lines, so with both selected a bit
+        // reads as 0 (pressed) if the button is pressed in either group.
+        let state = &self.players[self.current_player];
+        let mut pressed = [false; 4];
 
         if self.direction_buttons {
-            set_bit_mut(&mut base, 0, !self.right);
-            set_bit_mut(&mut base, 1, !self.left);
-            set_bit_mut(&mut base, 2, !self.up);
-            set_bit_mut(&mut base, 3, !self.down);
+            for (line, is_down) in state.directions(self.socd_policy).into_iter().enumerate() {
+                pressed[line] |= is_down;
+            }
         }
 
         if self.select_buttons {
-            set_bit_mut(&mut base, 0, !self.a);
-            set_bit_mut(&mut base, 1, !self.b);
-            set_bit_mut(&mut base, 2, !self.select);
-            set_bit_mut(&mut base, 3, !self.start);
+            for (line, is_down) in [state.a, state.b, state.select, state.start].into_iter().enumerate() {
+                pressed[line] |= is_down;
+            }
+        }
+
+        let mut base: u8 = 0xF;
+        for (line, is_down) in pressed.into_iter().enumerate() {
+            set_bit_mut(&mut base, line as u8, !is_down);
         }
 
         set_bit_mut(&mut base, 4, !self.direction_buttons);
@@ -326,39 +569,94 @@ impl Joypad {
 }
 
 impl MMU {
-    pub fn new(cartridge: Box<dyn Cartridge>, print_serial: bool) -> MMU {
+    pub fn new(cartridge: Box<dyn Cartridge>, print_serial: bool, is_cgb: bool) -> MMU {
         MMU {
             cartridge,
             video: Video::new(),
-            internal_ram: vec![0x00; 0x3000],
-            io: IO::new(print_serial),
+            internal_ram: vec![0x00; 8 * WRAM_BANK_SIZE],
+            wram_bank: 1,
+            io: IO::new(print_serial, is_cgb),
             high_ram: vec![0x00; 0x80],
             interrupt_enable: 0x00,
             interrupt_flags: 0x00,
             consumed_read_write_cycles: 0x00,
+            is_cgb,
+            pending_half_video_cycle: false,
+            lockstep_video: false,
+            #[cfg(test)]
+            flat_memory: false,
+            current_pc: 0x0000,
+            write_watch: None,
+            write_log: Attachment::new(Some(VecDeque::new())),
+            trace_mmio: false,
+            mmio_log: Vec::new(),
+        }
+    }
+
+    // Only meant to be used right after construction, as if the RAM had been like this at power on
+    pub fn init_ram(&mut self, ram_init: RamInit) {
+        for ram in [&mut self.internal_ram, &mut self.high_ram] {
+            for (index, value) in ram.iter_mut().enumerate() {
+                *value = ram_init.value_at(index);
+            }
         }
     }
 
+    // Leaves the IO registers like the boot ROM does, for when it's skipped
+    // https://gbdev.io/pandocs/Power_Up_Sequence.html#hardware-registers
+    pub fn init_post_boot_registers(&mut self) {
+        for (address, value) in POST_BOOT_IO_REGISTERS {
+            self.write_no_consume_cycles(Address::new(*address), *value);
+        }
+        self.io.timer.set_divider(POST_BOOT_DIVIDER);
+        // VBlank is left pending
+        self.interrupt_flags = 0xE1;
+    }
+
+    #[cfg(test)]
+    pub fn use_flat_memory(&mut self) {
+        self.flat_memory = true;
+    }
+
     pub fn take_consumed_cycles(&mut self) -> u8 {
         let ret = self.consumed_read_write_cycles;
         self.consumed_read_write_cycles = 0;
         return ret;
     }
 
+    pub fn cartridge(&self) -> &dyn Cartridge {
+        self.cartridge.as_ref()
+    }
+
+    pub fn cartridge_mut(&mut self) -> &mut dyn Cartridge {
+        self.cartridge.as_mut()
+    }
+
     pub fn video(&mut self) -> &mut Video {
         &mut self.video
     }
 
-    pub fn joypad(&mut self) -> &mut Joypad {
+    pub fn joypad_mut(&mut self) -> &mut Joypad {
         &mut self.io.joypad_input
     }
 
+    pub fn joypad(&self) -> &Joypad {
+        &self.io.joypad_input
+    }
+
     pub fn read(&mut self, address: Address) -> u8 {
         self.consume_cycle();
-        self.read_no_consume_cycles(address)
+        let value = self.read_no_consume_cycles(address);
+        self.maybe_log_mmio(address, value, MmioAccess::Read);
+        value
     }
 
     fn read_no_consume_cycles(&self, address: Address) -> u8 {
+        #[cfg(test)]
+        if self.flat_memory {
+            return self.cartridge.read(address);
+        }
+
         if address.value() == 0xFF0F {
             return self.interrupt_flags;
         }
@@ -373,29 +671,68 @@ impl MMU {
             }
             0x8000..=0x9FFF => self.video.read_vram(address),
             0xA000..=0xBFFF => self.cartridge.read(address),
-            0xC000..=0xDFFF => self.internal_ram[address.index_value() - 0xC000],
+            0xC000..=0xDFFF => self.internal_ram[self.wram_index(address)],
             0xE000..=0xFDFF => panic!("Read access for prohibited memory area"),
             0xFE00..=0xFE9F => self.video.read_oam(address),
-            0xFEA0..=0xFEFF => panic!("Read access for prohibited memory area"),
+            // Reads as 0x00 on DMG (while OAM isn't blocked by the PPU)
+            // https://gbdev.io/pandocs/Memory_Map.html#fea0feff-range
+            0xFEA0..=0xFEFF => 0x00,
             0xFF00..=0xFF7F => self.read_io(address),
             0xFF80..=0xFFFE => self.high_ram[address.index_value() - 0xFF80],
             0xFFFF => self.interrupt_enable,
         }
     }
 
+    // Reads without consuming cycles, and without panicking on the areas the CPU shouldn't
+    // read from. For debuggers and other tools.
+    pub fn peek(&self, address: Address) -> u8 {
+        #[cfg(test)]
+        if self.flat_memory {
+            return self.cartridge.read(address);
+        }
+
+        match address.value() {
+            // Echo RAM mirrors 0xC000-0xDDFF
+            0xE000..=0xFDFF => self.read_no_consume_cycles(Address::new(address.value() - 0x2000)),
+            // The DMA source isn't kept around after the transfer
+            0xFF46 => 0xFF,
+            _ => self.read_no_consume_cycles(address),
+        }
+    }
+
     pub fn read_word(&mut self, address: Address) -> Word {
         let low = self.read(address);
         let high = self.read(address.next());
 
-        Word::compose_new(high, low)
+        Word::from_le_bytes([low, high])
     }
 
     pub fn write(&mut self, address: Address, value: u8) {
         self.consume_cycle();
+        let is_watched = self.write_watch.as_ref().is_some_and(|range| range.contains(&address.value()));
+        if let Some(write_log) = self.write_log.as_mut().filter(|_| is_watched) {
+            if write_log.len() == MAX_WRITE_LOG_LEN {
+                write_log.pop_front();
+            }
+            write_log.push_back((self.current_pc, address.value(), value));
+        }
+        self.maybe_log_mmio(address, value, MmioAccess::Write);
         self.write_no_consume_cycles(address, value);
     }
 
+    fn maybe_log_mmio(&mut self, address: Address, value: u8, access: MmioAccess) {
+        if self.trace_mmio && (0xFF00..=0xFF7F).contains(&address.value()) {
+            self.mmio_log.push((self.current_pc, address.value(), value, access));
+        }
+    }
+
     fn write_no_consume_cycles(&mut self, address: Address, value: u8) {
+        #[cfg(test)]
+        if self.flat_memory {
+            self.cartridge.write(address, value);
+            return;
+        }
+
         if address.value() == 0xFF0F {
             self.interrupt_flags = value;
             return;
@@ -405,10 +742,14 @@ impl MMU {
             0x0000..=0x7FFF => self.cartridge.write(address, value),
             0x8000..=0x9FFF => self.video.write_vram(address, value),
             0xA000..=0xBFFF => self.cartridge.write(address, value),
-            0xC000..=0xDFFF => self.internal_ram[address.index_value() - 0xC000] = value,
+            0xC000..=0xDFFF => {
+                let index = self.wram_index(address);
+                self.internal_ram[index] = value;
+            }
             0xE000..=0xFDFF => panic!("Write access for prohibited memory area"),
             0xFE00..=0xFE9F => self.video.write_oam(address, value),
-            0xFEA0..=0xFEFF => println!("Write access for prohibited memory area: {:#06X}", address.value()),
+            // Ignored, like on DMG
+            0xFEA0..=0xFEFF => (),
             0xFF00..=0xFF7F => self.write_io(address, value),
             0xFF80..=0xFFFE => self.high_ram[address.index_value() - 0xFF80] = value,
             0xFFFF => self.interrupt_enable = value,
@@ -416,8 +757,14 @@ impl MMU {
     }
 
     pub fn write_word(&mut self, address: Address, value: Word) {
-        self.write(address, value.low());
-        self.write(address.next(), value.high());
+        let [low, high] = value.to_le_bytes();
+        self.write(address, low);
+        self.write(address.next(), high);
+    }
+
+    // IE and IF, for tracing
+    pub fn interrupt_registers(&self) -> (u8, u8) {
+        (self.interrupt_enable, self.interrupt_flags)
     }
 
     pub fn is_interrupt_enabled(&self, interrupt: InterruptSource) -> bool {
@@ -436,6 +783,86 @@ impl MMU {
         if self.io.timer.maybe_tick_cycles(elapsed_cycles) {
             self.set_interrupt_flag(InterruptSource::Timer, true);
         }
+        self.step_frame_sequencer();
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        for _ in 0..self.io.timer.take_frame_sequencer_ticks() {
+            self.io.audio.step_frame_sequencer();
+        }
+    }
+
+    // Executing STOP resets DIV, so the timer starts over once the CPU resumes
+    // https://gbdev.io/pandocs/Timer_and_Divider_Registers.html#ff04--div-divider-register
+    pub fn reset_divider(&mut self) {
+        self.io.timer.reset_divider();
+        self.step_frame_sequencer();
+    }
+
+    // Called when executing STOP, returns true if the speed was switched.
+    pub fn try_switch_speed(&mut self) -> bool {
+        if !self.is_cgb {
+            return false;
+        }
+        self.io.speed_switch.try_switch()
+    }
+
+    pub fn set_lockstep_video(&mut self, enabled: bool) {
+        self.lockstep_video = enabled;
+    }
+
+    pub fn lockstep_video(&self) -> bool {
+        self.lockstep_video
+    }
+
+    // Advances the PPU alongside the CPU, requesting any interrupts it raises
+    pub fn tick_video(&mut self, cpu_cycles: u8) {
+        let video_cycles = self.video_cycles_for(cpu_cycles);
+        // TODO: Should we tick cycles * 4 here?
+        for interrupt in self.video.tick_many(video_cycles as usize) {
+            let interrupt_flag = match interrupt {
+                VideoInterrupt::Stat => InterruptSource::Lcd,
+                VideoInterrupt::VBlank => InterruptSource::VBlank,
+            };
+            self.set_interrupt_flag(interrupt_flag, true);
+        }
+    }
+
+    // The PPU runs at the same speed regardless of CPU speed, so in double speed
+    // mode it only gets one cycle for every two CPU cycles.
+    pub fn video_cycles_for(&mut self, cpu_cycles: u8) -> u8 {
+        if !self.io.speed_switch.double_speed {
+            return cpu_cycles;
+        }
+        let total_half_cycles = cpu_cycles + self.pending_half_video_cycle as u8;
+        self.pending_half_video_cycle = total_half_cycles & 1 != 0;
+        total_half_cycles / 2
+    }
+
+    pub fn set_current_pc(&mut self, pc: u16) {
+        self.current_pc = pc;
+    }
+
+    pub fn set_write_watch(&mut self, range: Option<RangeInclusive<u16>>) {
+        self.write_watch = range;
+    }
+
+    pub fn take_write_log(&mut self) -> Vec<(u16, u16, u8)> {
+        self.write_log.as_mut().map(|write_log| std::mem::take(write_log).into()).unwrap_or_default()
+    }
+
+    // Save states are cloned without attachments, so they're moved over from the replaced state
+    pub fn take_attachments_from(&mut self, previous: &mut MMU) {
+        *self.write_log = previous.write_log.take();
+        self.video.set_scanline_hook(previous.video.take_scanline_hook());
+    }
+
+    pub fn set_trace_mmio(&mut self, enabled: bool) {
+        self.trace_mmio = enabled;
+    }
+
+    pub fn take_mmio_log(&mut self) -> Vec<(u16, u16, u8, MmioAccess)> {
+        std::mem::take(&mut self.mmio_log)
     }
 
     pub fn disable_boot_rom(&mut self) {
@@ -446,22 +873,35 @@ impl MMU {
         self.io.boot_rom_disabled != 0
     }
 
+    // https://gbdev.io/pandocs/CGB_Registers.html#ff70--svbk-cgb-mode-only-wram-bank
+    fn wram_index(&self, address: Address) -> usize {
+        match address.value() {
+            0xC000..=0xCFFF => address.index_value() - 0xC000,
+            0xD000..=0xDFFF => {
+                self.wram_bank as usize * WRAM_BANK_SIZE + address.index_value() - 0xD000
+            }
+            _ => panic!("Invalid WRAM address: {:#06X}", address.value()),
+        }
+    }
+
     fn read_io(&self, address: Address) -> u8 {
         match address.value() {
             0xFF00 => self.io.joypad_input.read(),
             0xFF01..=0xFF02 => self.io.serial.read(address),
             0xFF04..=0xFF07 => self.io.timer.read(address),
-            0xFF10..=0xFF26 => self.io.audio[address.index_value() - 0xFF10],
-            0xFF30..=0xFF3F => self.io.wave_pattern[address.index_value() - 0xFF30],
+            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.io.audio.read(address),
+            // Unused gap between the sound registers and wave RAM
+            0xFF27..=0xFF2F => 0xFF,
             0xFF40..=0xFF45 => self.video.read_register(address),
             0xFF46 => panic!("Reading from DMA transfer register"),
             0xFF47..=0xFF4B => self.video.read_register(address),
-            0xFF4D => {
-                // TODO: This is for CGB, but still used by some roms. Log?
-                0x00
-            },
+            // Unmapped on DMG, which some ROMs use to detect CGB
+            0xFF4D if self.is_cgb => self.io.speed_switch.read(),
+            0xFF4F if self.is_cgb => self.video.read_vram_bank(),
             0xFF50 => self.io.boot_rom_disabled,
-            _ => panic!("Read for unmapped IO address: {:#06X}", address.value()),
+            0xFF70 if self.is_cgb => 0xF8 | self.wram_bank,
+            // Unmapped IO reads as open bus
+            _ => 0xFF,
         }
     }
 
@@ -469,19 +909,23 @@ impl MMU {
         match address.value() {
             0xFF00 => self.io.joypad_input.write(value),
             0xFF01..=0xFF02 => self.io.serial.write(address, value),
-            0xFF04..=0xFF07 => self.io.timer.write(address, value),
-            0xFF10..=0xFF26 => self.io.audio[address.index_value() - 0xFF10] = value,
-            0xFF30..=0xFF3F => self.io.wave_pattern[address.index_value() - 0xFF30] = value,
+            0xFF04..=0xFF07 => {
+                self.io.timer.write(address, value);
+                self.step_frame_sequencer();
+            }
+            0xFF10..=0xFF26 | 0xFF30..=0xFF3F => self.io.audio.write(address, value),
+            0xFF27..=0xFF2F => (),
             0xFF40..=0xFF45 => self.video.write_register(address, value),
             0xFF46 => self.do_dma_transfer(value),
             0xFF47..=0xFF4B => self.video.write_register(address, value),
-            0xFF4D => {
-                // TODO: This is for CGB, but still used by some roms. Log?
-            },
+            0xFF4D if self.is_cgb => self.io.speed_switch.write(value),
+            0xFF4F if self.is_cgb => self.video.write_vram_bank(value),
             0xFF50 => self.io.boot_rom_disabled = value,
-            // Undocumented but used
-            0xFF7F => println!("Write to undocumented IO address: {:?} = {}", address, value),
-            _ => panic!("Write for unmapped IO address: {:#06X}", address.value()),
+            // Selecting bank 0 selects bank 1 instead
+            0xFF70 if self.is_cgb => self.wram_bank = (value & 0b111).max(1),
+            // Writes to unmapped IO are ignored. This includes 0xFF7F, which some
+            // games write to in a loop.
+            _ => (),
         };
     }
 
@@ -501,8 +945,430 @@ impl MMU {
         }
     }
 
+    // An M-cycle where the CPU doesn't access memory, e.g. the wait states of an
+    // interrupt dispatch. The timers still advance like for any read or write.
+    pub fn consume_idle_cycle(&mut self) {
+        self.consume_cycle();
+    }
+
     fn consume_cycle(&mut self) {
         self.consumed_read_write_cycles += 1;
         self.maybe_tick_timers(1);
+        if self.lockstep_video {
+            self.tick_video(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct EmptyCartridge;
+
+    impl Cartridge for EmptyCartridge {
+        fn read(&self, _address: Address) -> u8 {
+            0xFF
+        }
+
+        fn write(&mut self, _address: Address, _value: u8) {}
+    }
+
+    fn new_mmu(is_cgb: bool) -> MMU {
+        MMU::new(Box::new(EmptyCartridge), false, is_cgb)
+    }
+
+    fn divider_after_video_cycles(mmu: &mut MMU, video_cycles: usize) -> u8 {
+        let mut elapsed_video_cycles = 0;
+        while elapsed_video_cycles < video_cycles {
+            mmu.maybe_tick_timers(1);
+            elapsed_video_cycles += mmu.video_cycles_for(1) as usize;
+        }
+        mmu.read_no_consume_cycles(Address::new(0xFF04))
+    }
+
+    #[test]
+    fn test_word_le_bytes() {
+        assert_eq!(Word::new(0x1234).to_le_bytes(), [0x34, 0x12]);
+        assert_eq!(Word::from_le_bytes([0x34, 0x12]).value, 0x1234);
+        for value in [0x0000, 0x00FF, 0xFF00, 0xBEEF, 0xFFFF] {
+            assert_eq!(Word::from_le_bytes(Word::new(value).to_le_bytes()).value, value);
+        }
+
+        let mut mmu = new_mmu(false);
+        mmu.write_word(Address::new(0xC000), Word::new(0xBEEF));
+        assert_eq!(mmu.read_no_consume_cycles(Address::new(0xC000)), 0xEF);
+        assert_eq!(mmu.read_no_consume_cycles(Address::new(0xC001)), 0xBE);
+        assert_eq!(mmu.read_word(Address::new(0xC000)).value, 0xBEEF);
+    }
+
+    #[test]
+    fn test_speed_switch() {
+        let key1 = Address::new(0xFF4D);
+        let mut mmu = new_mmu(true);
+
+        assert_eq!(divider_after_video_cycles(&mut mmu, 256), 4);
+
+        // STOP without arming the switch does nothing
+        assert!(!mmu.try_switch_speed());
+        assert_eq!(mmu.read_no_consume_cycles(key1) & 0x81, 0x00);
+
+        mmu.write_no_consume_cycles(key1, 0x01);
+        assert_eq!(mmu.read_no_consume_cycles(key1) & 0x81, 0x01);
+        assert!(mmu.try_switch_speed());
+        assert_eq!(mmu.read_no_consume_cycles(key1) & 0x81, 0x80);
+
+        // The timer now ticks twice as fast compared to the PPU
+        mmu.write_no_consume_cycles(Address::new(0xFF04), 0x00);
+        assert_eq!(divider_after_video_cycles(&mut mmu, 256), 8);
+
+        // And back to normal speed
+        mmu.write_no_consume_cycles(key1, 0x01);
+        assert!(mmu.try_switch_speed());
+        assert_eq!(mmu.read_no_consume_cycles(key1) & 0x81, 0x00);
+    }
+
+    #[test]
+    fn test_prohibited_area_reads_zero() {
+        let mut mmu = new_mmu(false);
+        assert_eq!(mmu.read(Address::new(0xFEB0)), 0x00);
+
+        // Writes are ignored
+        mmu.write(Address::new(0xFEB0), 0x12);
+        assert_eq!(mmu.read(Address::new(0xFEB0)), 0x00);
+    }
+
+    #[test]
+    fn test_init_ram() {
+        let mut mmu = new_mmu(false);
+        assert_eq!(mmu.read(Address::new(0xC000)), 0x00);
+
+        mmu.init_ram(RamInit::Ones);
+        assert_eq!(mmu.read(Address::new(0xC000)), 0xFF);
+        assert_eq!(mmu.read(Address::new(0xDFFF)), 0xFF);
+        assert_eq!(mmu.read(Address::new(0xFF80)), 0xFF);
+
+        mmu.init_ram(RamInit::Dmg);
+        let wram: Vec<u8> = (0xC000..0xC018).map(|address| mmu.read(Address::new(address))).collect();
+        assert_eq!(wram, [[0x00; 8], [0xFF; 8], [0x00; 8]].concat());
+    }
+
+    #[test]
+    fn test_undocumented_io_writes_are_ignored() {
+        let mut mmu = new_mmu(false);
+        for value in 0..=0xFF {
+            mmu.write(Address::new(0xFF7F), value);
+            // Normally taken after every instruction
+            mmu.take_consumed_cycles();
+        }
+        assert_eq!(mmu.read(Address::new(0xFF7F)), 0xFF);
+    }
+
+    #[test]
+    fn test_lockstep_video_ticks_on_memory_access() {
+        let mut mmu = new_mmu(false);
+        mmu.write(Address::new(0xFF40), 0x80);
+        for _ in 0..500 {
+            mmu.read(Address::new(0xC000));
+            // Normally taken after every instruction
+            mmu.take_consumed_cycles();
+        }
+        // Left for after the instruction
+        assert_eq!(mmu.video().debug_ly(), 0);
+
+        let mut mmu = new_mmu(false);
+        mmu.write(Address::new(0xFF40), 0x80);
+        mmu.set_lockstep_video(true);
+        for _ in 0..500 {
+            mmu.read(Address::new(0xC000));
+            // Normally taken after every instruction
+            mmu.take_consumed_cycles();
+        }
+        // A line is 456 dots
+        assert_eq!(mmu.video().debug_ly(), 1);
+    }
+
+    #[test]
+    fn test_audio_gap_is_unused() {
+        let mut mmu = new_mmu(false);
+        mmu.write(Address::new(0xFF28), 0x12);
+        assert_eq!(mmu.read(Address::new(0xFF28)), 0xFF);
+    }
+
+    #[test]
+    fn test_key1_reads_unmapped_for_dmg() {
+        let mut mmu = new_mmu(false);
+        assert_eq!(mmu.read(Address::new(0xFF4D)), 0xFF);
+
+        let mut mmu = new_mmu(true);
+        assert_eq!(mmu.read(Address::new(0xFF4D)), 0x7E);
+    }
+
+    #[test]
+    fn test_speed_switch_is_inert_for_dmg() {
+        let mut mmu = new_mmu(false);
+
+        mmu.write_no_consume_cycles(Address::new(0xFF4D), 0x01);
+        assert!(!mmu.try_switch_speed());
+        assert_eq!(divider_after_video_cycles(&mut mmu, 256), 4);
+    }
+
+    #[test]
+    fn test_vram_banking() {
+        let mut mmu = new_mmu(true);
+        let vbk = Address::new(0xFF4F);
+        let address = Address::new(0x8123);
+
+        mmu.write(address, 0x11);
+        mmu.write(vbk, 0x01);
+        assert_eq!(mmu.read(vbk), 0xFF);
+        assert_eq!(mmu.read(address), 0x00);
+        mmu.write(address, 0x22);
+
+        mmu.write(vbk, 0x00);
+        assert_eq!(mmu.read(vbk), 0xFE);
+        assert_eq!(mmu.read(address), 0x11);
+        mmu.write(vbk, 0x01);
+        assert_eq!(mmu.read(address), 0x22);
+    }
+
+    #[test]
+    fn test_wram_banking() {
+        let mut mmu = new_mmu(true);
+        let svbk = Address::new(0xFF70);
+        let fixed_address = Address::new(0xC123);
+        let banked_address = Address::new(0xD123);
+
+        for bank in 1..=7 {
+            mmu.write(svbk, bank);
+            mmu.write(banked_address, bank * 0x10);
+            mmu.write(fixed_address, bank);
+        }
+
+        for bank in 1..=7 {
+            mmu.write(svbk, bank);
+            assert_eq!(mmu.read(svbk) & 0b111, bank);
+            assert_eq!(mmu.read(banked_address), bank * 0x10);
+            assert_eq!(mmu.read(fixed_address), 7);
+        }
+
+        // Bank 0 maps to bank 1
+        mmu.write(svbk, 0x00);
+        assert_eq!(mmu.read(banked_address), 0x10);
+    }
+
+    #[test]
+    fn test_banking_is_inert_for_dmg() {
+        let mut mmu = new_mmu(false);
+        let vram_address = Address::new(0x8123);
+        let wram_address = Address::new(0xD123);
+
+        mmu.write(vram_address, 0x11);
+        mmu.write(wram_address, 0x22);
+        mmu.write(Address::new(0xFF4F), 0x01);
+        mmu.write(Address::new(0xFF70), 0x02);
+
+        assert_eq!(mmu.read(Address::new(0xFF4F)), 0xFF);
+        assert_eq!(mmu.read(Address::new(0xFF70)), 0xFF);
+        assert_eq!(mmu.read(vram_address), 0x11);
+        assert_eq!(mmu.read(wram_address), 0x22);
+    }
+
+    #[test]
+    fn test_serial_control() {
+        let mut mmu = new_mmu(false);
+        let serial_control = Address::new(0xFF02);
+        assert_eq!(mmu.read(serial_control), 0x7E);
+
+        mmu.write(serial_control, 0x81);
+        assert_eq!(mmu.read(serial_control), 0xFF);
+
+        mmu.write(serial_control, 0x01);
+        assert_eq!(mmu.read(serial_control), 0x7F);
+
+        // Unused bits can't be cleared
+        mmu.write(serial_control, 0x00);
+        assert_eq!(mmu.read(serial_control), 0x7E);
+
+        // The clock speed bit only exists on CGB
+        mmu.write(serial_control, 0x02);
+        assert_eq!(mmu.read(serial_control), 0x7E);
+    }
+
+    #[test]
+    fn test_serial_control_cgb() {
+        let mut mmu = new_mmu(true);
+        let serial_control = Address::new(0xFF02);
+        assert_eq!(mmu.read(serial_control), 0x7C);
+
+        mmu.write(serial_control, 0x03);
+        assert_eq!(mmu.read(serial_control), 0x7F);
+
+        mmu.write(serial_control, 0x82);
+        assert_eq!(mmu.read(serial_control), 0xFE);
+
+        mmu.write(serial_control, 0x00);
+        assert_eq!(mmu.read(serial_control), 0x7C);
+    }
+
+    #[test]
+    fn test_unmapped_io() {
+        let mut mmu = new_mmu(false);
+        assert_eq!(mmu.read(Address::new(0xFF03)), 0xFF);
+
+        mmu.write(Address::new(0xFF03), 0x12);
+        assert_eq!(mmu.read(Address::new(0xFF03)), 0xFF);
+    }
+
+    #[test]
+    fn test_joypad_both_groups_selected() {
+        let mut joypad = Joypad::new();
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::Up));
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::A));
+
+        joypad.write(0b0010_0000);
+        assert_eq!(joypad.read() & 0x3F, 0b10_1011);
+        joypad.write(0b0001_0000);
+        assert_eq!(joypad.read() & 0x3F, 0b01_1110);
+
+        // Up is on line 2 and A on line 0, and the groups are ANDed together
+        joypad.write(0b0000_0000);
+        assert_eq!(joypad.read() & 0x3F, 0b00_1010);
+
+        joypad.write(0b0011_0000);
+        assert_eq!(joypad.read() & 0x3F, 0b11_1111);
+    }
+
+    #[test]
+    fn test_joypad_socd_policy() {
+        let mut joypad = Joypad::new();
+        joypad.write(0b0010_0000);
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::Up));
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::Down));
+
+        // Up is line 2 and down is line 3
+        assert_eq!(joypad.read() & 0x0F, 0b0011);
+
+        joypad.set_socd_policy(SocdPolicy::Neutral);
+        assert_eq!(joypad.read() & 0x0F, 0b1111);
+
+        joypad.set_socd_policy(SocdPolicy::LastInputPriority);
+        assert_eq!(joypad.read() & 0x0F, 0b0111);
+        // Pressing up again makes it the latest
+        joypad.consume_platform_event(JoypadEvent::new_up(JoypadButton::Up));
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::Up));
+        assert_eq!(joypad.read() & 0x0F, 0b1011);
+
+        // Only a single direction is unaffected by the policy
+        joypad.consume_platform_event(JoypadEvent::new_up(JoypadButton::Up));
+        for policy in [SocdPolicy::AllowBoth, SocdPolicy::Neutral, SocdPolicy::LastInputPriority] {
+            joypad.set_socd_policy(policy);
+            assert_eq!(joypad.read() & 0x0F, 0b0111, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn test_joypad_players_are_separate() {
+        let mut joypad = Joypad::new();
+        joypad.consume_player_event(1, JoypadEvent::new_down(JoypadButton::Up));
+        joypad.consume_player_event(1, JoypadEvent::new_down(JoypadButton::A));
+
+        // The register shows player 0, which has nothing pressed
+        joypad.write(0b0000_0000);
+        assert_eq!(joypad.read() & 0x3F, 0b00_1111);
+
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::Down));
+        assert_eq!(joypad.read() & 0x3F, 0b00_0111);
+    }
+
+    #[test]
+    fn test_joypad_pressed_buttons() {
+        let mut joypad = Joypad::new();
+        assert!(joypad.pressed_buttons().is_empty());
+
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::A));
+        joypad.consume_platform_event(JoypadEvent::new_down(JoypadButton::Up));
+        // Neither group is selected, so the register shows nothing
+        assert_eq!(joypad.read() & 0x0F, 0x0F);
+        assert_eq!(joypad.pressed_buttons(), vec![JoypadButton::Up, JoypadButton::A]);
+
+        joypad.consume_platform_event(JoypadEvent::new_up(JoypadButton::A));
+        assert_eq!(joypad.pressed_buttons(), vec![JoypadButton::Up]);
+    }
+
+    fn step_frame_sequencer(timer: &mut Timer, audio: &mut Audio) {
+        for _ in 0..timer.take_frame_sequencer_ticks() {
+            audio.step_frame_sequencer();
+        }
+    }
+
+    fn tick_timer_with_audio(timer: &mut Timer, audio: &mut Audio, m_cycles: usize) {
+        for _ in 0..m_cycles {
+            timer.maybe_tick_cycles(1);
+            step_frame_sequencer(timer, audio);
+        }
+    }
+
+    fn is_square2_enabled(audio: &Audio) -> bool {
+        get_bit(audio.read(Address::new(0xFF26)), 1)
+    }
+
+    fn trigger_square2_with_length_one(audio: &mut Audio) {
+        // DAC on, length counter of 64 - 63 = 1, trigger with length enabled
+        audio.write(Address::new(0xFF17), 0xF0);
+        audio.write(Address::new(0xFF16), 63);
+        audio.write(Address::new(0xFF19), 0b1100_0000);
+    }
+
+    #[test]
+    fn test_frame_sequencer_follows_divider() {
+        let mut timer = Timer::new();
+        let mut audio = Audio::new();
+        trigger_square2_with_length_one(&mut audio);
+
+        // The first falling edge of bit 12 happens after 8192 T-cycles
+        tick_timer_with_audio(&mut timer, &mut audio, 2047);
+        assert!(is_square2_enabled(&audio));
+        tick_timer_with_audio(&mut timer, &mut audio, 1);
+        assert!(!is_square2_enabled(&audio));
+    }
+
+    #[test]
+    fn test_divider_reset_clocks_frame_sequencer() {
+        let mut timer = Timer::new();
+        let mut audio = Audio::new();
+        trigger_square2_with_length_one(&mut audio);
+
+        // Bit 12 is set after 4096 T-cycles, resetting DIV then causes a falling edge
+        tick_timer_with_audio(&mut timer, &mut audio, 1025);
+        assert!(is_square2_enabled(&audio));
+        timer.write(Address::new(0xFF04), 0x00);
+        step_frame_sequencer(&mut timer, &mut audio);
+        assert!(!is_square2_enabled(&audio));
+
+        // Resetting with bit 12 cleared shouldn't clock anything
+        trigger_square2_with_length_one(&mut audio);
+        tick_timer_with_audio(&mut timer, &mut audio, 1000);
+        timer.write(Address::new(0xFF04), 0x00);
+        step_frame_sequencer(&mut timer, &mut audio);
+        tick_timer_with_audio(&mut timer, &mut audio, 2047);
+        assert!(is_square2_enabled(&audio));
+    }
+
+    #[test]
+    fn test_write_log_is_capped() {
+        let mut mmu = new_mmu(false);
+        mmu.set_write_watch(Some(0xC000..=0xC000));
+        for i in 0..MAX_WRITE_LOG_LEN + 10 {
+            mmu.set_current_pc(i as u16);
+            mmu.write(Address::new(0xC000), 0x42);
+            mmu.take_consumed_cycles();
+        }
+
+        let write_log = mmu.take_write_log();
+        assert_eq!(write_log.len(), MAX_WRITE_LOG_LEN);
+        // The oldest writes were dropped
+        assert_eq!(write_log[0], (10, 0xC000, 0x42));
     }
 }
@@ -1,11 +1,16 @@
 pub mod address;
+pub mod audio;
 pub mod cartridge;
 pub mod cpu;
+pub mod error;
 pub mod header;
 pub mod instruction_decoder;
 pub mod mmu;
 pub mod reference;
+pub mod rewind;
 pub mod video;
 pub mod cycles;
 pub mod utils;
 pub mod gameboy;
+#[cfg(test)]
+mod single_step_tests;
@@ -0,0 +1,99 @@
+use std::collections::VecDeque;
+
+// Ring of snapshots, captured every `interval_frames` frames. Once `capacity` snapshots are
+// held the oldest one is dropped, so memory use is bounded by `capacity` times the size of a
+// snapshot, and at most `interval_frames * capacity` frames can be rewound.
+pub struct RewindBuffer<T> {
+    states: VecDeque<T>,
+    capacity: usize,
+    interval_frames: usize,
+    frames_since_capture: usize,
+    // Whether the newest snapshot was captured on the latest frame, i.e. is what's on screen
+    newest_is_current: bool,
+}
+
+impl<T> RewindBuffer<T> {
+    pub fn new(interval_frames: usize, capacity: usize) -> Self {
+        assert!(interval_frames > 0, "Rewind interval must be at least one frame");
+        assert!(capacity > 0, "Rewind buffer must hold at least one state");
+        Self {
+            states: VecDeque::with_capacity(capacity),
+            capacity,
+            interval_frames,
+            frames_since_capture: 0,
+            newest_is_current: false,
+        }
+    }
+
+    // Called once per frame, returns whether a snapshot should be captured for it
+    pub fn on_frame(&mut self) -> bool {
+        self.frames_since_capture += 1;
+        self.newest_is_current = false;
+        if self.frames_since_capture < self.interval_frames {
+            return false;
+        }
+        self.frames_since_capture = 0;
+        true
+    }
+
+    pub fn push(&mut self, state: T) {
+        if self.states.len() == self.capacity {
+            self.states.pop_front();
+        }
+        self.states.push_back(state);
+        self.newest_is_current = true;
+    }
+
+    // The most recent snapshot, which is removed so the next call goes further back.
+    // A snapshot of the current frame is skipped, as restoring it wouldn't change anything.
+    pub fn pop(&mut self) -> Option<T> {
+        self.frames_since_capture = 0;
+        if std::mem::take(&mut self.newest_is_current) {
+            self.states.pop_back();
+        }
+        self.states.pop_back()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_interval() {
+        let mut buffer: RewindBuffer<u32> = RewindBuffer::new(3, 4);
+        let captured: Vec<bool> = (0..6).map(|_| buffer.on_frame()).collect();
+        assert_eq!(captured, vec![false, false, true, false, false, true]);
+    }
+
+    #[test]
+    fn test_oldest_state_is_dropped() {
+        let mut buffer = RewindBuffer::new(1, 3);
+        for state in 0..5 {
+            buffer.push(state);
+        }
+        buffer.on_frame();
+        assert_eq!(buffer.pop(), Some(4));
+        assert_eq!(buffer.pop(), Some(3));
+        assert_eq!(buffer.pop(), Some(2));
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn test_current_state_is_skipped() {
+        let mut buffer = RewindBuffer::new(1, 4);
+        for state in 0..3 {
+            buffer.on_frame();
+            buffer.push(state);
+        }
+        // State 2 was captured for the frame being shown
+        assert_eq!(buffer.pop(), Some(1));
+        assert_eq!(buffer.pop(), Some(0));
+        assert_eq!(buffer.pop(), None);
+
+        // Once another frame has run the newest state is older than it
+        buffer.push(3);
+        buffer.on_frame();
+        assert_eq!(buffer.pop(), Some(3));
+    }
+}
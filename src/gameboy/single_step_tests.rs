@@ -0,0 +1,123 @@
+// Runner for single instruction test vectors, in the JSON format used by SingleStepTests.
+// https://github.com/SingleStepTests/sm83
+//
+// Each file holds a list of tests with the CPU and RAM state before and after executing
+// exactly one instruction. PC points at the opcode in the initial state. The bus activity
+// per cycle ("cycles") isn't checked, only the resulting state.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use super::address::Address;
+use super::cpu::{Registers, CPU};
+
+const VECTOR_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/test_vectors/sm83");
+
+#[derive(Deserialize)]
+struct TestVector {
+    name: String,
+    initial: State,
+    #[serde(rename = "final")]
+    expected: State,
+}
+
+#[derive(Deserialize)]
+struct State {
+    pc: u16,
+    sp: u16,
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    f: u8,
+    h: u8,
+    l: u8,
+    #[serde(default)]
+    ime: u8,
+    // Pairs of (address, value)
+    ram: Vec<(u16, u8)>,
+}
+
+impl State {
+    fn registers(&self) -> Registers {
+        Registers {
+            pc: self.pc,
+            sp: self.sp,
+            a: self.a,
+            b: self.b,
+            c: self.c,
+            d: self.d,
+            e: self.e,
+            f: self.f,
+            h: self.h,
+            l: self.l,
+            ime: self.ime != 0,
+        }
+    }
+}
+
+fn run_vector(vector: &TestVector) -> Result<(), String> {
+    let mut memory = vec![0x00; 0x10000];
+    for &(address, value) in &vector.initial.ram {
+        memory[address as usize] = value;
+    }
+
+    let mut cpu = CPU::new_for_test(memory);
+    cpu.set_registers(&vector.initial.registers());
+    cpu.tick(None, 0);
+
+    let expected = vector.expected.registers();
+    let actual = cpu.registers();
+    if actual != expected {
+        return Err(format!("{}: expected {:X?}, got {:X?}", vector.name, expected, actual));
+    }
+
+    for &(address, value) in &vector.expected.ram {
+        let actual = cpu.mmu().cartridge().read(Address::new(address));
+        if actual != value {
+            return Err(format!(
+                "{}: expected {:#04X} at {:#06X}, got {:#04X}",
+                vector.name, value, address, actual
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn load_vectors(path: &Path) -> Vec<TestVector> {
+    let content = fs::read_to_string(path)
+        .unwrap_or_else(|err| panic!("Failed to read {}: {}", path.display(), err));
+    serde_json::from_str(&content)
+        .unwrap_or_else(|err| panic!("Failed to parse {}: {}", path.display(), err))
+}
+
+fn vector_files() -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(VECTOR_DIRECTORY)
+        .expect("Failed to read test vector directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn test_single_step_vectors() {
+    let paths = vector_files();
+    assert!(!paths.is_empty(), "No test vectors found in {}", VECTOR_DIRECTORY);
+
+    let mut failures = Vec::new();
+    for path in &paths {
+        for vector in load_vectors(path) {
+            if let Err(err) = run_vector(&vector) {
+                failures.push(err);
+            }
+        }
+    }
+
+    assert!(failures.is_empty(), "{} failing vector(s):\n{}", failures.len(), failures.join("\n"));
+}
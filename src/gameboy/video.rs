@@ -1,3 +1,4 @@
+use crate::common::attachment::Attachment;
 use crate::common::framebuffer::{FrameBuffer, RgbColor};
 
 use super::address::Address;
@@ -11,8 +12,11 @@ const TILE_BYTE_COUNT: u16 = 16;
 const SPRITE_TILE_START: u16 = 0x8000;
 
 const DOTS_PER_MODE2: usize = 80;
+// Shortest possible Mode 3, the actual length depends on the scanline contents
 const DOTS_PER_MODE3: usize = 172;
 const DOTS_PER_MODE0: usize = 204;
+// Mode 3 and Mode 0 share these, so a longer Mode 3 gives a shorter Mode 0
+const DOTS_PER_MODE3_AND_MODE0: usize = DOTS_PER_MODE3 + DOTS_PER_MODE0;
 const DOTS_PER_MODE1_ROW: usize = 456;
 
 #[derive(Debug, PartialEq, Copy, Clone)]
@@ -23,6 +27,7 @@ enum VideoMode {
     Mode1VerticalBlank = 1,
 }
 
+#[derive(Clone)]
 struct LcdStatus {
     data: u8,
     ppu_mode: VideoMode,
@@ -61,13 +66,14 @@ impl LcdStatus {
     }
 
     fn read_as_byte(&self) -> u8 {
-        return self.data | self.ppu_mode as u8;
+        // Bit 7 is unused and always reads as 1
+        return 0b1000_0000 | self.data | self.ppu_mode as u8;
     }
 
     fn write_as_byte(&mut self, value: u8) {
-        // Only bits 3 to 6 are writable
+        // Only bits 3 to 6 are writable, the LYC == LY bit is kept as is
         let masked_value = value & 0b0111_1000;
-        self.data = masked_value;
+        self.data = (self.data & 0b0000_0100) | masked_value;
     }
 }
 
@@ -85,6 +91,7 @@ impl ObjectSize {
     }
 }
 
+#[derive(Clone)]
 struct LcdControl {
     data: u8,
 }
@@ -126,6 +133,7 @@ enum PaletteColor {
     Black = 3,
 }
 
+#[derive(Clone)]
 struct Palette {
     id0: PaletteColor,
     id1: PaletteColor,
@@ -194,6 +202,34 @@ fn to_screen_color(palette_color: PaletteColor) -> RgbColor {
     }
 }
 
+pub fn default_screen_colors() -> [RgbColor; 4] {
+    [
+        to_screen_color(PaletteColor::White),
+        to_screen_color(PaletteColor::LightGray),
+        to_screen_color(PaletteColor::DarkGray),
+        to_screen_color(PaletteColor::Black),
+    ]
+}
+
+// The MMU only routes 0x8000..=0x9FFF and 0xFE00..=0xFE9F here, anything else is a bug there
+fn vram_index(bank: u8, address: Address) -> usize {
+    debug_assert!(
+        (0x8000..=0x9FFF).contains(&address.value()),
+        "VRAM access out of range: {:#06X}",
+        address.value()
+    );
+    bank as usize * VRAM_BANK_SIZE + address.index_value() - 0x8000
+}
+
+fn oam_index(address: Address) -> usize {
+    debug_assert!(
+        (OAM_START..=0xFE9F).contains(&address.value()),
+        "OAM access out of range: {:#06X}",
+        address.value()
+    );
+    address.index_value() - OAM_START as usize
+}
+
 struct SpriteObject {
     y_pos: u8,
     x_pos: u8,
@@ -210,20 +246,21 @@ enum SpritePalette {
 impl SpriteObject {
     fn resolve_row_in_sprite(&self, line: u8, size: &ObjectSize) -> Option<u8> {
         // Y = Object’s vertical position on the screen + 16.
-        let line_with_offset = line + 16;
+        let line_with_offset = line as u16 + 16;
+        let y_pos = self.y_pos as u16;
 
-        if line_with_offset < self.y_pos {
+        if line_with_offset < y_pos {
             return None;
         }
 
-        if line_with_offset >= self.y_pos + size.height_pixels() {
+        if line_with_offset >= y_pos + size.height_pixels() as u16 {
             return None;
         }
 
-        let row_in_sprite = line_with_offset - self.y_pos;
+        let row_in_sprite = (line_with_offset - y_pos) as u8;
 
         return Some(if self.y_flip() {
-            size.height_pixels() - row_in_sprite
+            size.height_pixels() - 1 - row_in_sprite
         } else {
             row_in_sprite
         });
@@ -250,8 +287,16 @@ impl SpriteObject {
     }
 }
 
+const VRAM_BANK_SIZE: usize = 0x2000;
+
+// Called with the line number and its pixels after each scanline is drawn
+pub type ScanlineHook = Box<dyn FnMut(u8, &[RgbColor])>;
+
+#[derive(Clone)]
 pub struct Video {
     vram: Vec<u8>,
+    // Only bank 0 is used in DMG mode, see MMU
+    vram_bank: u8,
     oam: Vec<u8>,
     lyc: u8,
 
@@ -262,16 +307,39 @@ pub struct Video {
     bg_palette: Palette,
     obj_palette_0: Palette,
     obj_palette_1: Palette,
+    // The final colors for each shade, from white to black. These can be overridden by
+    // the frontend, unlike the palettes above which are set by the game.
+    bg_screen_colors: [RgbColor; 4],
+    obj_screen_colors: [RgbColor; 4],
     window_y: u8,
     window_x: u8,
     current_line: u8,
 
     // internal
     dot_in_current_mode: usize,
-    frame_buffer: FrameBuffer,
+    mode3_length: usize,
+    // The window keeps its own line counter, which only advances on lines where it's drawn
+    window_line: u8,
+    // Raw BG/window color index (0-3) of each pixel on the current line, before the palette.
+    // Sprites behind the background are only hidden by indices 1-3.
+    bg_priority: [u8; SCREEN_WIDTH as usize],
+    // Scanlines are drawn to the back buffer, which is presented
+    // through the front buffer once the frame is complete.
+    back_buffer: FrameBuffer,
+    front_buffer: FrameBuffer,
     is_frame_ready: bool,
+    // The LCD needs a frame to stabilize after being turned on, which isn't shown
+    blank_next_frame: bool,
+    // Only every (frame_skip + 1)th frame is drawn, the others keep showing the last one.
+    // Timing is unaffected, as drawing a scanline has no side effects besides the pixels.
+    frame_skip: usize,
+    frames_until_drawn: usize,
+
+    // Debug
+    on_scanline: Attachment<ScanlineHook>,
 }
 
+#[derive(Debug, PartialEq)]
 pub enum VideoInterrupt {
     Stat,
     VBlank,
@@ -280,7 +348,8 @@ pub enum VideoInterrupt {
 impl Video {
     pub fn new() -> Self {
         Self {
-            vram: vec![0x00; 0x4000],
+            vram: vec![0x00; 2 * VRAM_BANK_SIZE],
+            vram_bank: 0,
             oam: vec![0x00; 0xA0],
             lcd_status: LcdStatus::new(),
             lcd_control: LcdControl::new(),
@@ -290,13 +359,23 @@ impl Video {
             bg_palette: Palette::new(),
             obj_palette_0: Palette::new(),
             obj_palette_1: Palette::new(),
+            bg_screen_colors: default_screen_colors(),
+            obj_screen_colors: default_screen_colors(),
             window_y: 0,
             window_x: 0,
             current_line: 0,
 
             dot_in_current_mode: 0,
-            frame_buffer: FrameBuffer::new(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize),
-            is_frame_ready: true,
+            mode3_length: DOTS_PER_MODE3,
+            window_line: 0,
+            bg_priority: [0; SCREEN_WIDTH as usize],
+            back_buffer: FrameBuffer::new(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize),
+            front_buffer: FrameBuffer::new(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize),
+            is_frame_ready: false,
+            blank_next_frame: false,
+            frame_skip: 0,
+            frames_until_drawn: 0,
+            on_scanline: Attachment::new(None),
         }
     }
 
@@ -305,36 +384,66 @@ impl Video {
             return None;
         }
         self.is_frame_ready = false;
-        return Some(&self.frame_buffer);
+        return Some(&self.front_buffer);
+    }
+
+    pub fn frame_buffer(&self) -> &FrameBuffer {
+        &self.front_buffer
+    }
+
+    pub fn set_bg_screen_colors(&mut self, colors: [RgbColor; 4]) {
+        self.bg_screen_colors = colors;
+    }
+
+    pub fn set_obj_screen_colors(&mut self, colors: [RgbColor; 4]) {
+        self.obj_screen_colors = colors;
+    }
+
+    pub fn set_frame_skip(&mut self, frame_skip: usize) {
+        self.frame_skip = frame_skip;
+        self.frames_until_drawn = 0;
+    }
+
+    pub fn set_scanline_hook(&mut self, hook: Option<ScanlineHook>) {
+        *self.on_scanline = hook;
+    }
+
+    pub fn take_scanline_hook(&mut self) -> Option<ScanlineHook> {
+        self.on_scanline.take()
+    }
+
+    // Debug accessors which don't go through the memory map
+    pub fn debug_mode(&self) -> u8 {
+        self.lcd_status.get_ppu_mode() as u8
+    }
+
+    pub fn debug_ly(&self) -> u8 {
+        self.current_line
     }
 
     pub fn tick(&mut self) -> Vec<VideoInterrupt> {
         self.dot_in_current_mode += 1;
 
         let mut interrupts: Vec<VideoInterrupt> = vec![];
+        let previous_line = self.current_line;
 
         let maybe_next_mode = match self.lcd_status.get_ppu_mode() {
             VideoMode::Mode2OamScan if self.dot_in_current_mode >= DOTS_PER_MODE2 => {
                 self.dot_in_current_mode = 0;
+                self.mode3_length = self.compute_mode3_length(self.current_line);
                 Some(VideoMode::Mode3DrawPixels)
             }
 
-            VideoMode::Mode3DrawPixels if self.dot_in_current_mode >= DOTS_PER_MODE3 => {
+            VideoMode::Mode3DrawPixels if self.dot_in_current_mode >= self.mode3_length => {
                 self.dot_in_current_mode = 0;
                 self.draw_scanline(self.current_line);
                 Some(VideoMode::Mode0HorizontalBlank)
             }
 
-            VideoMode::Mode0HorizontalBlank if self.dot_in_current_mode >= DOTS_PER_MODE0 => {
+            VideoMode::Mode0HorizontalBlank if self.dot_in_current_mode >= self.mode0_length() => {
                 self.dot_in_current_mode = 0;
                 self.current_line += 1;
 
-                if self.current_line == self.lyc
-                    && self.lcd_status.get_field(LcdStatusBit::LycIntSelect)
-                {
-                    interrupts.push(VideoInterrupt::Stat);
-                }
-
                 if self.current_line > 143 {
                     Some(VideoMode::Mode1VerticalBlank)
                 } else {
@@ -347,8 +456,22 @@ impl Video {
                 self.current_line += 1;
 
                 if self.current_line > 153 {
+                    // The screen goes blank while the LCD is disabled, but frames are still
+                    // presented at the normal rate so the frontend doesn't freeze on the last one.
+                    if self.frames_until_drawn > 0 {
+                        self.frames_until_drawn -= 1;
+                    } else {
+                        if self.lcd_control.get_field(LcdControlBit::LcdEnable) && !self.blank_next_frame {
+                            self.front_buffer.copy_from(&self.back_buffer);
+                        } else {
+                            self.front_buffer.fill(self.bg_screen_colors[PaletteColor::White as usize]);
+                        }
+                        self.frames_until_drawn = self.frame_skip;
+                    }
+                    self.blank_next_frame = false;
                     self.is_frame_ready = true;
                     self.current_line = 0;
+                    self.window_line = 0;
                     Some(VideoMode::Mode2OamScan)
                 } else {
                     None
@@ -361,6 +484,14 @@ impl Video {
         self.lcd_status
             .set_lyc_condition(self.current_line == self.lyc);
 
+        // LY passes through the VBlank lines as well, and wraps around to 0
+        if self.current_line != previous_line
+            && self.current_line == self.lyc
+            && self.lcd_status.get_field(LcdStatusBit::LycIntSelect)
+        {
+            interrupts.push(VideoInterrupt::Stat);
+        }
+
         if let Some(next_mode) = maybe_next_mode {
             self.lcd_status.set_ppu_mode(next_mode);
 
@@ -386,6 +517,8 @@ impl Video {
                 }
 
                 VideoMode::Mode1VerticalBlank => {
+                    // Only entered from line 143, so once per frame. The VBlank interrupt is
+                    // always requested, the STAT one only if the game selected mode 1 as a source.
                     interrupts.push(VideoInterrupt::VBlank);
                     if self.lcd_status.get_field(LcdStatusBit::Mode1IntSelect) {
                         interrupts.push(VideoInterrupt::Stat);
@@ -397,24 +530,119 @@ impl Video {
         return interrupts;
     }
 
+    // Same as calling tick for each dot, but skips straight to the next possible mode
+    // transition since nothing observable happens in between.
+    pub fn tick_many(&mut self, dots: usize) -> Vec<VideoInterrupt> {
+        let mut interrupts = vec![];
+        let mut remaining_dots = dots;
+
+        while remaining_dots > 0 {
+            // Leave the transition itself, and the last dot, to tick
+            let dots_until_transition = self.current_mode_length().saturating_sub(self.dot_in_current_mode + 1);
+            let skipped_dots = dots_until_transition.min(remaining_dots - 1);
+            self.dot_in_current_mode += skipped_dots;
+
+            interrupts.extend(self.tick());
+            remaining_dots -= skipped_dots + 1;
+        }
+
+        interrupts
+    }
+
+    fn current_mode_length(&self) -> usize {
+        match self.lcd_status.get_ppu_mode() {
+            VideoMode::Mode2OamScan => DOTS_PER_MODE2,
+            VideoMode::Mode3DrawPixels => self.mode3_length,
+            VideoMode::Mode0HorizontalBlank => self.mode0_length(),
+            VideoMode::Mode1VerticalBlank => DOTS_PER_MODE1_ROW,
+        }
+    }
+
+    fn mode0_length(&self) -> usize {
+        DOTS_PER_MODE3_AND_MODE0 - self.mode3_length
+    }
+
+    // Mode 3 is extended by fine scrolling, the window and sprites on the line.
+    // https://gbdev.io/pandocs/Rendering.html#mode-3-length
+    fn compute_mode3_length(&self, line: u8) -> usize {
+        // The fetcher discards SCX % 8 pixels at the start of the line
+        let mut length = DOTS_PER_MODE3 + (self.scx % 8) as usize;
+
+        let is_window_on_line = self.lcd_control.get_field(LcdControlBit::BgWindowEnable)
+            && self.lcd_control.get_field(LcdControlBit::WindowEnable)
+            && line >= self.window_y
+            && self.window_x < SCREEN_WIDTH + 7;
+        if is_window_on_line {
+            length += 6;
+        }
+
+        if self.lcd_control.get_field(LcdControlBit::ObjEnable) {
+            let sprite_size = self.lcd_control.get_object_size();
+            // At most 10 objects are selected for a line, so there's no need to allocate
+            let mut x_positions = [0u8; 10];
+            let mut sprite_count = 0;
+            for (sprite, _) in self.select_sprites_for_line(line, &sprite_size) {
+                x_positions[sprite_count] = sprite.x_pos;
+                sprite_count += 1;
+            }
+            let x_positions = &mut x_positions[..sprite_count];
+            x_positions.sort_unstable();
+
+            // TODO: The window resets the background tiles considered here
+            // One bit per background tile, of which there are at most (255 + 255 - 8) / 8 + 1 = 63
+            let mut penalized_tiles: u64 = 0;
+            for &x_pos in x_positions.iter() {
+                if x_pos == 0 {
+                    length += 11;
+                    continue;
+                }
+                // Each object fetch takes 6 dots, and the first object in each background
+                // tile also waits for the background fetch, depending on where in the tile it is.
+                // Objects partially off the left edge count as being in the first tile.
+                length += 6;
+                let x_in_bg = (x_pos as usize + self.scx as usize).saturating_sub(8);
+                let tile_bit = 1 << (x_in_bg / 8);
+                if penalized_tiles & tile_bit == 0 {
+                    penalized_tiles |= tile_bit;
+                    let pixels_right_of_object = 7 - x_in_bg % 8;
+                    length += pixels_right_of_object.saturating_sub(2);
+                }
+            }
+        }
+
+        length
+    }
+
     pub fn write_vram(&mut self, address: Address, value: u8) {
-        let index = address.index_value() - 0x8000;
+        let index = vram_index(self.vram_bank, address);
         self.vram[index] = value;
     }
 
     pub fn read_vram(&self, address: Address) -> u8 {
-        let index = address.index_value() - 0x8000;
-        self.vram[index]
+        self.vram[vram_index(self.vram_bank, address)]
+    }
+
+    // The PPU reads tile data from bank 0 regardless of the selected bank.
+    // TODO: CGB BG map attributes can select tile data from bank 1.
+    fn read_vram_for_ppu(&self, address: Address) -> u8 {
+        self.vram[vram_index(0, address)]
+    }
+
+    // https://gbdev.io/pandocs/CGB_Registers.html#ff4f--vbk-cgb-mode-only-vram-bank
+    pub fn read_vram_bank(&self) -> u8 {
+        0xFE | self.vram_bank
+    }
+
+    pub fn write_vram_bank(&mut self, value: u8) {
+        self.vram_bank = value & 1;
     }
 
     pub fn write_oam(&mut self, address: Address, value: u8) {
-        let index = address.index_value() - 0xFE00;
-        self.oam[index] = value;
+        self.oam[oam_index(address)] = value;
     }
 
     pub fn read_oam(&self, address: Address) -> u8 {
-        let index = address.index_value() - 0xFE00;
-        self.oam[index]
+        self.oam[oam_index(address)]
     }
 
     pub fn read_register(&self, address: Address) -> u8 {
@@ -443,7 +671,13 @@ impl Video {
 
     pub fn write_register(&mut self, address: Address, value: u8) {
         match address.value() {
-            0xFF40 => self.lcd_control.data = value,
+            0xFF40 => {
+                let was_enabled = self.lcd_control.get_field(LcdControlBit::LcdEnable);
+                self.lcd_control.data = value;
+                if !was_enabled && self.lcd_control.get_field(LcdControlBit::LcdEnable) {
+                    self.blank_next_frame = true;
+                }
+            }
             0xFF41 => self.lcd_status.write_as_byte(value),
             0xFF42 => self.scy = value,
             0xFF43 => self.scx = value,
@@ -460,42 +694,76 @@ impl Video {
     }
 
     fn draw_scanline(&mut self, line: u8) {
-        if !self.lcd_control.get_field(LcdControlBit::LcdEnable) {
+        if !self.lcd_control.get_field(LcdControlBit::LcdEnable) || self.frames_until_drawn > 0 {
             return;
         }
 
         if self.lcd_control.get_field(LcdControlBit::BgWindowEnable) {
             self.draw_bg_for_current_line(line);
             if self.lcd_control.get_field(LcdControlBit::WindowEnable) {
-                self.draw_window_for_current_line();
+                self.draw_window_for_current_line(line);
             }
+        } else {
+            // On DMG, background and window become blank (white) when bit 0 is cleared.
+            // https://gbdev.io/pandocs/LCDC.html#non-cgb-mode-dmg-sgb-and-cgb-in-compatibility-mode-bg-and-window-display
+            self.clear_line(line);
         }
 
         if self.lcd_control.get_field(LcdControlBit::ObjEnable) {
             self.draw_sprites_for_current_line(line);
         }
+
+        if let Some(hook) = self.on_scanline.as_mut() {
+            hook(line, self.back_buffer.row(line as usize));
+        }
+    }
+
+    fn clear_line(&mut self, line: u8) {
+        self.bg_priority.fill(0);
+        self.back_buffer
+            .row_mut(line as usize)
+            .fill(self.bg_screen_colors[PaletteColor::White as usize]);
     }
 
     fn draw_bg_for_current_line(&mut self, line: u8) {
         let y = line;
+        let mut row = [self.bg_screen_colors[PaletteColor::White as usize]; SCREEN_WIDTH as usize];
+
+        let tile_map_start_addr = self.resolve_tile_map_start_addr(LcdControlBit::BgTileMapArea);
 
         for x in 0..SCREEN_WIDTH {
-            let tile_index = self.resolve_tile_index(x, y);
-            let tile_start_addr = self.resolve_tile_addr(tile_index);
+            let map_x = self.scx.wrapping_add(x);
+            let map_y = self.scy.wrapping_add(y);
+            let color_id = self.read_tile_map_color_id(tile_map_start_addr, map_x, map_y);
+            self.bg_priority[x as usize] = color_id;
+            row[x as usize] = self.bg_screen_colors[self.bg_palette.resolve_for_bg_from_color_id(color_id) as usize];
+        }
+
+        self.back_buffer.row_mut(y as usize).copy_from_slice(&row);
+    }
 
-            let x_in_tile = self.scx.wrapping_add(x) % 8;
-            let y_in_tile = self.scy.wrapping_add(y) % 8;
-            let tile_row_byte_count: u16 = 2;
-            let tile_row_addr =
-                Address::new(tile_start_addr.value() + (y_in_tile as u16) * tile_row_byte_count);
+    // https://gbdev.io/pandocs/Window.html
+    fn draw_window_for_current_line(&mut self, line: u8) {
+        // WX is the window's horizontal position on the screen + 7
+        if line < self.window_y || self.window_x >= SCREEN_WIDTH + 7 {
+            return;
+        }
 
-            let color = self.read_bg_tile_pixel_color(tile_row_addr, x_in_tile, &self.bg_palette);
-            self.frame_buffer.set_pixel(x, y, to_screen_color(color));
+        // The window uses its own tile map, but shares the tile data area with the background
+        let tile_map_start_addr = self.resolve_tile_map_start_addr(LcdControlBit::WindowTileMapArea);
+        let window_start_x = self.window_x as i16 - 7;
+        let map_y = self.window_line;
+
+        let first_x = window_start_x.max(0) as u8;
+        for x in first_x..SCREEN_WIDTH {
+            let map_x = (x as i16 - window_start_x) as u8;
+            let color_id = self.read_tile_map_color_id(tile_map_start_addr, map_x, map_y);
+            self.bg_priority[x as usize] = color_id;
+            let color = self.bg_screen_colors[self.bg_palette.resolve_for_bg_from_color_id(color_id) as usize];
+            self.back_buffer.set_pixel(x, line, color);
         }
-    }
 
-    fn draw_window_for_current_line(&mut self) {
-        println!("TODO: Draw window!");
+        self.window_line += 1;
     }
 
     fn draw_sprites_for_current_line(&mut self, line: u8) {
@@ -506,26 +774,18 @@ impl Video {
 
         let sprite_size = self.lcd_control.get_object_size();
 
-        let mut visible_sprites_with_row = (0..40)
-            .map(|index| self.read_sprite_object(index))
-            .filter_map(|sprite| {
-                if let Some(row_in_sprite) = sprite.resolve_row_in_sprite(line, &sprite_size) {
-                    return Some((sprite, row_in_sprite));
-                }
-                return None;
-            })
-            .collect::<Vec<_>>();
+        let mut visible_sprites_with_row = self.select_sprites_for_line(line, &sprite_size);
 
-        // Sprites with the lowest X position should be drawn first,
+        // Sprites with the lowest X position have priority,
         // if the X position is the same then index is used.
         visible_sprites_with_row.sort_by_key(|(sprite, _)| (sprite.x_pos, sprite.index));
 
-        // Because of a limitation of hardware, only ten objects can be displayed per scanline.
-        visible_sprites_with_row.truncate(10);
+        // Sprites are drawn in priority order, so a pixel that is already taken by
+        // a sprite can't be drawn over by the following lower priority sprites.
+        let mut is_sprite_pixel = [false; SCREEN_WIDTH as usize];
 
-        // TODO: Do this in reverse?
         for (sprite, row_in_sprite) in visible_sprites_with_row {
-            let sprite_row_start_addr = self.resolve_sprite_row_addr(&sprite, row_in_sprite);
+            let sprite_row_start_addr = self.resolve_sprite_row_addr(&sprite, row_in_sprite, &sprite_size);
 
             // From pandocs:
             // X = Object’s horizontal position on the screen + 8.
@@ -552,25 +812,49 @@ impl Video {
                 };
 
                 let maybe_color = palette.resolve_for_sprite_from_color_id(color_id);
-                if maybe_color.is_none() {
+                if maybe_color.is_none() || is_sprite_pixel[x_on_screen as usize] {
                     continue;
                 }
+                // This holds even if the pixel ends up behind the background
+                is_sprite_pixel[x_on_screen as usize] = true;
 
                 // Pandocs:
                 // Priority: 0 = No, 1 = BG and Window colors 1–3 are drawn over this OBJ
                 let bg_has_priority = sprite.priority();
-                if !bg_has_priority || self.frame_buffer.get_pixel(x_on_screen as usize, line as usize) == to_screen_color(PaletteColor::White) {
-                    self.frame_buffer.set_pixel(x_on_screen, line, to_screen_color(maybe_color.unwrap()));
+                if !bg_has_priority || self.bg_priority[x_on_screen as usize] == 0 {
+                    let color = self.obj_screen_colors[maybe_color.unwrap() as usize];
+                    self.back_buffer.set_pixel(x_on_screen, line, color);
                 }
             }
         }
     }
 
-    fn resolve_sprite_row_addr(&self, sprite: &SpriteObject, row: u8) -> Address {
+    fn select_sprites_for_line(&self, line: u8, sprite_size: &ObjectSize) -> Vec<(SpriteObject, u8)> {
+        // Because of a limitation of hardware, only ten objects can be displayed per scanline.
+        // These are the first ten found during the OAM scan, i.e. by OAM index regardless of X.
+        (0..40)
+            .map(|index| self.read_sprite_object(index))
+            .filter_map(|sprite| {
+                let row_in_sprite = sprite.resolve_row_in_sprite(line, sprite_size)?;
+                Some((sprite, row_in_sprite))
+            })
+            .take(10)
+            .collect()
+    }
+
+    fn resolve_sprite_row_addr(&self, sprite: &SpriteObject, row: u8, size: &ObjectSize) -> Address {
+        // For 8x16 objects the top tile is "NN & $FE" and the bottom tile is "NN | $01",
+        // so rows 8-15 continue into the following tile.
+        // https://gbdev.io/pandocs/OAM.html#byte-2--tile-index
+        let tile_index = match size {
+            ObjectSize::Size8x8 => sprite.tile_index,
+            ObjectSize::Size8x16 => sprite.tile_index & 0xFE,
+        };
+
         let row_byte_count = 2;
         Address::new(
             SPRITE_TILE_START
-                + (sprite.tile_index as u16) * TILE_BYTE_COUNT
+                + (tile_index as u16) * TILE_BYTE_COUNT
                 + (row as u16) * row_byte_count,
         )
     }
@@ -592,25 +876,37 @@ impl Video {
         }
     }
 
-    fn resolve_tile_index(&self, x: u8, y: u8) -> u8 {
-        // Background map is 256x256 pixels, i.e. 32x32 tiles (tiles are 8x8 pixel)
-
-        let scrolled_x = self.scx.wrapping_add(x);
-        let scrolled_y = self.scy.wrapping_add(y);
-
-        let tile_x = scrolled_x / 8;
-        let tile_y = scrolled_y / 8;
-
-        let tile_addr_offset = (tile_y as u16) * 32 + tile_x as u16;
-
-        let tile_map_start_addr: u16 = if self.lcd_control.get_field(LcdControlBit::BgTileMapArea) {
+    // The background and the window each have a bit selecting their tile map
+    fn resolve_tile_map_start_addr(&self, tile_map_area: LcdControlBit) -> u16 {
+        if self.lcd_control.get_field(tile_map_area) {
             0x9C00
         } else {
             0x9800
-        };
+        }
+    }
+
+    fn resolve_tile_index(&self, tile_map_start_addr: u16, map_x: u8, map_y: u8) -> u8 {
+        // Tile maps are 256x256 pixels, i.e. 32x32 tiles (tiles are 8x8 pixel)
+        let tile_x = map_x / 8;
+        let tile_y = map_y / 8;
+
+        let tile_addr_offset = (tile_y as u16) * 32 + tile_x as u16;
 
         let tile_index_addr = Address::new(tile_map_start_addr + tile_addr_offset);
-        return self.read_vram(tile_index_addr);
+        return self.read_vram_for_ppu(tile_index_addr);
+    }
+
+    fn read_tile_map_color_id(&self, tile_map_start_addr: u16, map_x: u8, map_y: u8) -> u8 {
+        let tile_index = self.resolve_tile_index(tile_map_start_addr, map_x, map_y);
+        let tile_start_addr = self.resolve_tile_addr(tile_index);
+
+        let x_in_tile = map_x % 8;
+        let y_in_tile = map_y % 8;
+        let tile_row_byte_count: u16 = 2;
+        let tile_row_addr =
+            Address::new(tile_start_addr.value() + (y_in_tile as u16) * tile_row_byte_count);
+
+        self.read_color_id(tile_row_addr, x_in_tile)
     }
 
     fn resolve_tile_addr(&self, tile_index: u8) -> Address {
@@ -630,8 +926,8 @@ impl Video {
 
     fn read_color_id(&self, tile_row_addr: Address, x_in_tile: u8) -> u8 {
         assert!(x_in_tile < 8);
-        let first_byte = self.read_vram(tile_row_addr);
-        let second_byte = self.read_vram(tile_row_addr.next());
+        let first_byte = self.read_vram_for_ppu(tile_row_addr);
+        let second_byte = self.read_vram_for_ppu(tile_row_addr.next());
 
         let ls_bit_color_id = if get_bit(first_byte, 7 - x_in_tile) {
             1
@@ -646,14 +942,605 @@ impl Video {
 
         return ms_bit_color_id << 1 | ls_bit_color_id;
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LCD_ON_WITH_BG_AND_OBJ: u8 = 0b1001_0011;
+    const IDENTITY_PALETTE: u8 = 0b1110_0100;
+
+    // As if the LCD was already on, so the first frame isn't blanked
+    fn new_video(lcd_control: u8) -> Video {
+        let mut video = Video::new();
+        video.lcd_control.data = lcd_control;
+        video.write_register(Address::new(0xFF47), IDENTITY_PALETTE);
+        video.write_register(Address::new(0xFF48), IDENTITY_PALETTE);
+        video
+    }
+
+    // Fills all rows of the tile with the same color ID
+    fn write_solid_tile(video: &mut Video, tile_index: u8, color_id: u8) {
+        for row in 0..8 {
+            write_tile_row(video, tile_index, row, color_id);
+        }
+    }
+
+    fn write_tile_row(video: &mut Video, tile_index: u8, row: u16, color_id: u8) {
+        let low = if get_bit(color_id, 0) { 0xFF } else { 0x00 };
+        let high = if get_bit(color_id, 1) { 0xFF } else { 0x00 };
+        let row_addr = Address::new(0x8000 + tile_index as u16 * TILE_BYTE_COUNT + row * 2);
+        video.write_vram(row_addr, low);
+        video.write_vram(row_addr.next(), high);
+    }
+
+    fn write_sprite(video: &mut Video, index: u8, y_pos: u8, x_pos: u8, tile_index: u8, attributes: u8) {
+        let oam_addr = Address::new(OAM_START + index as u16 * 4);
+        video.write_oam(oam_addr, y_pos);
+        video.write_oam(oam_addr.plus(1), x_pos);
+        video.write_oam(oam_addr.plus(2), tile_index);
+        video.write_oam(oam_addr.plus(3), attributes);
+    }
+
+    fn drawn_pixel(video: &Video, x: usize, y: usize) -> RgbColor {
+        video.back_buffer.get_pixel(x, y)
+    }
+
+    fn tick_until_frame_ready(video: &mut Video) {
+        while !video.is_frame_ready {
+            video.tick();
+        }
+    }
+
+    #[test]
+    fn test_bg_window_enable_blanks_background() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        // The whole background map uses tile 0
+        write_solid_tile(&mut video, 0, 3);
+        write_solid_tile(&mut video, 1, 1);
+        // Sprite on the top left corner of the screen
+        write_sprite(&mut video, 0, 16, 8, 1, 0);
+
+        video.draw_scanline(0);
+        assert_eq!(drawn_pixel(&video, 20, 0), to_screen_color(PaletteColor::Black));
+        assert_eq!(drawn_pixel(&video, 0, 0), to_screen_color(PaletteColor::LightGray));
+
+        video.write_register(Address::new(0xFF40), LCD_ON_WITH_BG_AND_OBJ & !0b1);
+        video.draw_scanline(0);
+        assert_eq!(drawn_pixel(&video, 20, 0), to_screen_color(PaletteColor::White));
+        assert_eq!(drawn_pixel(&video, 0, 0), to_screen_color(PaletteColor::LightGray));
+    }
+
+    #[test]
+    fn test_taken_frame_is_complete() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        write_solid_tile(&mut video, 0, 3);
+        let black = to_screen_color(PaletteColor::Black);
+        let white = to_screen_color(PaletteColor::White);
+
+        tick_until_frame_ready(&mut video);
+
+        // Change the background halfway through the next frame
+        for _ in 0..(456 * 10) {
+            video.tick();
+        }
+        write_solid_tile(&mut video, 0, 0);
+        for _ in 0..(456 * 100) {
+            video.tick();
+        }
+        assert_eq!(drawn_pixel(&video, 0, 0), black);
+        assert_eq!(drawn_pixel(&video, 0, 100), white);
+
+        // The taken frame should only contain the previous complete frame
+        let frame = video.try_take_frame().unwrap();
+        assert_eq!(frame.get_pixel(0, 0), black);
+        assert_eq!(frame.get_pixel(0, 100), black);
+
+        tick_until_frame_ready(&mut video);
+        let frame = video.try_take_frame().unwrap();
+        assert_eq!(frame.get_pixel(0, 0), black);
+        assert_eq!(frame.get_pixel(0, 100), white);
+    }
+
+    #[test]
+    fn test_vram_and_oam_access() {
+        let mut video = Video::new();
+        video.write_vram(Address::new(0x9FFF), 0x12);
+        assert_eq!(video.read_vram(Address::new(0x9FFF)), 0x12);
+        video.write_oam(Address::new(0xFE9F), 0x34);
+        assert_eq!(video.read_oam(Address::new(0xFE9F)), 0x34);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "VRAM access out of range: 0x7FFF")]
+    fn test_vram_access_out_of_range() {
+        Video::new().read_vram(Address::new(0x7FFF));
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "OAM access out of range: 0xFEA0")]
+    fn test_oam_access_out_of_range() {
+        Video::new().write_oam(Address::new(0xFEA0), 0x00);
+    }
+
+    #[test]
+    fn test_screen_color_override() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        // Tiles 0-3 use the color ID of their index, laid out left to right
+        for tile_index in 0..4 {
+            write_solid_tile(&mut video, tile_index, tile_index);
+            video.write_vram(Address::new(0x9800 + tile_index as u16), tile_index);
+        }
+        write_solid_tile(&mut video, 4, 3);
+        write_sprite(&mut video, 0, 16, 40, 4, 0);
+
+        let colors = [
+            RgbColor { r: 0xE0, g: 0xF8, b: 0xD0 },
+            RgbColor { r: 0x88, g: 0xC0, b: 0x70 },
+            RgbColor { r: 0x34, g: 0x68, b: 0x56 },
+            RgbColor { r: 0x08, g: 0x18, b: 0x20 },
+        ];
+        let obj_color = RgbColor { r: 0xFF, g: 0x00, b: 0x00 };
+        video.set_bg_screen_colors(colors);
+        video.set_obj_screen_colors([obj_color; 4]);
+        video.draw_scanline(0);
+
+        for (tile_index, &color) in colors.iter().enumerate() {
+            assert_eq!(drawn_pixel(&video, tile_index * 8, 0), color);
+        }
+        assert_eq!(drawn_pixel(&video, 32, 0), obj_color);
+    }
+
+    #[test]
+    fn test_scanline_hook() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        write_solid_tile(&mut video, 0, 3);
+        let black = to_screen_color(PaletteColor::Black);
+
+        let lines = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let hook_lines = lines.clone();
+        video.set_scanline_hook(Some(Box::new(move |line: u8, pixels: &[RgbColor]| {
+            assert_eq!(pixels.len(), SCREEN_WIDTH as usize);
+            assert!(pixels.iter().all(|&pixel| pixel == black));
+            hook_lines.borrow_mut().push(line);
+        })));
+
+        tick_until_frame_ready(&mut video);
+
+        let expected: Vec<u8> = (0..SCREEN_HEIGHT).collect();
+        assert_eq!(*lines.borrow(), expected);
+    }
+
+    #[test]
+    fn test_disabled_lcd_gives_white_frames() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        write_solid_tile(&mut video, 0, 3);
+        let black = to_screen_color(PaletteColor::Black);
+
+        video.try_take_frame();
+        tick_until_frame_ready(&mut video);
+        let frame = video.try_take_frame().unwrap();
+        assert!(frame.pixels().iter().all(|&pixel| pixel == black));
+
+        video.write_register(Address::new(0xFF40), LCD_ON_WITH_BG_AND_OBJ & !0b1000_0000);
+        tick_until_frame_ready(&mut video);
+        let frame = video.try_take_frame().unwrap();
+        assert!(frame.pixels().iter().all(|&pixel| pixel == RgbColor::white()));
+    }
+
+    #[test]
+    fn test_first_frame_after_enable_is_blank() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ & !0b1000_0000);
+        write_solid_tile(&mut video, 0, 3);
+        let black = to_screen_color(PaletteColor::Black);
+        video.try_take_frame();
+
+        video.write_register(Address::new(0xFF40), LCD_ON_WITH_BG_AND_OBJ);
+        tick_until_frame_ready(&mut video);
+        let frame = video.try_take_frame().unwrap();
+        assert!(frame.pixels().iter().all(|&pixel| pixel == RgbColor::white()));
+
+        tick_until_frame_ready(&mut video);
+        let frame = video.try_take_frame().unwrap();
+        assert!(frame.pixels().iter().all(|&pixel| pixel == black));
+
+        // Changing other bits while the LCD stays on doesn't blank anything
+        video.write_register(Address::new(0xFF40), LCD_ON_WITH_BG_AND_OBJ);
+        tick_until_frame_ready(&mut video);
+        let frame = video.try_take_frame().unwrap();
+        assert!(frame.pixels().iter().all(|&pixel| pixel == black));
+    }
+
+    fn sprite_column(video: &mut Video, rows: u8) -> Vec<RgbColor> {
+        (0..rows)
+            .map(|line| {
+                video.draw_scanline(line);
+                drawn_pixel(video, 0, line as usize)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_8x16_sprite() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ | 0b100);
+        let light_gray = to_screen_color(PaletteColor::LightGray);
+        let dark_gray = to_screen_color(PaletteColor::DarkGray);
+        let black = to_screen_color(PaletteColor::Black);
+
+        // Top tile with a distinct first row, and the bottom tile
+        write_solid_tile(&mut video, 2, 1);
+        write_tile_row(&mut video, 2, 0, 3);
+        write_solid_tile(&mut video, 3, 2);
+
+        // The lowest bit of the tile index is ignored for 8x16 objects
+        write_sprite(&mut video, 0, 16, 8, 3, 0);
+        let mut expected = vec![black];
+        expected.extend(vec![light_gray; 7]);
+        expected.extend(vec![dark_gray; 8]);
+        assert_eq!(sprite_column(&mut video, 16), expected);
+
+        // Flipping vertically swaps the tiles as well
+        write_sprite(&mut video, 0, 16, 8, 3, 0b0100_0000);
+        expected.reverse();
+        assert_eq!(sprite_column(&mut video, 16), expected);
+    }
+
+    #[test]
+    fn test_mode_sequence() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+
+        let mut modes = vec![video.debug_mode()];
+        let mut vblank_lines = vec![];
+        for _ in 0..(154 * 456) {
+            video.tick();
+            if video.debug_mode() != *modes.last().unwrap() {
+                modes.push(video.debug_mode());
+            }
+            if video.debug_mode() == 1 && vblank_lines.last() != Some(&video.debug_ly()) {
+                vblank_lines.push(video.debug_ly());
+            }
+        }
+
+        let mut expected = [2, 3, 0].repeat(144);
+        expected.extend([1, 2]);
+        assert_eq!(modes, expected);
+        assert_eq!(vblank_lines, (144..=153).collect::<Vec<_>>());
+        assert_eq!(video.debug_ly(), 0);
+    }
+
+    #[test]
+    fn test_first_ten_sprites_by_oam_index_are_selected() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        write_solid_tile(&mut video, 1, 3);
+
+        // Later sprites have lower X positions, so they would be first if sorted by X
+        for index in 0..12 {
+            write_sprite(&mut video, index, 16, 8 + (11 - index) * 10, 1, 0);
+        }
+
+        let selected = video.select_sprites_for_line(0, &ObjectSize::Size8x8);
+        let selected_indices: Vec<u8> = selected.iter().map(|(sprite, _)| sprite.index).collect();
+        assert_eq!(selected_indices, (0..10).collect::<Vec<_>>());
+
+        video.draw_scanline(0);
+        let black = to_screen_color(PaletteColor::Black);
+        let white = to_screen_color(PaletteColor::White);
+        // Sprite 11 and 10
+        assert_eq!(drawn_pixel(&video, 0, 0), white);
+        assert_eq!(drawn_pixel(&video, 10, 0), white);
+        // Sprite 9 and 0
+        assert_eq!(drawn_pixel(&video, 20, 0), black);
+        assert_eq!(drawn_pixel(&video, 110, 0), black);
+    }
+
+    #[test]
+    fn test_overlapping_sprite_priority() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        write_solid_tile(&mut video, 1, 1);
+        write_solid_tile(&mut video, 2, 2);
+        let light_gray = to_screen_color(PaletteColor::LightGray);
+        let dark_gray = to_screen_color(PaletteColor::DarkGray);
+
+        // Sprite 1 has the lower X, so it wins where they overlap on 12-15
+        write_sprite(&mut video, 0, 16, 20, 1, 0);
+        write_sprite(&mut video, 1, 16, 16, 2, 0);
+        video.draw_scanline(0);
+        assert_eq!(drawn_pixel(&video, 11, 0), dark_gray);
+        assert_eq!(drawn_pixel(&video, 12, 0), dark_gray);
+        assert_eq!(drawn_pixel(&video, 15, 0), dark_gray);
+        assert_eq!(drawn_pixel(&video, 16, 0), light_gray);
+
+        // With the same X, the lower OAM index wins
+        write_sprite(&mut video, 1, 16, 20, 2, 0);
+        video.draw_scanline(0);
+        assert_eq!(drawn_pixel(&video, 12, 0), light_gray);
+        assert_eq!(drawn_pixel(&video, 19, 0), light_gray);
+    }
+
+    #[test]
+    fn test_sprite_behind_bg_uses_color_index() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        // Index 0 is black and index 1 is white, so the drawn color can't be relied on
+        video.write_register(Address::new(0xFF47), 0b1001_0011);
+        write_solid_tile(&mut video, 1, 1);
+        // Behind the background
+        write_sprite(&mut video, 0, 16, 8, 1, 0b1000_0000);
+        let light_gray = to_screen_color(PaletteColor::LightGray);
+
+        // The whole background map uses tile 0
+        write_solid_tile(&mut video, 0, 0);
+        video.draw_scanline(0);
+        assert_eq!(drawn_pixel(&video, 0, 0), light_gray);
+        assert_eq!(drawn_pixel(&video, 8, 0), to_screen_color(PaletteColor::Black));
+
+        for color_id in 1..=3 {
+            write_solid_tile(&mut video, 0, color_id);
+            video.draw_scanline(0);
+            let bg_color = to_screen_color(video.bg_palette.resolve_for_bg_from_color_id(color_id));
+            assert_eq!(drawn_pixel(&video, 0, 0), bg_color, "color ID {}", color_id);
+        }
+    }
+
+    // Ticks until the given mode starts, and returns how many dots it lasted
+    fn measure_mode_length(video: &mut Video, mode: VideoMode) -> usize {
+        while video.debug_mode() != mode as u8 {
+            video.tick();
+        }
+        let mut dots = 0;
+        while video.debug_mode() == mode as u8 {
+            video.tick();
+            dots += 1;
+        }
+        dots
+    }
+
+    #[test]
+    fn test_mode3_length() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        assert_eq!(measure_mode_length(&mut video, VideoMode::Mode3DrawPixels), 172);
+        assert_eq!(measure_mode_length(&mut video, VideoMode::Mode0HorizontalBlank), 204);
+
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        video.write_register(Address::new(0xFF43), 3);
+        // Sprites on the first line, with the object X position (screen + 8)
+        // 3 pixels into the first tile: 6 + 2
+        write_sprite(&mut video, 0, 16, 8, 0, 0);
+        // Same tile as the previous one: 6
+        write_sprite(&mut video, 1, 16, 8, 0, 0);
+        // Last pixels of a tile: 6 + 0
+        write_sprite(&mut video, 2, 16, 50, 0, 0);
+        // Fully hidden to the left: 11
+        write_sprite(&mut video, 3, 16, 0, 0, 0);
+        // Not on the first line
+        write_sprite(&mut video, 4, 32, 80, 0, 0);
+
+        let mode3_length = 172 + 3 + 8 + 6 + 6 + 11;
+        assert_eq!(measure_mode_length(&mut video, VideoMode::Mode3DrawPixels), mode3_length);
+        assert_eq!(measure_mode_length(&mut video, VideoMode::Mode0HorizontalBlank), 376 - mode3_length);
+
+        // Partially off the left edge, without any scrolling to make up for it
+        for x_pos in 1..8 {
+            let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+            write_sprite(&mut video, 0, 16, x_pos, 0, 0);
+            // In the first tile, with its 7 pixels to the right: 6 + 5
+            assert_eq!(measure_mode_length(&mut video, VideoMode::Mode3DrawPixels), 172 + 6 + 5, "X {}", x_pos);
+        }
+    }
+
+    #[test]
+    fn test_tick_many_matches_tick() {
+        let mut single_video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        let mut batched_video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        for video in [&mut single_video, &mut batched_video] {
+            // Enable all STAT interrupt sources
+            video.write_register(Address::new(0xFF41), 0b0111_1000);
+            video.write_register(Address::new(0xFF45), 10);
+            write_solid_tile(video, 0, 2);
+        }
+
+        // Batches of varying sizes, like the CPU would give, over a couple of frames
+        let batch_sizes = [1, 2, 3, 4, 5, 6, 24, 79, 80, 81, 455, 456, 457, 1000];
+        let mut elapsed_dots = 0;
+        for batch_size in batch_sizes.iter().cycle() {
+            if elapsed_dots > 3 * 154 * 456 {
+                break;
+            }
+            elapsed_dots += batch_size;
+
+            let mut single_interrupts = vec![];
+            for _ in 0..*batch_size {
+                single_interrupts.extend(single_video.tick());
+            }
+            let batched_interrupts = batched_video.tick_many(*batch_size);
+
+            assert_eq!(batched_interrupts, single_interrupts, "after {} dots", elapsed_dots);
+            assert_eq!(batched_video.debug_mode(), single_video.debug_mode());
+            assert_eq!(batched_video.debug_ly(), single_video.debug_ly());
+            assert_eq!(batched_video.dot_in_current_mode, single_video.dot_in_current_mode);
+            assert_eq!(
+                batched_video.read_register(Address::new(0xFF41)),
+                single_video.read_register(Address::new(0xFF41))
+            );
+            assert_eq!(batched_video.try_take_frame().is_some(), single_video.try_take_frame().is_some());
+        }
+
+        let (single_frame, batched_frame) = (single_video.frame_buffer(), batched_video.frame_buffer());
+        for y in 0..SCREEN_HEIGHT as usize {
+            for x in 0..SCREEN_WIDTH as usize {
+                assert_eq!(batched_frame.get_pixel(x, y), single_frame.get_pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn test_lyc_interrupt_during_vblank() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        // Only the LYC interrupt source
+        video.write_register(Address::new(0xFF41), 0b0100_0000);
+        video.write_register(Address::new(0xFF45), 150);
+
+        let mut stat_lines = vec![];
+        for _ in 0..(154 * 456) {
+            if video.tick().contains(&VideoInterrupt::Stat) {
+                stat_lines.push(video.debug_ly());
+            }
+        }
+        assert_eq!(stat_lines, vec![150]);
+
+        // Also when LY wraps around to line 0
+        video.write_register(Address::new(0xFF45), 0);
+        let mut stat_lines = vec![];
+        for _ in 0..(154 * 456) {
+            if video.tick().contains(&VideoInterrupt::Stat) {
+                stat_lines.push(video.debug_ly());
+            }
+        }
+        assert_eq!(stat_lines, vec![0]);
+    }
+
+    #[test]
+    fn test_vblank_interrupt_is_independent_of_stat() {
+        let count_frame_interrupts = |video: &mut Video| {
+            let mut vblank_lines = vec![];
+            let mut stat_count = 0;
+            for _ in 0..(154 * 456) {
+                for interrupt in video.tick() {
+                    match interrupt {
+                        VideoInterrupt::VBlank => vblank_lines.push(video.debug_ly()),
+                        VideoInterrupt::Stat => stat_count += 1,
+                    }
+                }
+            }
+            (vblank_lines, stat_count)
+        };
+
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        video.write_register(Address::new(0xFF41), 0b0000_0000);
+        assert_eq!(count_frame_interrupts(&mut video), (vec![144], 0));
+
+        // Only the mode 1 source
+        video.write_register(Address::new(0xFF41), 0b0001_0000);
+        assert_eq!(count_frame_interrupts(&mut video), (vec![144], 1));
+    }
+
+    #[test]
+    fn test_stat_read() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        let stat = Address::new(0xFF41);
+
+        // LY is 0, so LYC = 0 is a match
+        video.tick();
+        assert_eq!(video.read_register(stat), 0b1000_0110);
+
+        // Writing doesn't clobber the coincidence bit or the mode
+        video.write_register(stat, 0b1111_1111);
+        assert_eq!(video.read_register(stat), 0b1111_1110);
+
+        video.write_register(Address::new(0xFF45), 1);
+        video.tick();
+        assert_eq!(video.read_register(stat), 0b1111_1010);
+
+        video.write_register(stat, 0b0000_0000);
+        assert_eq!(video.read_register(stat), 0b1000_0010);
+    }
+
+    fn fill_tile_map(video: &mut Video, tile_map_start_addr: u16, tile_index: u8) {
+        for offset in 0..(32 * 32) {
+            video.write_vram(Address::new(tile_map_start_addr + offset), tile_index);
+        }
+    }
+
+    #[test]
+    fn test_bg_scroll_wraps_around_tile_map() {
+        let mut video = new_video(LCD_ON_WITH_BG_AND_OBJ);
+        video.write_register(Address::new(0xFF42), 250);
+        video.write_register(Address::new(0xFF43), 252);
+
+        // The four corners of the tile map, as (row, column, tile index)
+        let corners = [(31, 31, 1), (31, 0, 2), (0, 31, 3), (0, 0, 4)];
+        for (row, column, tile_index) in corners {
+            video.write_vram(Address::new(0x9800 + row * 32 + column), tile_index);
+            // Each row of the tile has its own color, to catch the wrong row being used
+            for tile_row in 0..8 {
+                write_tile_row(&mut video, tile_index, tile_row, (tile_index + tile_row as u8) % 4);
+            }
+        }
+
+        for line in 0..=10u8 {
+            video.draw_scanline(line);
+
+            let map_y = 250u8.wrapping_add(line);
+            for x in 0..8u8 {
+                let map_x = 252u8.wrapping_add(x);
+                let &(_, _, tile_index) = corners
+                    .iter()
+                    .find(|&&(row, column, _)| (row == 31) == (map_y >= 248) && (column == 31) == (map_x >= 248))
+                    .unwrap();
+                let expected = map_palette_color((tile_index + map_y % 8) % 4);
+                assert_eq!(
+                    drawn_pixel(&video, x as usize, line as usize),
+                    to_screen_color(expected),
+                    "x: {}, line: {}",
+                    x,
+                    line
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_window_uses_its_own_tile_map() {
+        let mut video = new_video(0);
+        write_solid_tile(&mut video, 1, 1);
+        write_solid_tile(&mut video, 2, 2);
+        fill_tile_map(&mut video, 0x9800, 1);
+        fill_tile_map(&mut video, 0x9C00, 2);
+        let light_gray = to_screen_color(PaletteColor::LightGray);
+        let dark_gray = to_screen_color(PaletteColor::DarkGray);
+
+        // Window starts at line 2, column 80
+        video.write_register(Address::new(0xFF4A), 2);
+        video.write_register(Address::new(0xFF4B), 80 + 7);
+
+        // BG on 0x9800, window on 0x9C00, tile data at 0x8000 for both
+        video.write_register(Address::new(0xFF40), 0b1111_0001);
+        video.draw_scanline(1);
+        assert_eq!(drawn_pixel(&video, 80, 1), light_gray);
+        video.draw_scanline(2);
+        assert_eq!(drawn_pixel(&video, 79, 2), light_gray);
+        assert_eq!(drawn_pixel(&video, 80, 2), dark_gray);
+        assert_eq!(drawn_pixel(&video, 159, 2), dark_gray);
+
+        // And swapped
+        video.write_register(Address::new(0xFF40), 0b1011_1001);
+        video.draw_scanline(3);
+        assert_eq!(drawn_pixel(&video, 79, 3), dark_gray);
+        assert_eq!(drawn_pixel(&video, 80, 3), light_gray);
+    }
+
+    #[test]
+    fn test_window_line_counter() {
+        let mut video = new_video(0);
+        write_solid_tile(&mut video, 1, 3);
+        // Only the second row of the window tile map has a black tile
+        video.write_vram(Address::new(0x9C00 + 32), 1);
+
+        video.write_register(Address::new(0xFF4A), 100);
+        video.write_register(Address::new(0xFF4B), 7);
+        video.write_register(Address::new(0xFF40), 0b1111_0001);
+        for line in 0..=115 {
+            video.draw_scanline(line);
+        }
 
-    fn read_bg_tile_pixel_color(
-        &self,
-        tile_row_addr: Address,
-        x_in_tile: u8,
-        palette: &Palette,
-    ) -> PaletteColor {
-        let color_id = self.read_color_id(tile_row_addr, x_in_tile);
-        return palette.resolve_for_bg_from_color_id(color_id);
+        // Window line 8 is drawn at line 108
+        let black = to_screen_color(PaletteColor::Black);
+        let white = to_screen_color(PaletteColor::White);
+        assert_eq!(drawn_pixel(&video, 0, 107), white);
+        assert_eq!(drawn_pixel(&video, 0, 108), black);
+        assert_eq!(drawn_pixel(&video, 0, 115), black);
+        assert_eq!(drawn_pixel(&video, 8, 108), white);
     }
 }
@@ -1,16 +1,48 @@
 mod gameboy;
 mod common;
+mod debugger;
 mod platform;
 
-use std::{fs, path::PathBuf};
+use std::cmp::Reverse;
+use std::{fs, path::PathBuf, thread};
+use std::io::{self, BufWriter};
+use std::ops::RangeInclusive;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use clap::Parser;
-use platform::platform::{Platform, Size, PlatformEvent};
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Parser, ValueEnum};
+use common::framebuffer::RgbColor;
+use common::ghosting::{LcdGhosting, DEFAULT_GHOSTING_FACTOR};
+use common::gif::GifRecorder;
+use common::input_script::{InputRecorder, InputScript};
+use debugger::{parse_address_range, Debugger};
+use platform::platform::{Frontend, OverlayInfo, PlatformEvent};
+#[cfg(any(feature = "sdl-frontend", feature = "minifb-frontend"))]
+use platform::platform::Size;
+#[cfg(feature = "sdl-frontend")]
+use platform::sdl_platform::{Platform, TextureFormat};
+#[cfg(feature = "minifb-frontend")]
+use platform::minifb_platform::MinifbPlatform;
 
-use crate::gameboy::gameboy::Gameboy;
-use crate::gameboy::cpu::TraceMode;
+use crate::gameboy::cartridge::SUPPORTED_CARTRIDGE_TYPES;
+use crate::gameboy::gameboy::{Gameboy, GameboyOptions};
+use crate::gameboy::error::EmulatorError;
+use crate::gameboy::header::{validate_rom_data, CartridgeType, Header};
+use crate::gameboy::mmu::{RamInit, SocdPolicy};
+use crate::gameboy::cpu::{TraceMode, UnknownOpcodePolicy};
 use crate::gameboy::reference::get_reference_metadata;
-use crate::gameboy::video::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::gameboy::video::{default_screen_colors, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Roughly 10 seconds of emulated time
+const DEFAULT_BENCHMARK_FRAMES: u64 = 600;
+// A snapshot every 6 frames, for the last ~10 seconds. Each rewind step goes
+// back one snapshot, so rewinding runs at 6x speed.
+const REWIND_INTERVAL_FRAMES: usize = 6;
+const REWIND_CAPACITY: usize = 100;
+// How many of the most executed opcodes --opcode-stats lists
+const OPCODE_STATS_COUNT: usize = 20;
+// Recordings keep every other frame, so this is ~30 seconds and 20 MB
+const RECORDING_MAX_FRAMES: usize = 900;
 
 #[derive(Parser)]
 struct Args {
@@ -21,54 +53,394 @@ struct Args {
     #[arg(long)]
     #[arg(value_enum, default_value_t=TraceMode::Off)]
     trace_mode: TraceMode,
+    // Write the trace to a file instead of stdout
+    #[arg(long)]
+    trace_file: Option<PathBuf>,
     #[arg(long)]
     headless: bool,
     #[arg(long)]
     skip_boot_rom: bool,
+    // Contents of WRAM and HRAM at power on
+    #[arg(long)]
+    #[arg(value_enum, default_value_t=RamInit::Zero)]
+    ram_init: RamInit,
+    // Use this cartridge type instead of the one in the header, for bad dumps and homebrew
+    #[arg(long, value_parser = supported_cartridge_type_parser())]
+    force_mbc: Option<CartridgeType>,
+    // Run as a DMG, even for ROMs with CGB features
+    #[arg(long)]
+    force_dmg: bool,
+    #[arg(long)]
+    frames: Option<u64>,
+    // What to do when running into an undefined opcode
+    #[arg(long)]
+    #[arg(value_enum, default_value_t=UnknownOpcodePolicy::Lock)]
+    on_unknown_opcode: UnknownOpcodePolicy,
+    // Report when the game writes to ROM on cartridges without an MBC
+    #[arg(long)]
+    log_rom_writes: bool,
+    // Advance the PPU on every memory access instead of after each instruction
+    #[arg(long)]
+    lockstep_video: bool,
+    // Print each CPU write to an address range, given as START-END in hex
+    #[arg(long, value_parser = parse_address_range)]
+    watch_writes: Option<RangeInclusive<u16>>,
+    // Load the cartridge RAM from this file if it exists, and write it back on exit
+    #[arg(long)]
+    save_file: Option<PathBuf>,
+    // Print the cartridge header and exit
+    #[arg(long)]
+    info: bool,
+    // Run headless as fast as possible and report the throughput
+    #[arg(long)]
+    benchmark: bool,
+    // Count the executed opcodes, and list the most common ones on exit
+    #[arg(long)]
+    opcode_stats: bool,
+    // Joypad input to feed in, as lines of `<frame> <button> <down|up>`
+    #[arg(long)]
+    input_script: Option<PathBuf>,
+    // Write the joypad input to a file, in the same format as --input-script
+    #[arg(long)]
+    record_input: Option<PathBuf>,
+    // Start paused in a debugger, reading commands from stdin
+    #[arg(long)]
+    debug: bool,
+    // The initial window size as a multiple of the screen size
+    #[arg(long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+    window_scale: u32,
+    #[cfg(feature = "sdl-frontend")]
+    #[arg(long)]
+    #[arg(value_enum, default_value_t=TextureFormat::Rgb24)]
+    texture_format: TextureFormat,
+    // Shown around the screen when the window doesn't match its aspect ratio, as RRGGBB
+    #[cfg(feature = "sdl-frontend")]
+    #[arg(long, default_value = "000000", value_parser = RgbColor::from_hex)]
+    border_color: RgbColor,
+    // Only draw one of every N + 1 frames, for slow hosts. The emulation is unaffected.
+    #[arg(long, default_value_t = 0)]
+    frame_skip: usize,
+    // Blend each frame with the previous one, like the slow DMG LCD. Toggled with F2.
+    #[arg(long)]
+    lcd_ghosting: bool,
+    // How much of the previous frame is kept, from 0.0 to 1.0
+    #[arg(long, default_value_t = DEFAULT_GHOSTING_FACTOR)]
+    lcd_ghosting_factor: f32,
+    // Screen colors of the four shades, from white to black, as RRGGBB,RRGGBB,RRGGBB,RRGGBB
+    #[arg(long, value_delimiter = ',', value_parser = RgbColor::from_hex)]
+    palette: Option<Vec<RgbColor>>,
+    // How opposing directions held at the same time are resolved
+    #[arg(long)]
+    #[arg(value_enum, default_value_t=SocdPolicy::AllowBoth)]
+    socd: SocdPolicy,
+    // Use the minifb window instead of SDL
+    #[cfg(feature = "minifb-frontend")]
+    #[arg(long)]
+    minifb: bool,
+}
+
+#[cfg(any(feature = "sdl-frontend", feature = "minifb-frontend"))]
+fn create_frontend(args: &Args, title: &str) -> Result<Box<dyn Frontend>, EmulatorError> {
+    let screen_size = Size::new(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize);
+    let window_size = screen_size.scaled(args.window_scale as usize);
+
+    #[cfg(all(feature = "minifb-frontend", feature = "sdl-frontend"))]
+    if args.minifb {
+        return Ok(Box::new(MinifbPlatform::new(title, window_size, screen_size)?));
+    }
+
+    #[cfg(feature = "sdl-frontend")]
+    return Ok(Box::new(Platform::new(
+        title,
+        window_size,
+        screen_size,
+        args.texture_format,
+        args.border_color,
+    )?));
+
+    // Without SDL, minifb is the only window there is
+    #[cfg(not(feature = "sdl-frontend"))]
+    Ok(Box::new(MinifbPlatform::new(title, window_size, screen_size)?))
+}
+
+#[cfg(not(any(feature = "sdl-frontend", feature = "minifb-frontend")))]
+fn create_frontend(_args: &Args, _title: &str) -> Result<Box<dyn Frontend>, EmulatorError> {
+    Err(EmulatorError::Platform("Built without a window, run with --headless".to_owned()))
+}
+
+// Only offer the cartridge types the emulator can actually run
+fn supported_cartridge_type_parser() -> impl TypedValueParser<Value = CartridgeType> {
+    PossibleValuesParser::new(SUPPORTED_CARTRIDGE_TYPES.iter().filter_map(ValueEnum::to_possible_value))
+        .map(|name| CartridgeType::from_str(&name, true).unwrap())
+}
+
+// CB prefixed opcodes are listed as CBxx
+fn print_opcode_stats(gameboy: &Gameboy) {
+    let opcodes = gameboy
+        .opcode_histogram()
+        .iter()
+        .enumerate()
+        .map(|(opcode, &count)| (format!("{:02X}", opcode), count));
+    let cb_opcodes = gameboy
+        .cb_opcode_histogram()
+        .iter()
+        .enumerate()
+        .map(|(opcode, &count)| (format!("CB{:02X}", opcode), count));
+    let mut counts: Vec<(String, u64)> = opcodes
+        .chain(cb_opcodes)
+        .filter(|&(_, count)| count > 0)
+        .collect();
+    counts.sort_by_key(|&(_, count)| Reverse(count));
+
+    println!("Executed {} distinct opcodes, the most common being:", counts.len());
+    for (opcode, count) in counts.iter().take(OPCODE_STATS_COUNT) {
+        println!("{:>4}: {}", opcode, count);
+    }
+}
+
+// Writes the recording next to where the emulator was started, named by the current time
+fn save_recording(recorder: &GifRecorder) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let path = format!("recording-{}.gif", timestamp);
+    match fs::write(&path, recorder.encode()) {
+        Ok(()) => println!("Saved {} frames to {}", recorder.frame_count(), path),
+        Err(e) => println!("Failed to save recording to {}: {}", path, e),
+    }
 }
 
 fn main() -> Result<(), String> {
     let args = Args::parse();
-    let rom_data = fs::read(args.rom).unwrap();
-    let reference_metdata = if let Some(reference) = args.reference {
-        Some(get_reference_metadata(&reference))
+    let rom_data = validate_rom_data(fs::read(&args.rom).unwrap())?;
+    if args.info {
+        let header = Header::read_from_rom(&rom_data)?;
+        print!("{}", header.format_info());
+        let supported = SUPPORTED_CARTRIDGE_TYPES.contains(&header.cartridge_type);
+        println!("supported: {}", if supported { "yes" } else { "no" });
+        return Ok(());
+    }
+
+    let reference_metdata = if let Some(reference) = &args.reference {
+        Some(get_reference_metadata(reference))
     } else {
         None
     };
 
-    let mut gameboy = Gameboy::new(
-        rom_data,
-        reference_metdata,
-        args.trace_mode,
-        args.skip_boot_rom,
-    );
+    let options = GameboyOptions {
+        trace_mode: args.trace_mode,
+        skip_boot_rom: args.skip_boot_rom,
+        ram_init: args.ram_init,
+        force_cartridge_type: args.force_mbc,
+        force_dmg: args.force_dmg,
+    };
+    let mut gameboy = Gameboy::new(rom_data, reference_metdata, options)?;
+    gameboy.set_socd_policy(args.socd);
+    if let Some(range) = &args.watch_writes {
+        gameboy.set_write_watch(range.clone());
+    }
+    let screen_colors = match &args.palette {
+        Some(palette) => {
+            let colors: [RgbColor; 4] = palette
+                .as_slice()
+                .try_into()
+                .map_err(|_| "--palette takes four colors".to_owned())?;
+            gameboy.set_bg_palette_colors(colors);
+            gameboy.set_obj_palette_colors(colors);
+            colors
+        }
+        None => default_screen_colors(),
+    };
+    gameboy.set_log_rom_writes(args.log_rom_writes);
+    gameboy.set_unknown_opcode_policy(args.on_unknown_opcode);
+    gameboy.set_frame_skip(args.frame_skip);
+    gameboy.set_lockstep_video(args.lockstep_video);
+    gameboy.set_count_opcodes(args.opcode_stats);
+
+    if let Some(save_file) = args.save_file.as_ref().filter(|path| path.exists()) {
+        let data = fs::read(save_file)
+            .map_err(|e| format!("Failed to read save file {:?}: {}", save_file, e))?;
+        gameboy.import_cartridge_ram(&data)?;
+    }
+
+    if let Some(trace_file) = &args.trace_file {
+        let file = fs::File::create(trace_file)
+            .map_err(|e| format!("Failed to create trace file {:?}: {}", trace_file, e))?;
+        gameboy.set_trace_writer(Box::new(BufWriter::new(file)));
+    }
+
+    let mut maybe_input_script = match &args.input_script {
+        Some(path) => {
+            let script = fs::read_to_string(path)
+                .map_err(|e| format!("Failed to read input script {:?}: {}", path, e))?;
+            let input_script = InputScript::parse(&script)
+                .map_err(|e| format!("Invalid input script {:?}: {}", path, e))?;
+            Some(input_script)
+        }
+        None => None,
+    };
+
+    let mut maybe_input_recorder = match &args.record_input {
+        Some(path) => {
+            let file = fs::File::create(path)
+                .map_err(|e| format!("Failed to create input recording {:?}: {}", path, e))?;
+            Some(InputRecorder::new(BufWriter::new(file)))
+        }
+        None => None,
+    };
 
-    let mut maybe_platform: Option<Platform> = if args.headless {
+    let max_frames = if args.benchmark {
+        Some(args.frames.unwrap_or(DEFAULT_BENCHMARK_FRAMES))
+    } else {
+        args.frames
+    };
+
+    let mut maybe_platform: Option<Box<dyn Frontend>> = if args.headless || args.benchmark {
         None
     } else {
-        let platform_or_err = Platform::new(
-            Size::new(600, 540),
-            Size::new(SCREEN_WIDTH as usize, SCREEN_HEIGHT as usize),
-        );
-        if platform_or_err.is_err() {
-            return Err(platform_or_err.err().unwrap());
-        }
-        Some(platform_or_err.unwrap())
+        let title = if gameboy.title().is_empty() {
+            "gameboy-rs".to_owned()
+        } else {
+            format!("gameboy-rs — {}", gameboy.title())
+        };
+        Some(create_frontend(&args, &title)?)
     };
+    if maybe_platform.is_some() {
+        gameboy.enable_rewind(REWIND_INTERVAL_FRAMES, REWIND_CAPACITY);
+    }
+
+    let mut frame_count: u64 = 0;
+    let start_time = gameboy.clock().now();
+
+    let mut rewinding = false;
+    let mut ghosting = LcdGhosting::new(args.lcd_ghosting, args.lcd_ghosting_factor);
+    let mut maybe_recorder: Option<GifRecorder> = None;
+    let mut maybe_debugger = args.debug.then(Debugger::new);
 
     'running: loop {
-        let maybe_frame = gameboy.tick();
+        if let Some(input_script) = maybe_input_script.as_mut() {
+            for event in input_script.take_events(frame_count) {
+                if let Some(recorder) = maybe_input_recorder.as_mut() {
+                    recorder
+                        .record(frame_count, &event)
+                        .map_err(|e| format!("Failed to record input: {}", e))?;
+                }
+                gameboy.handle_joypad(event);
+            }
+        }
+
+        if rewinding {
+            // Stays on the oldest snapshot until the key is released
+            gameboy.rewind_step();
+        } else if let Some(debugger) = maybe_debugger.as_mut() {
+            if debugger.is_paused() {
+                let resume = debugger
+                    .prompt(&mut gameboy, &mut io::stdin().lock(), &mut io::stdout())
+                    .map_err(|e| format!("Debugger IO failed: {}", e))?;
+                if !resume {
+                    break 'running;
+                }
+            }
+            debugger
+                .run_until_vblank(&mut gameboy, &mut io::stdout())
+                .map_err(|e| format!("Debugger IO failed: {}", e))?;
+        } else {
+            gameboy.run_until_vblank();
+        }
+        frame_count += 1;
 
-        if let (Some(frame), Some(platform)) = (maybe_frame, maybe_platform.as_mut()) {
-            let events = platform.give_new_frame(frame);
+        if let Some(platform) = maybe_platform.as_mut() {
+            let overlay_info = OverlayInfo {
+                ppu_mode: gameboy.debug_ppu_mode(),
+                ly: gameboy.debug_ly(),
+                pc: gameboy.debug_pc(),
+                buttons: gameboy.pressed_buttons(),
+            };
+            let frame = ghosting.apply(gameboy.frame_buffer());
+            let recording_full = maybe_recorder
+                .as_mut()
+                .is_some_and(|recorder| !recorder.push_frame(frame));
+            if let Some(recorder) = maybe_recorder.as_ref().filter(|_| recording_full) {
+                println!("Recording is full");
+                save_recording(recorder);
+                maybe_recorder = None;
+            }
+            let events = platform.give_new_frame(frame, &overlay_info);
             for event in events {
                 match event {
                     PlatformEvent::Quit => break 'running,
-                    PlatformEvent::Joypad(event) => gameboy.take_joypad_event(event),
+                    PlatformEvent::Joypad(event) => {
+                        // Takes effect from the next frame, which is where a replay feeds it in
+                        if let Some(recorder) = maybe_input_recorder.as_mut() {
+                            recorder
+                                .record(frame_count, &event)
+                                .map_err(|e| format!("Failed to record input: {}", e))?;
+                        }
+                        gameboy.handle_joypad(event);
+                    }
+                    PlatformEvent::SpeedUp | PlatformEvent::SlowDown => {
+                        let factor = if matches!(event, PlatformEvent::SpeedUp) { 2.0 } else { 0.5 };
+                        gameboy.set_speed_multiplier(gameboy.speed_multiplier() * factor);
+                        println!("Speed multiplier: {}x", gameboy.speed_multiplier());
+                    }
+                    PlatformEvent::Rewind(is_down) => rewinding = is_down,
+                    PlatformEvent::ToggleGhosting => {
+                        println!("LCD ghosting: {}", if ghosting.toggle() { "on" } else { "off" });
+                    }
+                    PlatformEvent::ToggleRecording => match maybe_recorder.take() {
+                        Some(recorder) => save_recording(&recorder),
+                        None => {
+                            println!("Recording started");
+                            maybe_recorder = Some(GifRecorder::new(
+                                screen_colors,
+                                SCREEN_WIDTH as usize,
+                                SCREEN_HEIGHT as usize,
+                                RECORDING_MAX_FRAMES,
+                            ));
+                        }
+                    },
                 }
             }
+
+            // Only pace presented frames, headless runs go as fast as possible.
+            thread::sleep(gameboy.time_until_next_frame());
+        }
+
+        for (pc, address, value) in gameboy.take_write_log() {
+            println!("Write to {:#06X} = {:#04X} at PC {:#06X}", address, value, pc);
+        }
+
+        if max_frames.is_some_and(|frames| frame_count >= frames) {
+            break 'running;
         }
     }
 
+    if let Some(recorder) = maybe_recorder.take() {
+        save_recording(&recorder);
+    }
+
+    // Cartridges without RAM have nothing to save
+    if let (Some(save_file), Some(data)) = (&args.save_file, gameboy.export_cartridge_ram()) {
+        fs::write(save_file, data)
+            .map_err(|e| format!("Failed to write save file {:?}: {}", save_file, e))?;
+    }
+
+    println!("Ran {} frames ({} cycles)", frame_count, gameboy.cycle_count());
+    if args.opcode_stats {
+        print_opcode_stats(&gameboy);
+    }
+
+    if args.benchmark {
+        let elapsed_seconds = (gameboy.clock().now() - start_time).as_secs_f64();
+        println!(
+            "Benchmark: {} frames in {:.3} s, {:.1} frames/s, {:.0} cycles/s",
+            frame_count,
+            elapsed_seconds,
+            frame_count as f64 / elapsed_seconds,
+            gameboy.cycle_count() as f64 / elapsed_seconds,
+        );
+    }
+
     return Ok(());
 }
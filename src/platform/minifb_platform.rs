@@ -0,0 +1,131 @@
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use crate::common::framebuffer::FrameBuffer;
+use crate::common::joypad_events::{JoypadButton, JoypadEvent};
+use crate::gameboy::error::EmulatorError;
+
+use super::platform::{Frontend, OverlayInfo, PlatformEvent, Size};
+
+// Pure Rust alternative to the SDL frontend, without the debug overlay.
+// https://github.com/emoon/rust_minifb
+pub struct MinifbPlatform {
+    window: Window,
+    // 0RGB pixels, as expected by minifb
+    buffer: Vec<u32>,
+    buffer_size: Size,
+}
+
+// Same layout as the SDL frontend
+fn key_to_button(key: Key) -> Option<JoypadButton> {
+    match key {
+        Key::NumPad8 => Some(JoypadButton::Up),
+        Key::NumPad2 => Some(JoypadButton::Down),
+        Key::NumPad4 => Some(JoypadButton::Left),
+        Key::NumPad6 => Some(JoypadButton::Right),
+        Key::NumPad7 => Some(JoypadButton::A),
+        Key::NumPad9 => Some(JoypadButton::B),
+        Key::NumPad3 => Some(JoypadButton::Select),
+        Key::NumPad1 => Some(JoypadButton::Start),
+        _ => None,
+    }
+}
+
+// minifb reports presses without repeat, so no tracking of held keys is needed
+fn key_down_event(key: Key) -> Option<PlatformEvent> {
+    match key {
+        Key::Escape => Some(PlatformEvent::Quit),
+        Key::NumPadPlus => Some(PlatformEvent::SpeedUp),
+        Key::NumPadMinus => Some(PlatformEvent::SlowDown),
+        Key::Backspace => Some(PlatformEvent::Rewind(true)),
+        Key::F2 => Some(PlatformEvent::ToggleGhosting),
+        Key::F3 => Some(PlatformEvent::ToggleRecording),
+        _ => key_to_button(key).map(|button| PlatformEvent::Joypad(JoypadEvent::new_down(button))),
+    }
+}
+
+fn key_up_event(key: Key) -> Option<PlatformEvent> {
+    match key {
+        Key::Backspace => Some(PlatformEvent::Rewind(false)),
+        _ => key_to_button(key).map(|button| PlatformEvent::Joypad(JoypadEvent::new_up(button))),
+    }
+}
+
+fn copy_frame_to_buffer(frame: &FrameBuffer, buffer: &mut [u32]) {
+    for (color, pixel) in frame.pixels().iter().zip(buffer.iter_mut()) {
+        *pixel = (color.r as u32) << 16 | (color.g as u32) << 8 | color.b as u32;
+    }
+}
+
+impl MinifbPlatform {
+    pub fn new(title: &str, window_size: Size, buffer_size: Size) -> Result<Self, EmulatorError> {
+        let window = Window::new(
+            title,
+            window_size.width,
+            window_size.height,
+            WindowOptions::default(),
+        )
+        .map_err(|e| EmulatorError::Platform(e.to_string()))?;
+
+        Ok(Self {
+            window,
+            buffer: vec![0; buffer_size.width * buffer_size.height],
+            buffer_size,
+        })
+    }
+}
+
+impl Frontend for MinifbPlatform {
+    fn give_new_frame(&mut self, frame: &FrameBuffer, _overlay_info: &OverlayInfo) -> Vec<PlatformEvent> {
+        debug_assert_eq!((frame.width, frame.height), (self.buffer_size.width, self.buffer_size.height));
+        copy_frame_to_buffer(frame, &mut self.buffer);
+        // The buffer is stretched to the window size
+        self.window
+            .update_with_buffer(&self.buffer, self.buffer_size.width, self.buffer_size.height)
+            .expect("Failed to present frame");
+
+        if !self.window.is_open() {
+            return vec![PlatformEvent::Quit];
+        }
+
+        let key_downs = self.window.get_keys_pressed(KeyRepeat::No).into_iter().filter_map(key_down_event);
+        let key_ups = self.window.get_keys_released().into_iter().filter_map(key_up_event);
+        key_downs.chain(key_ups).collect()
+    }
+}
+
+// The window itself needs a display, so only the headless parts are tested
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::framebuffer::RgbColor;
+
+    #[test]
+    fn test_copy_frame_to_buffer() {
+        let mut frame = FrameBuffer::new(160, 144);
+        frame.set_pixel(1, 0, RgbColor::new_gray(0x12));
+        let mut buffer = vec![0; 160 * 144];
+        copy_frame_to_buffer(&frame, &mut buffer);
+
+        assert_eq!(buffer[0], 0x00FF_FFFF);
+        assert_eq!(buffer[1], 0x0012_1212);
+    }
+
+    #[test]
+    fn test_key_events() {
+        assert!(matches!(key_down_event(Key::Escape), Some(PlatformEvent::Quit)));
+        assert!(matches!(key_down_event(Key::NumPadPlus), Some(PlatformEvent::SpeedUp)));
+        assert!(matches!(
+            key_down_event(Key::NumPad1),
+            Some(PlatformEvent::Joypad(JoypadEvent { is_down: true, button: JoypadButton::Start }))
+        ));
+        assert!(matches!(
+            key_up_event(Key::NumPad8),
+            Some(PlatformEvent::Joypad(JoypadEvent { is_down: false, button: JoypadButton::Up }))
+        ));
+        assert!(matches!(key_down_event(Key::Backspace), Some(PlatformEvent::Rewind(true))));
+        assert!(matches!(key_up_event(Key::Backspace), Some(PlatformEvent::Rewind(false))));
+        // Action keys have nothing to release
+        assert!(key_up_event(Key::NumPadPlus).is_none());
+        assert!(key_down_event(Key::A).is_none());
+    }
+}
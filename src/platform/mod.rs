@@ -1 +1,7 @@
+#[cfg(feature = "minifb-frontend")]
+pub mod minifb_platform;
+#[cfg(feature = "sdl-frontend")]
+pub mod overlay;
 pub mod platform;
+#[cfg(feature = "sdl-frontend")]
+pub mod sdl_platform;
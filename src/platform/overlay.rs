@@ -0,0 +1,111 @@
+use std::time::{Duration, Instant};
+
+use crate::common::framebuffer::{FrameBuffer, RgbColor};
+use crate::common::text::{draw_text, GLYPH_SIZE};
+
+use super::platform::OverlayInfo;
+
+const MARGIN: usize = 2;
+// Around the text, to keep it readable regardless of the frame
+const PADDING: usize = 1;
+const LINE_HEIGHT: usize = GLYPH_SIZE + 2 * PADDING;
+
+fn fill_rect(frame: &mut FrameBuffer, x: usize, y: usize, width: usize, height: usize, color: RgbColor) {
+    for row in y..(y + height).min(frame.height) {
+        let row_pixels = frame.row_mut(row);
+        let end = (x + width).min(row_pixels.len());
+        if x < end {
+            row_pixels[x..end].fill(color);
+        }
+    }
+}
+
+// Draws on top of a copy of the frame, so the emulated frame buffer itself stays clean
+pub fn draw_overlay(frame: &mut FrameBuffer, fps: f32, info: &OverlayInfo) {
+    let buttons: Vec<String> = info.buttons.iter().map(|button| format!("{:?}", button)).collect();
+    let lines = [
+        format!("FPS {:.1}", fps),
+        format!("PPU {} LY {}", info.ppu_mode, info.ly),
+        format!("PC {:04X}", info.pc),
+        format!("IN {}", buttons.join(" ")),
+    ];
+    for (index, line) in lines.iter().enumerate() {
+        let y = MARGIN + index * LINE_HEIGHT;
+        let width = line.chars().count() * GLYPH_SIZE + 2 * PADDING;
+        fill_rect(frame, MARGIN, y, width, LINE_HEIGHT, RgbColor::new_gray(0x00));
+        draw_text(frame, MARGIN + PADDING, y + PADDING, line, RgbColor::white());
+    }
+}
+
+// Measures FPS from the timestamps of presented frames
+pub struct FpsCounter {
+    last_update: Instant,
+    frames_since_update: u32,
+    fps: f32,
+}
+
+impl FpsCounter {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            last_update: now,
+            frames_since_update: 0,
+            fps: 0.0,
+        }
+    }
+
+    pub fn on_frame_presented(&mut self, now: Instant) {
+        self.frames_since_update += 1;
+
+        let elapsed = now.duration_since(self.last_update);
+        if elapsed >= Duration::from_secs(1) {
+            self.fps = self.frames_since_update as f32 / elapsed.as_secs_f32();
+            self.frames_since_update = 0;
+            self.last_update = now;
+        }
+    }
+
+    pub fn fps(&self) -> f32 {
+        self.fps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::joypad_events::JoypadButton;
+
+    #[test]
+    fn test_fps_counter() {
+        let start = Instant::now();
+        let mut counter = FpsCounter::new(start);
+
+        for frame in 1..=60 {
+            counter.on_frame_presented(start + Duration::from_millis(frame * 20));
+            if frame < 50 {
+                assert_eq!(counter.fps(), 0.0);
+            }
+        }
+
+        // 50 frames were presented over the first second
+        assert_eq!(counter.fps(), 50.0);
+    }
+
+    #[test]
+    fn test_draw_overlay() {
+        let mut frame = FrameBuffer::new(160, 144);
+        draw_overlay(&mut frame, 59.7, &OverlayInfo { ppu_mode: 3, ly: 42, pc: 0x0150, buttons: vec![JoypadButton::A] });
+
+        let black = RgbColor::new_gray(0x00);
+        // The background of the first line, "FPS 59.7"
+        assert_eq!(frame.get_pixel(MARGIN, MARGIN), black);
+        assert_eq!(frame.get_pixel(MARGIN + 8 * GLYPH_SIZE + 1, MARGIN), black);
+        assert_eq!(frame.get_pixel(MARGIN + 8 * GLYPH_SIZE + 2, MARGIN), RgbColor::white());
+        // The top row of the F, which leaves its last column unset
+        let text_y = MARGIN + PADDING;
+        assert_eq!(frame.get_pixel(MARGIN + PADDING, text_y), RgbColor::white());
+        assert_eq!(frame.get_pixel(MARGIN + PADDING + 6, text_y), RgbColor::white());
+        assert_eq!(frame.get_pixel(MARGIN + PADDING + 7, text_y), black);
+        // Nothing is drawn below the four lines
+        assert!(frame.pixels()[(MARGIN + 4 * LINE_HEIGHT) * frame.width..].iter().all(|&pixel| pixel == RgbColor::white()));
+    }
+}
@@ -0,0 +1,335 @@
+use std::collections::HashSet;
+use std::time::Instant;
+
+use clap::ValueEnum;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::render::{Canvas, Texture};
+use sdl2::video::Window;
+use sdl2::EventPump;
+
+use crate::common::framebuffer::{FrameBuffer, RgbColor};
+use crate::common::joypad_events::{JoypadButton, JoypadEvent};
+use crate::gameboy::error::EmulatorError;
+
+use super::overlay::{draw_overlay, FpsCounter};
+use super::platform::{Frontend, OverlayInfo, PlatformEvent, Size};
+
+#[derive(Copy, Clone, ValueEnum)]
+pub enum TextureFormat {
+    Rgb24,
+    Rgba32,
+}
+
+impl TextureFormat {
+    fn pixel_format(&self) -> PixelFormatEnum {
+        match self {
+            TextureFormat::Rgb24 => PixelFormatEnum::RGB24,
+            // Byte order is always R, G, B, A, regardless of endianness
+            TextureFormat::Rgba32 => PixelFormatEnum::RGBA32,
+        }
+    }
+}
+
+// Copies the frame into an RGB24 texture buffer
+fn copy_frame_to_buffer(frame: &FrameBuffer, buffer: &mut [u8], pitch: usize) {
+    for (row, buffer_row) in frame.pixels().chunks_exact(frame.width).zip(buffer.chunks_mut(pitch)) {
+        for (color, buffer_pixel) in row.iter().zip(buffer_row.chunks_exact_mut(3)) {
+            buffer_pixel.copy_from_slice(&[color.r, color.g, color.b]);
+        }
+    }
+}
+
+fn to_sdl_color(color: RgbColor) -> Color {
+    Color::RGB(color.r, color.g, color.b)
+}
+
+pub struct Platform {
+    event_pump: EventPump,
+    canvas: Canvas<Window>,
+    texture: Texture,
+    texture_format: TextureFormat,
+    buffer_size: Size,
+    // What the canvas is cleared to, visible around the screen
+    border_color: Color,
+
+    // Debug overlay, toggled with F1
+    overlay_enabled: bool,
+    fps_counter: FpsCounter,
+    // The frame with the overlay drawn on top
+    overlay_frame: FrameBuffer,
+
+    action_keys: ActionKeys,
+}
+
+// Keys which trigger a one-off action, as opposed to joypad keys which are held
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ActionKey {
+    SpeedUp,
+    SlowDown,
+    ToggleOverlay,
+    ToggleGhosting,
+    ToggleRecording,
+}
+
+fn scancode_to_action(scancode: Scancode) -> Option<ActionKey> {
+    match scancode {
+        Scancode::KpPlus => Some(ActionKey::SpeedUp),
+        Scancode::KpMinus => Some(ActionKey::SlowDown),
+        Scancode::F1 => Some(ActionKey::ToggleOverlay),
+        Scancode::F2 => Some(ActionKey::ToggleGhosting),
+        Scancode::F3 => Some(ActionKey::ToggleRecording),
+        _ => None,
+    }
+}
+
+// SDL keeps sending KeyDown while a key is held, so action keys are only
+// triggered on the initial press by tracking which ones are currently held.
+struct ActionKeys {
+    held: HashSet<Scancode>,
+}
+
+impl ActionKeys {
+    fn new() -> Self {
+        Self { held: HashSet::new() }
+    }
+
+    fn on_key_down(&mut self, scancode: Scancode) -> Option<ActionKey> {
+        let action = scancode_to_action(scancode)?;
+        if self.held.insert(scancode) {
+            Some(action)
+        } else {
+            None
+        }
+    }
+
+    fn on_key_up(&mut self, scancode: Scancode) {
+        self.held.remove(&scancode);
+    }
+}
+
+fn scancode_to_button(scancode: Scancode) -> Option<JoypadButton> {
+    match scancode {
+        Scancode::Kp8 => Some(JoypadButton::Up),
+        Scancode::Kp2 => Some(JoypadButton::Down),
+        Scancode::Kp4 => Some(JoypadButton::Left),
+        Scancode::Kp6 => Some(JoypadButton::Right),
+        Scancode::Kp7 => Some(JoypadButton::A),
+        Scancode::Kp9 => Some(JoypadButton::B),
+        Scancode::Kp3 => Some(JoypadButton::Select),
+        Scancode::Kp1 => Some(JoypadButton::Start),
+        _ => None,
+    }
+}
+
+impl Platform {
+    pub fn new(
+        title: &str,
+        window_size: Size,
+        buffer_size: Size,
+        texture_format: TextureFormat,
+        border_color: RgbColor,
+    ) -> Result<Self, EmulatorError> {
+        let sdl_context = sdl2::init().map_err(EmulatorError::Platform)?;
+        let video_subsystem = sdl_context.video().map_err(EmulatorError::Platform)?;
+
+        let window = video_subsystem
+            .window(
+                title,
+                window_size.width as u32,
+                window_size.height as u32,
+            )
+            .position_centered()
+            .opengl()
+            .build()
+            .map_err(|e| EmulatorError::Platform(e.to_string()))?;
+
+        let canvas = window
+            .into_canvas()
+            .build()
+            .map_err(|e| EmulatorError::Platform(e.to_string()))?;
+        let texture_creator = canvas.texture_creator();
+
+        let texture = texture_creator
+            .create_texture_streaming(
+                texture_format.pixel_format(),
+                buffer_size.width as u32,
+                buffer_size.height as u32,
+            )
+            .map_err(|e| EmulatorError::Platform(e.to_string()))?;
+
+        let event_pump = sdl_context.event_pump().map_err(EmulatorError::Platform)?;
+        let overlay_frame = FrameBuffer::new(buffer_size.width, buffer_size.height);
+
+        Ok(Self {
+            event_pump,
+            canvas,
+            texture,
+            texture_format,
+            buffer_size,
+            border_color: to_sdl_color(border_color),
+            overlay_enabled: false,
+            fps_counter: FpsCounter::new(Instant::now()),
+            overlay_frame,
+            action_keys: ActionKeys::new(),
+        })
+    }
+}
+
+impl Frontend for Platform {
+    fn give_new_frame(&mut self, frame: &FrameBuffer, overlay_info: &OverlayInfo) -> Vec<PlatformEvent> {
+        let mut platform_events: Vec<PlatformEvent> = vec![];
+        for event in self.event_pump.poll_iter() {
+            let maybe_platform_event = match event {
+                Event::Quit { .. }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => Some(PlatformEvent::Quit),
+
+                Event::KeyDown {
+                    scancode: Some(Scancode::Backspace),
+                    ..
+                } => Some(PlatformEvent::Rewind(true)),
+                Event::KeyUp {
+                    scancode: Some(Scancode::Backspace),
+                    ..
+                } => Some(PlatformEvent::Rewind(false)),
+
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    ..
+                } if scancode_to_action(scancode).is_some() => {
+                    match self.action_keys.on_key_down(scancode) {
+                        Some(ActionKey::SpeedUp) => Some(PlatformEvent::SpeedUp),
+                        Some(ActionKey::SlowDown) => Some(PlatformEvent::SlowDown),
+                        Some(ActionKey::ToggleOverlay) => {
+                            self.overlay_enabled = !self.overlay_enabled;
+                            None
+                        }
+                        Some(ActionKey::ToggleGhosting) => Some(PlatformEvent::ToggleGhosting),
+                        Some(ActionKey::ToggleRecording) => Some(PlatformEvent::ToggleRecording),
+                        None => None,
+                    }
+                }
+
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    ..
+                } => {
+                    if let Some(button) = scancode_to_button(scancode) {
+                        Some(PlatformEvent::Joypad(JoypadEvent::new_down(button)))
+                    } else {
+                        None
+                    }
+                }
+                Event::KeyUp {
+                    scancode: Some(scancode),
+                    ..
+                } => {
+                    self.action_keys.on_key_up(scancode);
+                    if let Some(button) = scancode_to_button(scancode) {
+                        Some(PlatformEvent::Joypad(JoypadEvent::new_up(button)))
+                    } else {
+                        None
+                    }
+                }
+
+                _ => None,
+            };
+            if let Some(platform_event) = maybe_platform_event {
+                platform_events.push(platform_event);
+            }
+        }
+
+        debug_assert_eq!((frame.width, frame.height), (self.buffer_size.width, self.buffer_size.height));
+        let frame = if self.overlay_enabled {
+            self.overlay_frame.copy_from(frame);
+            draw_overlay(&mut self.overlay_frame, self.fps_counter.fps(), overlay_info);
+            &self.overlay_frame
+        } else {
+            frame
+        };
+
+        self.canvas.set_draw_color(self.border_color);
+        self.canvas.clear();
+        self.texture
+            .with_lock(None, |buffer: &mut [u8], pitch: usize| match self.texture_format {
+                TextureFormat::Rgb24 => copy_frame_to_buffer(frame, buffer, pitch),
+                TextureFormat::Rgba32 => {
+                    // Rows of 4 byte pixels are never padded
+                    debug_assert_eq!(pitch, frame.width * 4);
+                    frame.copy_to_rgba(buffer);
+                }
+            })
+            .expect("Failed to draw texture");
+
+        self.canvas
+            .copy(&self.texture, None, None)
+            .expect("Failed to copy texture to canvas");
+        self.canvas.present();
+        self.fps_counter.on_frame_presented(Instant::now());
+
+        return platform_events;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::framebuffer::RgbColor;
+
+    fn write_pixel_to_buffer(buffer: &mut [u8], pitch: usize, x: usize, y: usize, color: RgbColor) {
+        let offset = y * pitch + x * 3;
+        buffer[offset] = color.r;
+        buffer[offset + 1] = color.g;
+        buffer[offset + 2] = color.b
+    }
+
+    #[test]
+    fn test_copy_frame_to_buffer() {
+        let mut frame = FrameBuffer::new(160, 144);
+        for y in 0..144 {
+            for x in 0..160 {
+                frame.set_pixel(x, y, RgbColor::new_gray(x.wrapping_mul(y)));
+            }
+        }
+        // Texture rows can be padded
+        let pitch = 160 * 3 + 4;
+
+        let mut per_pixel_buffer = vec![0x00; pitch * 144];
+        for y in 0..144 {
+            for x in 0..160 {
+                write_pixel_to_buffer(&mut per_pixel_buffer, pitch, x, y, frame.get_pixel(x, y));
+            }
+        }
+
+        let mut buffer = vec![0x00; pitch * 144];
+        copy_frame_to_buffer(&frame, &mut buffer, pitch);
+        assert_eq!(buffer, per_pixel_buffer);
+    }
+
+    #[test]
+    fn test_border_color() {
+        let border_color = RgbColor::from_hex("#303030").unwrap();
+        assert_eq!(to_sdl_color(border_color), Color::RGB(0x30, 0x30, 0x30));
+    }
+
+    #[test]
+    fn test_action_keys_ignore_repeat() {
+        let mut action_keys = ActionKeys::new();
+        assert_eq!(action_keys.on_key_down(Scancode::KpPlus), Some(ActionKey::SpeedUp));
+        // Auto-repeat while the key is held
+        assert_eq!(action_keys.on_key_down(Scancode::KpPlus), None);
+        assert_eq!(action_keys.on_key_down(Scancode::KpPlus), None);
+        // Other keys are tracked separately
+        assert_eq!(action_keys.on_key_down(Scancode::F1), Some(ActionKey::ToggleOverlay));
+
+        action_keys.on_key_up(Scancode::KpPlus);
+        assert_eq!(action_keys.on_key_down(Scancode::KpPlus), Some(ActionKey::SpeedUp));
+
+        // Joypad keys are not action keys
+        assert_eq!(action_keys.on_key_down(Scancode::Kp8), None);
+    }
+}